@@ -0,0 +1,2 @@
+pub mod lightmap;
+pub mod obj;
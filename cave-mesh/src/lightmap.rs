@@ -0,0 +1,518 @@
+use std::f32::consts::PI;
+
+use cgmath::{ElementWise, InnerSpace, Vector3};
+use image::{Rgba, RgbaImage};
+
+use crate::obj::ObjMesh;
+
+// the lighting pass' diffuse strength for the cave, so baked light matches the dynamic lights
+const CAVE_DIFFUSE: f32 = 0.1;
+const LEAF_TRIANGLES: usize = 4;
+// texels outside every triangle are filled from their neighbours this many times, so filtering doesn't pull in black at seams
+const DILATE_PASSES: u32 = 4;
+
+/// A light that never moves or changes, in the mesh's own space.
+pub struct StaticLight {
+    pub position: Vector3<f32>,
+    pub color: Vector3<f32>,
+    // candela, like `Light::intensity`
+    pub intensity: f32,
+    pub radius: f32,
+}
+
+pub struct BakeSettings {
+    // width and height of each material's lightmap
+    pub size: u32,
+    pub ao_samples: u32,
+    // how far away geometry still occludes, in metres
+    pub ao_distance: f32,
+    pub lights: Vec<StaticLight>,
+}
+
+impl Default for BakeSettings {
+    fn default() -> Self {
+        Self {
+            size: 512,
+            ao_samples: 64,
+            ao_distance: 2.0,
+            lights: vec![],
+        }
+    }
+}
+
+struct Triangle {
+    material: usize,
+    positions: [Vector3<f32>; 3],
+    normals: [Vector3<f32>; 3],
+    tex_coords: [[f32; 2]; 3],
+}
+
+impl Triangle {
+    fn centroid(&self) -> Vector3<f32> {
+        (self.positions[0] + self.positions[1] + self.positions[2]) / 3.0
+    }
+
+    /// Distance along the ray to the triangle, Möller-Trumbore.
+    fn intersect(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> Option<f32> {
+        let edge_1 = self.positions[1] - self.positions[0];
+        let edge_2 = self.positions[2] - self.positions[0];
+        let p = direction.cross(edge_2);
+        let determinant = edge_1.dot(p);
+        if determinant.abs() < 1e-9 {
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let t_vec = origin - self.positions[0];
+        let u = t_vec.dot(p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = t_vec.cross(edge_1);
+        let v = direction.dot(q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        Some(edge_2.dot(q) * inverse)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BvhNode {
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+    // the first triangle for leaves, the second child for interior nodes, the first child always directly follows
+    index: usize,
+    count: usize,
+}
+
+fn ray_hits_box(min: Vector3<f32>, max: Vector3<f32>, origin: Vector3<f32>, inverse_direction: Vector3<f32>, max_distance: f32) -> bool {
+    let t_1 = (min - origin).mul_element_wise(inverse_direction);
+    let t_2 = (max - origin).mul_element_wise(inverse_direction);
+    let near = t_1.x.min(t_2.x).max(t_1.y.min(t_2.y)).max(t_1.z.min(t_2.z));
+    let far = t_1.x.max(t_2.x).min(t_1.y.max(t_2.y)).min(t_1.z.max(t_2.z));
+    near <= far && far >= 0.0 && near <= max_distance
+}
+
+/// Every triangle of the mesh in a bounding volume hierarchy, for occlusion rays.
+struct Bvh {
+    triangles: Vec<Triangle>,
+    nodes: Vec<BvhNode>,
+}
+
+impl Bvh {
+    fn new(mut triangles: Vec<Triangle>) -> Self {
+        let mut nodes = vec![];
+        let count = triangles.len();
+        Self::build(&mut triangles, 0, count, &mut nodes);
+        Self { triangles, nodes }
+    }
+
+    /// Splits at the median centroid along the longest axis. Sorting is stable so the same mesh always builds the same tree.
+    fn build(triangles: &mut [Triangle], first: usize, count: usize, nodes: &mut Vec<BvhNode>) -> usize {
+        let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+        for triangle in &triangles[first..first + count] {
+            for position in triangle.positions {
+                min = Vector3::new(min.x.min(position.x), min.y.min(position.y), min.z.min(position.z));
+                max = Vector3::new(max.x.max(position.x), max.y.max(position.y), max.z.max(position.z));
+            }
+        }
+        let node = nodes.len();
+        nodes.push(BvhNode { min, max, index: first, count });
+        if count <= LEAF_TRIANGLES {
+            return node;
+        }
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        triangles[first..first + count].sort_by(|a, b| a.centroid()[axis].total_cmp(&b.centroid()[axis]));
+        let half = count / 2;
+        Self::build(triangles, first, half, nodes);
+        let second = Self::build(triangles, first + half, count - half, nodes);
+        nodes[node].index = second;
+        nodes[node].count = 0;
+        node
+    }
+
+    /// Whether anything is hit closer than `max_distance`.
+    fn occluded(&self, origin: Vector3<f32>, direction: Vector3<f32>, max_distance: f32) -> bool {
+        let inverse_direction = direction.map(|d| 1.0 / d);
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let BvhNode { min, max, index, count } = self.nodes[node];
+            if !ray_hits_box(min, max, origin, inverse_direction, max_distance) {
+                continue;
+            }
+            if count > 0 {
+                let hit = self.triangles[index..index + count].iter()
+                    .any(|triangle| triangle.intersect(origin, direction).is_some_and(|t| t > 0.0 && t < max_distance));
+                if hit {
+                    return true;
+                }
+            } else {
+                stack.push(index);
+                stack.push(node + 1);
+            }
+        }
+        false
+    }
+}
+
+/// Where a lightmap texel lies on the mesh.
+#[derive(Clone, Copy)]
+struct TexelSample {
+    position: Vector3<f32>,
+    normal: Vector3<f32>,
+}
+
+/// Bakes ambient occlusion and static light into a lightmap per material, laid out in the
+/// material's own texture coordinates. Nothing depends on timing or thread scheduling,
+/// the same mesh and settings always give the same images.
+pub struct LightmapBaker {
+    bvh: Bvh,
+    // triangle indices into the bvh per material
+    materials: Vec<Vec<usize>>,
+    settings: BakeSettings,
+    // how far rays start off the surface, relative to the mesh size
+    bias: f32,
+}
+
+impl LightmapBaker {
+    pub fn new(mesh: &ObjMesh, settings: BakeSettings) -> Self {
+        let (min, max) = mesh.bounds();
+        let mut triangles = vec![];
+        for (material, material_triangles) in mesh.triangles.iter().enumerate() {
+            for triangle in material_triangles {
+                if triangle.iter().any(|corner| corner[0].is_none()) {
+                    continue;
+                }
+                let positions = triangle.map(|corner| mesh.position(corner[0].unwrap()));
+                let face_normal = (positions[1] - positions[0]).cross(positions[2] - positions[0]);
+                if face_normal.magnitude2() == 0.0 {
+                    continue;
+                }
+                triangles.push(Triangle {
+                    material,
+                    positions,
+                    normals: triangle.map(|corner| corner[2].map_or(face_normal.normalize(), |i| Vector3::from(mesh.normals[i]).normalize())),
+                    tex_coords: triangle.map(|corner| corner[1].map_or([0.0; 2], |i| mesh.tex_coords[i])),
+                });
+            }
+        }
+        let bvh = Bvh::new(triangles);
+        let mut materials = vec![vec![]; mesh.triangles.len()];
+        for (i, triangle) in bvh.triangles.iter().enumerate() {
+            materials[triangle.material].push(i);
+        }
+        Self { bvh, materials, settings, bias: (max - min).magnitude() * 1e-5 + 1e-3 }
+    }
+
+    /// The lightmap of `material`, static light in rgb encoded as sRGB and ambient occlusion in alpha.
+    pub fn bake_material(&self, material: usize) -> RgbaImage {
+        self.bake_material_on(material, std::thread::available_parallelism().map_or(1, |threads| threads.get()))
+    }
+
+    /// `bake_material` with its rows split between `threads` threads.
+    fn bake_material_on(&self, material: usize, threads: usize) -> RgbaImage {
+        let size = self.settings.size;
+        let samples = self.rasterize(material);
+        let rows_per_thread = (size as usize).div_ceil(threads);
+        let mut texels = vec![None; samples.len()];
+        std::thread::scope(|scope| {
+            for (samples, texels) in samples.chunks(rows_per_thread * size as usize).zip(texels.chunks_mut(rows_per_thread * size as usize)) {
+                scope.spawn(move || {
+                    for (sample, texel) in samples.iter().zip(texels) {
+                        *texel = sample.map(|sample| self.bake_texel(sample));
+                    }
+                });
+            }
+        });
+        dilate(&mut texels, size);
+        RgbaImage::from_fn(size, size, |x, y| {
+            let (light, occlusion) = texels[(y * size + x) as usize].unwrap_or((Vector3::new(0.0, 0.0, 0.0), 1.0));
+            let [r, g, b] = [light.x, light.y, light.z].map(|channel| (linear_to_srgb(channel.clamp(0.0, 1.0)) * 255.0).round() as u8);
+            Rgba([r, g, b, (occlusion.clamp(0.0, 1.0) * 255.0).round() as u8])
+        })
+    }
+
+    /// The point on the mesh under every texel centre, None where no triangle of the material covers it.
+    fn rasterize(&self, material: usize) -> Vec<Option<TexelSample>> {
+        let size = self.settings.size;
+        let mut samples = vec![None; (size * size) as usize];
+        for &i in &self.materials[material] {
+            let triangle = &self.bvh.triangles[i];
+            let corners = triangle.tex_coords.map(|[u, v]| [u * size as f32, v * size as f32]);
+            let min_x = corners.iter().map(|c| c[0]).fold(f32::MAX, f32::min).floor().max(0.0) as u32;
+            let max_x = corners.iter().map(|c| c[0]).fold(f32::MIN, f32::max).ceil().min(size as f32) as u32;
+            let min_y = corners.iter().map(|c| c[1]).fold(f32::MAX, f32::min).floor().max(0.0) as u32;
+            let max_y = corners.iter().map(|c| c[1]).fold(f32::MIN, f32::max).ceil().min(size as f32) as u32;
+            let area = edge(corners[0], corners[1], corners[2]);
+            if area == 0.0 {
+                continue;
+            }
+            for y in min_y..max_y {
+                for x in min_x..max_x {
+                    let point = [x as f32 + 0.5, y as f32 + 0.5];
+                    let weights = [edge(corners[1], corners[2], point) / area, edge(corners[2], corners[0], point) / area, edge(corners[0], corners[1], point) / area];
+                    if weights.iter().any(|weight| *weight < 0.0) {
+                        continue;
+                    }
+                    let position = triangle.positions[0] * weights[0] + triangle.positions[1] * weights[1] + triangle.positions[2] * weights[2];
+                    let normal = (triangle.normals[0] * weights[0] + triangle.normals[1] * weights[1] + triangle.normals[2] * weights[2]).normalize();
+                    samples[(y * size + x) as usize] = Some(TexelSample { position, normal });
+                }
+            }
+        }
+        samples
+    }
+
+    /// Static light and ambient occlusion at one point.
+    fn bake_texel(&self, sample: TexelSample) -> (Vector3<f32>, f32) {
+        let origin = sample.position + sample.normal * self.bias;
+        // the same texel always gets the same rotation of the sample pattern, neighbours get different ones
+        let rotation = hash(sample.position);
+        let (tangent, bitangent) = orthonormal_basis(sample.normal);
+        let mut unoccluded = 0;
+        for i in 0..self.settings.ao_samples {
+            let [u, v] = hammersley(i, self.settings.ao_samples).map(|value| (value + rotation).fract());
+            // cosine weighted, occluders near the normal matter most
+            let radius = u.sqrt();
+            let phi = 2.0 * PI * v;
+            let direction = tangent * (radius * phi.cos()) + bitangent * (radius * phi.sin()) + sample.normal * (1.0 - u).sqrt();
+            if !self.bvh.occluded(origin, direction, self.settings.ao_distance) {
+                unoccluded += 1;
+            }
+        }
+        let occlusion = unoccluded as f32 / self.settings.ao_samples.max(1) as f32;
+
+        let mut light = Vector3::new(0.0, 0.0, 0.0);
+        for static_light in &self.settings.lights {
+            let offset = static_light.position - origin;
+            let distance = offset.magnitude();
+            let direction = offset / distance;
+            let n_dot_l = sample.normal.dot(direction);
+            if distance >= static_light.radius || n_dot_l <= 0.0 || self.bvh.occluded(origin, direction, distance) {
+                continue;
+            }
+            // the same windowed inverse square falloff as distance_attenuation in custom_shader_types.wgsl
+            let window = (1.0 - (distance / static_light.radius).powi(4)).clamp(0.0, 1.0);
+            let attenuation = static_light.intensity / (distance * distance).max(0.01) * window * window;
+            light += static_light.color * (n_dot_l * CAVE_DIFFUSE * attenuation);
+        }
+        (light, occlusion)
+    }
+}
+
+fn edge(a: [f32; 2], b: [f32; 2], point: [f32; 2]) -> f32 {
+    (b[0] - a[0]) * (point[1] - a[1]) - (b[1] - a[1]) * (point[0] - a[0])
+}
+
+fn orthonormal_basis(normal: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let up = if normal.z.abs() < 0.999 { Vector3::unit_z() } else { Vector3::unit_x() };
+    let tangent = up.cross(normal).normalize();
+    (tangent, normal.cross(tangent))
+}
+
+fn hammersley(i: u32, count: u32) -> [f32; 2] {
+    [i as f32 / count as f32, (i.reverse_bits() as f64 / 4294967296.0) as f32]
+}
+
+/// A number in [0, 1) from the bits of a position.
+fn hash(position: Vector3<f32>) -> f32 {
+    let mut h = 2166136261u32;
+    for bits in [position.x.to_bits(), position.y.to_bits(), position.z.to_bits()] {
+        h = (h ^ bits).wrapping_mul(16777619);
+    }
+    (h >> 8) as f32 / (1u32 << 24) as f32
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Grows baked texels into the empty ones around them, averaging the baked neighbours.
+fn dilate(texels: &mut [Option<(Vector3<f32>, f32)>], size: u32) {
+    for _ in 0..DILATE_PASSES {
+        let previous = texels.to_vec();
+        for y in 0..size as i32 {
+            for x in 0..size as i32 {
+                if previous[(y * size as i32 + x) as usize].is_some() {
+                    continue;
+                }
+                let mut sum = (Vector3::new(0.0, 0.0, 0.0), 0.0);
+                let mut count = 0;
+                for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx >= size as i32 || ny >= size as i32 {
+                        continue;
+                    }
+                    if let Some((light, occlusion)) = previous[(ny * size as i32 + nx) as usize] {
+                        sum = (sum.0 + light, sum.1 + occlusion);
+                        count += 1;
+                    }
+                }
+                if count > 0 {
+                    texels[(y * size as i32 + x) as usize] = Some((sum.0 / count as f32, sum.1 / count as f32));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, so the random rays and triangles are the same every run
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as f32 / u32::MAX as f32
+        }
+
+        fn vector(&mut self, scale: f32) -> Vector3<f32> {
+            Vector3::new(self.next() - 0.5, self.next() - 0.5, self.next() - 0.5) * scale
+        }
+
+        fn direction(&mut self) -> Vector3<f32> {
+            loop {
+                let direction = self.vector(2.0);
+                if direction.magnitude2() > 0.01 && direction.magnitude2() <= 1.0 {
+                    return direction.normalize();
+                }
+            }
+        }
+    }
+
+    fn triangle(positions: [Vector3<f32>; 3]) -> Triangle {
+        let normal = (positions[1] - positions[0]).cross(positions[2] - positions[0]).normalize();
+        Triangle { material: 0, positions, normals: [normal; 3], tex_coords: [[0.0; 2]; 3] }
+    }
+
+    // intersects the triangle's plane and checks which side of each edge the point is on
+    fn brute_force_intersect(triangle: &Triangle, origin: Vector3<f32>, direction: Vector3<f32>) -> Option<f32> {
+        let [a, b, c] = triangle.positions;
+        let normal = (b - a).cross(c - a);
+        let t = normal.dot(a - origin) / normal.dot(direction);
+        let point = origin + direction * t;
+        let inside = [(a, b), (b, c), (c, a)].iter().all(|&(from, to)| (to - from).cross(point - from).dot(normal) >= 0.0);
+        (t.is_finite() && inside).then_some(t)
+    }
+
+    // how far the point the ray passes through the plane is from the triangle's nearest edge, relative to its size
+    fn edge_distance(triangle: &Triangle, origin: Vector3<f32>, direction: Vector3<f32>) -> f32 {
+        let [a, b, c] = triangle.positions;
+        let normal = (b - a).cross(c - a);
+        let point = origin + direction * (normal.dot(a - origin) / normal.dot(direction));
+        [(a, b), (b, c), (c, a)].iter().map(|&(from, to)| {
+            let edge = to - from;
+            edge.cross(point - from).magnitude() / edge.magnitude2()
+        }).fold(f32::MAX, f32::min)
+    }
+
+    #[test]
+    fn moller_trumbore_hits_what_it_should() {
+        let triangle = triangle([Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0)]);
+        let down = Vector3::new(0.0, 0.0, -1.0);
+        let t = triangle.intersect(Vector3::new(0.25, 0.25, 2.0), down).unwrap();
+        assert!((t - 2.0).abs() < 1e-6);
+        // behind the origin comes back negative, occlusion rays ignore those
+        assert!(triangle.intersect(Vector3::new(0.25, 0.25, -2.0), down).unwrap() < 0.0);
+        assert!(triangle.intersect(Vector3::new(0.75, 0.75, 2.0), down).is_none());
+        assert!(triangle.intersect(Vector3::new(-0.1, 0.25, 2.0), down).is_none());
+        // parallel to the triangle
+        assert!(triangle.intersect(Vector3::new(0.25, 0.25, 0.0), Vector3::new(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn moller_trumbore_matches_brute_force() {
+        let mut random = Random(0x2545f491);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let triangle = triangle([random.vector(2.0), random.vector(2.0), random.vector(2.0)]);
+            let origin = random.vector(4.0);
+            // aimed near the triangle so about half the rays hit
+            let direction = (triangle.centroid() + random.vector(1.5) - origin).normalize();
+            if edge_distance(&triangle, origin, direction) < 1e-3 {
+                continue;
+            }
+            let expected = brute_force_intersect(&triangle, origin, direction);
+            let t = triangle.intersect(origin, direction);
+            assert_eq!(t.is_some(), expected.is_some());
+            if let (Some(t), Some(expected)) = (t, expected) {
+                assert!((t - expected).abs() < 1e-3 * expected.abs().max(1.0), "{t} isn't {expected}");
+                hits += 1;
+            }
+        }
+        assert!(hits > 200);
+    }
+
+    #[test]
+    fn bvh_matches_testing_every_triangle() {
+        let mut random = Random(0x9e3779b9);
+        let triangles = (0..300).map(|_| {
+            let center = random.vector(10.0);
+            triangle([center + random.vector(1.0), center + random.vector(1.0), center + random.vector(1.0)])
+        }).filter(|triangle| triangle.normals[0].x.is_finite()).collect::<Vec<_>>();
+        let bvh = Bvh::new(triangles);
+        let mut occluded = 0;
+        for _ in 0..2000 {
+            let origin = random.vector(12.0);
+            let direction = random.direction();
+            let max_distance = random.next() * 20.0;
+            let expected = bvh.triangles.iter().any(|triangle| triangle.intersect(origin, direction).is_some_and(|t| t > 0.0 && t < max_distance));
+            assert_eq!(bvh.occluded(origin, direction, max_distance), expected);
+            occluded += expected as u32;
+        }
+        assert!(occluded > 100, "{occluded}");
+    }
+
+    #[test]
+    fn baking_is_the_same_on_any_number_of_threads() {
+        // a floor covering the whole lightmap with a box on it to cast occlusion and shadow
+        let mesh = ObjMesh::parse("
+            v 0 0 0
+            v 4 0 0
+            v 4 0 4
+            v 0 0 4
+            v 1.5 0 1.5
+            v 2.5 0 1.5
+            v 2.5 0 2.5
+            v 1.5 0 2.5
+            v 1.5 1 1.5
+            v 2.5 1 1.5
+            v 2.5 1 2.5
+            v 1.5 1 2.5
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            f 1/1 4/4 3/3 2/2
+            f 9/1 12/1 11/1 10/1
+            f 5/1 6/1 10/1 9/1
+            f 6/1 7/1 11/1 10/1
+            f 7/1 8/1 12/1 11/1
+            f 8/1 5/1 9/1 12/1
+        ", None);
+        let baker = LightmapBaker::new(&mesh, BakeSettings {
+            size: 32,
+            ao_samples: 16,
+            ao_distance: 2.0,
+            lights: vec![StaticLight { position: Vector3::new(0.5, 2.0, 0.5), color: Vector3::new(1.0, 0.8, 0.6), intensity: 40.0, radius: 10.0 }],
+        });
+        let single = baker.bake_material_on(0, 1);
+        // some texels are shadowed or occluded and some aren't, so the comparison means something
+        let alphas = single.pixels().map(|pixel| pixel[3]).collect::<Vec<_>>();
+        assert!(alphas.contains(&255) && alphas.iter().any(|&alpha| alpha < 200));
+        for threads in [2, 3, 7, 64] {
+            assert_eq!(baker.bake_material_on(0, threads).as_raw(), single.as_raw(), "{threads} threads");
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use cgmath::Vector3;
+
+/// Triangles of an OBJ file grouped by material, before any GPU upload.
+pub struct ObjMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub tex_coords: Vec<[f32; 2]>,
+    pub normals: Vec<[f32; 3]>,
+    pub material_names: Vec<String>,
+    pub material_textures: HashMap<String, String>,
+    // from `bump`, `map_bump` or `disp`
+    pub material_heights: HashMap<String, HeightMap>,
+    // (position, tex coord, normal) indices, three per triangle
+    pub triangles: Vec<Vec<[[Option<usize>; 3]; 3]>>,
+}
+
+/// A material's height map and how deep its darkest texel sits below the surface, in model units.
+pub struct HeightMap {
+    pub texture: String,
+    pub depth: f32,
+}
+
+// used when the MTL doesn't give a `-bm` multiplier or `-mm` gain
+const DEFAULT_HEIGHT_DEPTH: f32 = 0.05;
+
+// OBJ indices count from 1, negative ones count back from the last element so far.
+// Anything outside the elements read so far is None, as if the corner didn't give it.
+fn parse_index(value: Option<&str>, len: usize) -> Option<usize> {
+    let value = value.filter(|value| !value.is_empty())?.parse::<i64>().ok()?;
+    let index = if value < 0 { len as i64 + value } else { value - 1 };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+fn parse_floats<const N: usize>(parts: std::str::SplitWhitespace) -> [f32; N] {
+    let mut values = [0.0; N];
+    for (value, part) in values.iter_mut().zip(parts) {
+        *value = part.parse().unwrap_or(0.0);
+    }
+    values
+}
+
+impl ObjMesh {
+    pub fn parse(source: &str, mtl_source: Option<&str>) -> Self {
+        let mut mesh = ObjMesh {
+            positions: vec![],
+            tex_coords: vec![],
+            normals: vec![],
+            material_names: vec![],
+            material_textures: HashMap::new(),
+            material_heights: HashMap::new(),
+            triangles: vec![],
+        };
+        let mut material = 0;
+        for line in source.lines() {
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("v") => mesh.positions.push(parse_floats(parts)),
+                // OBJ texture coordinates start at the bottom of the image
+                Some("vt") => {
+                    let [u, v] = parse_floats(parts);
+                    mesh.tex_coords.push([u, 1.0 - v]);
+                }
+                Some("vn") => mesh.normals.push(parse_floats(parts)),
+                Some("usemtl") => {
+                    let name = parts.next().unwrap_or_default().to_string();
+                    material = match mesh.material_names.iter().position(|existing| existing == &name) {
+                        Some(i) => i,
+                        None => {
+                            mesh.material_names.push(name);
+                            mesh.material_names.len() - 1
+                        }
+                    };
+                }
+                Some("f") => {
+                    let corners = parts.map(|corner| {
+                        let mut indices = corner.split('/');
+                        [
+                            parse_index(indices.next(), mesh.positions.len()),
+                            parse_index(indices.next(), mesh.tex_coords.len()),
+                            parse_index(indices.next(), mesh.normals.len()),
+                        ]
+                    }).collect::<Vec<_>>();
+                    if mesh.material_names.is_empty() {
+                        mesh.material_names.push(String::new());
+                    }
+                    while mesh.triangles.len() <= material {
+                        mesh.triangles.push(vec![]);
+                    }
+                    // fan triangulation for quads and larger polygons
+                    for i in 1..corners.len().saturating_sub(1) {
+                        mesh.triangles[material].push([corners[0], corners[i], corners[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(mtl_source) = mtl_source {
+            let mut current = None;
+            for line in mtl_source.lines() {
+                let mut parts = line.split_whitespace();
+                match parts.next() {
+                    Some("newmtl") => current = parts.next().map(|name| name.to_string()),
+                    Some("map_Kd") => if let (Some(name), Some(texture)) = (&current, parts.last()) {
+                        mesh.material_textures.insert(name.clone(), texture.to_string());
+                    },
+                    Some("bump" | "map_bump" | "disp") => if let Some(name) = &current {
+                        if let Some(height) = parse_height_map(parts) {
+                            mesh.material_heights.insert(name.clone(), height);
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        }
+        mesh
+    }
+
+    pub fn position(&self, i: usize) -> Vector3<f32> {
+        self.positions[i].into()
+    }
+
+    pub fn bounds(&self) -> (Vector3<f32>, Vector3<f32>) {
+        let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+        for position in &self.positions {
+            min = Vector3::new(min.x.min(position[0]), min.y.min(position[1]), min.z.min(position[2]));
+            max = Vector3::new(max.x.max(position[0]), max.y.max(position[1]), max.z.max(position[2]));
+        }
+        (min, max)
+    }
+}
+
+/// Reads the options of a height map statement, `bump -bm 0.03 rock.height.png` or `disp -mm 0 0.03 rock.height.png`.
+fn parse_height_map(mut parts: std::str::SplitWhitespace) -> Option<HeightMap> {
+    let mut depth = DEFAULT_HEIGHT_DEPTH;
+    let mut texture = None;
+    while let Some(part) = parts.next() {
+        match part {
+            "-bm" => depth = parts.next()?.parse().ok()?,
+            // base then gain, the base only offsets the surface which parallax doesn't need
+            "-mm" => depth = parts.nth(1)?.parse().ok()?,
+            _ => texture = Some(part.to_string()),
+        }
+    }
+    Some(HeightMap { texture: texture?, depth })
+}
+
+/// The baked lightmap next to a material's diffuse texture, `valdenfer_jpg_1.jpg` is lit by `valdenfer_jpg_1.lightmap.png`.
+pub fn lightmap_name(texture: &str) -> String {
+    let stem = texture.rsplit_once('.').map_or(texture, |(stem, _)| stem);
+    format!("{stem}.lightmap.png")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indices_outside_the_elements_read_so_far_are_dropped() {
+        assert_eq!(parse_index(Some("1"), 3), Some(0));
+        assert_eq!(parse_index(Some("3"), 3), Some(2));
+        assert_eq!(parse_index(Some("-1"), 3), Some(2));
+        assert_eq!(parse_index(Some("0"), 3), None);
+        assert_eq!(parse_index(Some("4"), 3), None);
+        assert_eq!(parse_index(Some("-4"), 3), None);
+        assert_eq!(parse_index(Some(""), 3), None);
+        assert_eq!(parse_index(None, 3), None);
+    }
+
+    #[test]
+    fn faces_with_bad_indices_keep_their_other_corners() {
+        let mesh = ObjMesh::parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nf 0 2 9\nf -1 -2 -5\n", None);
+        assert_eq!(mesh.triangles[0].len(), 3);
+        assert_eq!(mesh.triangles[0][0].map(|corner| corner[0]), [Some(0), Some(1), Some(2)]);
+        assert_eq!(mesh.triangles[0][1].map(|corner| corner[0]), [None, Some(1), None]);
+        assert_eq!(mesh.triangles[0][2].map(|corner| corner[0]), [Some(2), Some(1), None]);
+    }
+}
@@ -6,10 +6,29 @@ mod instance;
 mod runner;
 mod cube;
 mod light;
-#[allow(unused)]
 mod blur;
 mod texture_types;
 mod point_shadow;
+mod antialiasing;
+mod resolution;
+mod profiler;
+mod frustum;
+mod mesh_chunks;
+mod lod;
+mod hi_z;
+mod crystal_field;
+mod clusters;
+mod shadow_atlas;
+mod fog;
+mod ssr;
+mod reflection_probe;
+mod irradiance;
+mod light_probes;
+mod scene;
+mod decals;
+mod parallax;
+mod particles;
+mod animation;
 
 include!(concat!(env!("OUT_DIR"), "/resources.rs"));
 
@@ -0,0 +1,161 @@
+use std::f32::consts::TAU;
+
+use bespoke_engine::binding::WgslType;
+use bytemuck::{Pod, Zeroable};
+
+use crate::scene::{AnimationCurve, CurveShape, MaterialAnimation};
+
+// what the scene calls the glowing crystals, the cave's materials go by their MTL names
+pub const CRYSTAL_ANIMATION: &str = "crystals";
+
+impl CurveShape {
+    // must match the CURVE_ constants in custom_shader_types.wgsl
+    fn raw(&self) -> u32 {
+        match self {
+            CurveShape::Constant => 0,
+            CurveShape::Sine => 1,
+            CurveShape::Flicker => 2,
+            CurveShape::Ramp => 3,
+        }
+    }
+}
+
+impl AnimationCurve {
+    /// A curve that stays at `value`.
+    pub fn constant(value: f32) -> Self {
+        Self { shape: CurveShape::Constant, period: 1.0, min: value, max: value, phase: 0.0 }
+    }
+
+    /// The curve's value `time` seconds in. The shaders work it out the same way in `animate`,
+    /// this is for what the CPU has to keep in step with them.
+    pub fn evaluate(&self, time: f32) -> f32 {
+        let cycles = time / self.period + self.phase;
+        let amount = match self.shape {
+            CurveShape::Constant => 0.0,
+            CurveShape::Sine => 0.5 - 0.5 * (cycles * TAU).cos(),
+            CurveShape::Flicker => flicker(cycles),
+            CurveShape::Ramp => cycles - cycles.floor(),
+        };
+        self.min + (self.max - self.min) * amount
+    }
+
+    fn raw(&self) -> (u32, [f32; 4]) {
+        (self.shape.raw(), [self.period, self.min, self.max, self.phase])
+    }
+}
+
+// pcg, the same hash as `pcg_hash` in custom_shader_types.wgsl so flickers match on both sides
+fn hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+// smoothly interpolated random values, one per whole number
+fn noise(x: f32) -> f32 {
+    let cell = x.floor();
+    let t = x - cell;
+    let a = hash(cell as i32 as u32) as f32 / u32::MAX as f32;
+    let b = hash((cell as i32).wrapping_add(1) as u32) as f32 / u32::MAX as f32;
+    a + (b - a) * t * t * (3.0 - 2.0 * t)
+}
+
+// a slow wander with quicker sputters on top
+fn flicker(cycles: f32) -> f32 {
+    noise(cycles) * 0.7 + noise(cycles * 2.7 + 17.0) * 0.3
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct MaterialAnimationRaw {
+    emission_color: [f32; 3],
+    emission_shape: u32,
+    // period, min, max and phase
+    emission_curve: [f32; 4],
+    // zero for materials that don't scroll
+    scroll_direction: [f32; 2],
+    scroll_shape: u32,
+    padding: u32,
+    scroll_curve: [f32; 4],
+}
+
+impl WgslType for MaterialAnimationRaw {
+    fn wgsl_name() -> String {
+        "MaterialAnimation".into()
+    }
+}
+
+impl MaterialAnimation {
+    /// `emission` is what the material gives off when the scene doesn't animate it.
+    pub fn to_raw(self, emission: f32) -> MaterialAnimationRaw {
+        let (emission_shape, emission_curve) = self.emission.unwrap_or(AnimationCurve::constant(emission)).raw();
+        let (scroll_shape, scroll_curve) = self.scroll.unwrap_or(AnimationCurve::constant(0.0)).raw();
+        MaterialAnimationRaw {
+            emission_color: self.emission_color.into(),
+            emission_shape,
+            emission_curve,
+            scroll_direction: if self.scroll.is_some() { self.scroll_direction.into() } else { [0.0; 2] },
+            scroll_shape,
+            padding: 0,
+            scroll_curve,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(shape: CurveShape, period: f32, min: f32, max: f32, phase: f32) -> AnimationCurve {
+        AnimationCurve { shape, period, min, max, phase }
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "{value} isn't {expected}");
+    }
+
+    #[test]
+    fn constant_stays_at_its_min() {
+        let constant = AnimationCurve::constant(0.7);
+        for time in [0.0, 0.4, 13.0] {
+            assert_near(constant.evaluate(time), 0.7);
+        }
+        assert_near(curve(CurveShape::Constant, 2.0, 0.2, 0.9, 0.5).evaluate(1.0), 0.2);
+    }
+
+    #[test]
+    fn sine_peaks_halfway_through_its_period() {
+        let sine = curve(CurveShape::Sine, 4.0, 0.5, 2.0, 0.0);
+        assert_near(sine.evaluate(0.0), 0.5);
+        assert_near(sine.evaluate(1.0), 1.25);
+        assert_near(sine.evaluate(2.0), 2.0);
+        assert_near(sine.evaluate(3.0), 1.25);
+        assert_near(sine.evaluate(4.0), 0.5);
+        // a quarter of a period ahead
+        assert_near(curve(CurveShape::Sine, 4.0, 0.5, 2.0, 0.25).evaluate(1.0), 2.0);
+    }
+
+    #[test]
+    fn ramp_climbs_and_jumps_back() {
+        let ramp = curve(CurveShape::Ramp, 2.0, 0.0, 1.0, 0.0);
+        assert_near(ramp.evaluate(0.0), 0.0);
+        assert_near(ramp.evaluate(0.5), 0.25);
+        assert_near(ramp.evaluate(1.9), 0.95);
+        assert_near(ramp.evaluate(2.5), 0.25);
+        assert_near(curve(CurveShape::Ramp, 2.0, 1.0, 3.0, 0.5).evaluate(0.5), 2.5);
+    }
+
+    #[test]
+    fn flicker_wanders_smoothly_between_min_and_max() {
+        let flicker = curve(CurveShape::Flicker, 0.3, 0.2, 0.8, 0.0);
+        let mut last = flicker.evaluate(-5.0);
+        for step in 1..2000 {
+            let time = -5.0 + step as f32 * 0.005;
+            let value = flicker.evaluate(time);
+            assert!((0.2..=0.8).contains(&value), "{value} at {time}");
+            assert!((value - last).abs() < 0.1, "jumped from {last} to {value} at {time}");
+            assert_eq!(value, flicker.evaluate(time));
+            last = value;
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use bespoke_engine::{binding::{create_layout, UniformBinding, WgslType}, model::Render, shader::Shader, surface_context::SurfaceCtx, texture::{DepthTexture, Texture}};
+use bytemuck::{Pod, Zeroable};
+use wgpu::{CommandEncoder, RenderPassTimestampWrites};
+
+use crate::game::ScreenInfo;
+
+const JITTER_SAMPLES: u32 = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AntiAliasing {
+    None,
+    Fxaa,
+    Taa,
+}
+
+impl AntiAliasing {
+    pub fn next(&self) -> Self {
+        match self {
+            AntiAliasing::None => AntiAliasing::Fxaa,
+            AntiAliasing::Fxaa => AntiAliasing::Taa,
+            AntiAliasing::Taa => AntiAliasing::None,
+        }
+    }
+
+    // the final pass only runs FXAA itself, TAA is resolved in an earlier pass
+    pub fn final_pass_mode(&self) -> u32 {
+        match self {
+            AntiAliasing::Fxaa => 1,
+            _ => 0,
+        }
+    }
+}
+
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut f = 1.0;
+    let mut r = 0.0;
+    while index > 0 {
+        f /= base as f32;
+        r += f * (index % base) as f32;
+        index /= base;
+    }
+    r
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct TaaParams {
+    pub blend: f32,
+    padding: [f32; 3],
+}
+
+impl WgslType for TaaParams {
+    fn wgsl_name() -> String {
+        "TaaParams".into()
+    }
+}
+
+pub struct TemporalAA {
+    shader: Shader,
+    history: UniformBinding<Texture>,
+    resolved: UniformBinding<Texture>,
+    params_binding: UniformBinding<TaaParams>,
+    frame: u32,
+    has_history: bool,
+}
+
+impl TemporalAA {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32, depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>) -> Self {
+        let params_binding = UniformBinding::new(surface_ctx.device(), "TAA Params", TaaParams { blend: 1.0, padding: [0.0; 3] }, None);
+        let shader = Shader::new_post_process(
+            include_str!("shaders/taa.wgsl"),
+            surface_ctx.device(),
+            surface_ctx.config().format,
+            vec![&create_layout::<Texture>(surface_ctx.device()), &create_layout::<Texture>(surface_ctx.device()), &depth_texture.layout, &screen_info.layout, &params_binding.layout],
+            vec![&Texture::shader_type(), &Texture::shader_type(), &depth_texture.shader_type, &screen_info.shader_type, &params_binding.shader_type]
+        );
+        let (history, resolved) = Self::create_targets(surface_ctx, width, height);
+        Self {
+            shader,
+            history,
+            resolved,
+            params_binding,
+            frame: 0,
+            has_history: false,
+        }
+    }
+
+    fn create_targets(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) -> (UniformBinding<Texture>, UniformBinding<Texture>) {
+        let format = surface_ctx.config().format;
+        (
+            UniformBinding::new(surface_ctx.device(), "TAA History", Texture::blank_texture(surface_ctx.device(), width, height, format), None),
+            UniformBinding::new(surface_ctx.device(), "TAA Resolved", Texture::blank_texture(surface_ctx.device(), width, height, format), None),
+        )
+    }
+
+    pub fn resize(&mut self, surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) {
+        (self.history, self.resolved) = Self::create_targets(surface_ctx, width, height);
+        self.has_history = false;
+    }
+
+    pub fn reset(&mut self) {
+        self.has_history = false;
+    }
+
+    /// Returns the sub-pixel jitter for the next frame in clip space units.
+    pub fn next_jitter(&mut self, render_size: [f32; 2]) -> [f32; 2] {
+        self.frame = (self.frame + 1) % JITTER_SAMPLES;
+        let x = halton(self.frame + 1, 2) - 0.5;
+        let y = halton(self.frame + 1, 3) - 0.5;
+        [x * 2.0 / render_size[0], y * 2.0 / render_size[1]]
+    }
+
+    pub fn resolve(&mut self, surface_ctx: &dyn SurfaceCtx, encoder: &mut CommandEncoder, lit: &UniformBinding<Texture>, depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>, timestamp_writes: Option<RenderPassTimestampWrites>) {
+        let blend = if self.has_history { 0.1 } else { 1.0 };
+        self.params_binding.set_data(surface_ctx.device(), TaaParams { blend, padding: [0.0; 3] });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("TAA Resolve Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.resolved.value.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                timestamp_writes,
+                occlusion_query_set: None,
+                depth_stencil_attachment: None,
+            });
+            self.shader.bind(&mut render_pass);
+            render_pass.set_bind_group(0, &lit.binding, &[]);
+            render_pass.set_bind_group(1, &self.history.binding, &[]);
+            render_pass.set_bind_group(2, &depth_texture.binding, &[]);
+            render_pass.set_bind_group(3, &screen_info.binding, &[]);
+            render_pass.set_bind_group(4, &self.params_binding.binding, &[]);
+            surface_ctx.screen_model().render(&mut render_pass);
+        }
+        std::mem::swap(&mut self.history, &mut self.resolved);
+        self.has_history = true;
+    }
+
+    /// The most recently resolved frame.
+    pub fn output(&self) -> &UniformBinding<Texture> {
+        &self.history
+    }
+}
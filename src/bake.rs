@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use cave_mesh::{lightmap::{BakeSettings, LightmapBaker, StaticLight}, obj::{lightmap_name, ObjMesh}};
+use cgmath::Vector3;
+
+const DEFAULT_MESH: &str = "src/res/cave/valdenfer_jpg_1.obj";
+const USAGE: &str = "Usage: bake [mesh.obj] [--size texels] [--samples rays] [--distance metres] [--light x,y,z,r,g,b,candela,radius]...";
+
+/// Bakes lightmaps for the cave on the CPU, next to its textures so they are embedded on the next build.
+/// Light positions are in the mesh's own space.
+fn main() -> anyhow::Result<()> {
+    let mut mesh_path = PathBuf::from(DEFAULT_MESH);
+    let mut settings = BakeSettings::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("{arg} needs a value\n{USAGE}"));
+        match arg.as_str() {
+            "--size" => settings.size = value()?.parse()?,
+            "--samples" => settings.ao_samples = value()?.parse()?,
+            "--distance" => settings.ao_distance = value()?.parse()?,
+            "--light" => settings.lights.push(parse_light(&value()?)?),
+            "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ if arg.starts_with("--") => bail!("Unknown option {arg}\n{USAGE}"),
+            _ => mesh_path = PathBuf::from(&arg),
+        }
+    }
+
+    let source = std::fs::read_to_string(&mesh_path).with_context(|| format!("Failed to read {}", mesh_path.display()))?;
+    let directory = mesh_path.parent().map(PathBuf::from).unwrap_or_default();
+    let mtl_source = source.lines()
+        .find_map(|line| line.strip_prefix("mtllib "))
+        .and_then(|mtl| std::fs::read_to_string(directory.join(mtl.trim())).ok());
+    let mesh = ObjMesh::parse(&source, mtl_source.as_deref());
+    println!("Building a BVH over {} triangles", mesh.triangles.iter().map(Vec::len).sum::<usize>());
+    let baker = LightmapBaker::new(&mesh, settings);
+
+    for (material, name) in mesh.material_names.iter().enumerate() {
+        let Some(texture) = mesh.material_textures.get(name) else {
+            println!("Skipping {name}, it has no texture to share coordinates with");
+            continue;
+        };
+        let path = directory.join(lightmap_name(texture));
+        baker.bake_material(material).save(&path).with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("Baked {}", path.display());
+    }
+    Ok(())
+}
+
+fn parse_light(value: &str) -> anyhow::Result<StaticLight> {
+    let values = value.split(',').map(|part| part.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>()?;
+    let [x, y, z, r, g, b, intensity, radius] = values[..] else {
+        bail!("A light is x,y,z,r,g,b,candela,radius, got {value}");
+    };
+    Ok(StaticLight { position: Vector3::new(x, y, z), color: Vector3::new(r, g, b), intensity, radius })
+}
@@ -1,61 +1,162 @@
-use bespoke_engine::{binding::{create_layout, Binding, Uniform, UniformBinding, WgslType}, compute::ComputeShader, shader::ShaderType, texture::StorageTexture};
+use bespoke_engine::{binding::{create_layout, Uniform, UniformBinding, WgslType}, compute::ComputeShader, shader::ShaderType, texture::Texture};
 use bytemuck::{Pod, Zeroable};
-use wgpu::{Device, Extent3d, Queue};
+use wgpu::{BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, Device, Queue, TextureFormat};
+
+const BLUR_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlurInput {
+    Color,
+    // the depth texture at this binding of the input's bind group, with its sampler at the next one
+    Depth(u32),
+}
+
+impl BlurInput {
+    fn sample_source(&self) -> String {
+        match self {
+            BlurInput::Color => "
+t_input: $0,0;
+s_input: $0,1;
+
+fn sample_input(tex_coords: vec2f) -> vec4f {
+    return textureSampleLevel(t_input, s_input, tex_coords, 0.0);
+}
+".into(),
+            BlurInput::Depth(binding) => format!("
+t_input: $0,{};
+s_input: $0,{};
+
+fn sample_input(tex_coords: vec2f) -> vec4f {{
+    let depth = textureSampleLevel(t_input, s_input, tex_coords, 0.0);
+    return vec4f(vec3f(depth), 1.0);
+}}
+", binding, binding + 1),
+        }
+    }
+}
 
 pub struct BlurCompute {
-    shader: ComputeShader,
+    horizontal: ComputeShader,
+    vertical: ComputeShader,
     pub params: BlurParams,
     params_binding: UniformBinding<BlurParams>,
-    flip_binding: UniformBinding<u32>,
+    horizontal_binding: UniformBinding<u32>,
+    vertical_binding: UniformBinding<u32>,
+    storage_layout: BindGroupLayout,
+    intermediate: UniformBinding<Texture>,
+    intermediate_storage: BindGroup,
+    pub output: UniformBinding<Texture>,
+    output_storage: BindGroup,
+    downscale: u32,
 }
 
 impl BlurCompute {
-    pub fn new(source: &str, input_layout: &wgpu::BindGroupLayout, input_shader_type: &ShaderType, device: &Device) -> Self {
-        let shader  = ComputeShader::new(
-            source, 
-            &[input_layout, &create_layout::<BlurParams>(device), &create_layout::<StorageTexture>(device), &create_layout::<u32>(device)], 
-            vec![input_shader_type, &BlurParams::shader_type(), &StorageTexture::shader_type(), &u32::shader_type()], 
+    pub fn new(input: BlurInput, input_layout: &BindGroupLayout, input_shader_type: &ShaderType, input_size: (u32, u32), downscale: u32, radius: i32, sigma: f32, device: &Device) -> Self {
+        let source = format!("{}{}", include_str!("shaders/blur.wgsl"), input.sample_source());
+        let storage_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Blur Storage Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: BLUR_FORMAT,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            }],
+        });
+        let storage_shader_type = ShaderType {
+            var_types: vec!["".into()],
+            wgsl_types: vec!["texture_storage_2d<rgba16float, write>".into()],
+        };
+        let horizontal = ComputeShader::new(
+            &source,
+            &[input_layout, &create_layout::<BlurParams>(device), &storage_layout, &create_layout::<u32>(device)],
+            vec![input_shader_type, &BlurParams::shader_type(), &storage_shader_type, &u32::shader_type()],
+            device
+        );
+        let intermediate_source = format!("{}{}", include_str!("shaders/blur.wgsl"), BlurInput::Color.sample_source());
+        let vertical = ComputeShader::new(
+            &intermediate_source,
+            &[&create_layout::<Texture>(device), &create_layout::<BlurParams>(device), &storage_layout, &create_layout::<u32>(device)],
+            vec![&Texture::shader_type(), &BlurParams::shader_type(), &storage_shader_type, &u32::shader_type()],
             device
         );
+        let downscale = downscale.max(1);
+        let output_size = [(input_size.0 / downscale).max(1), (input_size.1 / downscale).max(1)];
         let params = BlurParams {
-            image_size: [0; 2],
-            output_scale: 0.0,
-            padding: 0.0,
+            radius,
+            sigma,
+            output_size,
         };
         let params_binding = UniformBinding::new(device, "Blur Params", params, None);
-        let flip_binding = UniformBinding::new(device, "Flip Texture", 0, None);
+        let horizontal_binding = UniformBinding::new(device, "Blur Horizontal", 0, None);
+        let vertical_binding = UniformBinding::new(device, "Blur Vertical", 1, None);
+        let (intermediate, intermediate_storage) = Self::create_target(device, &storage_layout, output_size, "Blur Intermediate");
+        let (output, output_storage) = Self::create_target(device, &storage_layout, output_size, "Blur Output");
         Self {
-            shader,
+            horizontal,
+            vertical,
             params,
-            flip_binding,
             params_binding,
+            horizontal_binding,
+            vertical_binding,
+            storage_layout,
+            intermediate,
+            intermediate_storage,
+            output,
+            output_storage,
+            downscale,
         }
     }
 
-    pub fn blur(&mut self, input: &dyn Uniform, output: &dyn Uniform, input_size: Extent3d, output_scale: f32, device: &Device, queue: &Queue) {
-        self.params.image_size = [input_size.width, input_size.height];
-        self.params.output_scale = output_scale;
+    fn create_target(device: &Device, storage_layout: &BindGroupLayout, size: [u32; 2], label: &str) -> (UniformBinding<Texture>, BindGroup) {
+        let texture = UniformBinding::new(device, label, Texture::blank_texture(device, size[0], size[1], BLUR_FORMAT), None);
+        let storage = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(label),
+            layout: storage_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&texture.value.view),
+            }],
+        });
+        (texture, storage)
+    }
+
+    pub fn resize(&mut self, input_size: (u32, u32), device: &Device) {
+        self.params.output_size = [(input_size.0 / self.downscale).max(1), (input_size.1 / self.downscale).max(1)];
+        (self.intermediate, self.intermediate_storage) = Self::create_target(device, &self.storage_layout, self.params.output_size, "Blur Intermediate");
+        (self.output, self.output_storage) = Self::create_target(device, &self.storage_layout, self.params.output_size, "Blur Output");
+    }
+
+    pub fn set_radius(&mut self, radius: i32, sigma: f32) {
+        self.params.radius = radius;
+        self.params.sigma = sigma;
+    }
+
+    pub fn blur(&mut self, input: &dyn Uniform, device: &Device, queue: &Queue) {
         self.params_binding.set_data(device, self.params);
-        self.flip_binding.set_data(device, 0);
         let groups = [
-            input_size.width,
-            input_size.height,
+            self.params.output_size[0].div_ceil(8),
+            self.params.output_size[1].div_ceil(8),
             1,
         ];
-        self.shader.run_once(vec![&input.binding(), &self.params_binding.binding, &output.binding(), &self.flip_binding.binding], groups, device, queue);
+        self.horizontal.run_once(vec![&input.binding(), &self.params_binding.binding, &self.intermediate_storage, &self.horizontal_binding.binding], groups, device, queue);
+        self.vertical.run_once(vec![&self.intermediate.binding, &self.params_binding.binding, &self.output_storage, &self.vertical_binding.binding], groups, device, queue);
     }
 }
 
 #[repr(C)]
 #[derive(Pod, Zeroable, Clone, Copy)]
 pub struct BlurParams {
-    image_size: [u32; 2],
-    output_scale: f32,
-    padding: f32,
+    pub radius: i32,
+    pub sigma: f32,
+    pub output_size: [u32; 2],
 }
 
 impl WgslType for BlurParams {
     fn wgsl_name() -> String {
         "Params".into()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,170 @@
+use bespoke_engine::{binding::UniformBinding, camera::Camera, compute::ComputeShader, shader::ShaderType};
+use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use cgmath::InnerSpace;
+use wgpu::{BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, Buffer, Device, Queue};
+
+use crate::{game::ScreenInfo, light::{Light, RawLight}};
+
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+// must match MAX_CLUSTER_LIGHTS in custom_shader_types.wgsl
+const MAX_CLUSTER_LIGHTS: u32 = 32;
+// what the light probes are baked with
+pub const INITIAL_EXPOSURE: f32 = 1.0;
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct ClusterParams {
+    camera_position: [f32; 3],
+    near: f32,
+    camera_forward: [f32; 3],
+    far: f32,
+    grid: [u32; 3],
+    light_count: u32,
+    debug: u32,
+    exposure: f32,
+    padding: [u32; 2],
+}
+
+/// Froxel light assignment. Every frame a compute pass lists the lights touching each
+/// cluster of the view frustum, so the lighting pass only loops over nearby lights.
+pub struct ClusteredLights {
+    shader: ComputeShader,
+    params: Buffer,
+    lights: Buffer,
+    light_capacity: usize,
+    clusters: Buffer,
+    compute_layout: BindGroupLayout,
+    compute_group: BindGroup,
+    pub lighting_layout: BindGroupLayout,
+    pub lighting_group: BindGroup,
+    pub debug: bool,
+    pub exposure: f32,
+}
+
+fn layout_entry(binding: u32, ty: wgpu::BufferBindingType, visibility: wgpu::ShaderStages) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+impl ClusteredLights {
+    pub fn new(screen_info: &UniformBinding<ScreenInfo>, device: &Device) -> Self {
+        let compute_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Cluster Compute Layout"),
+            entries: &[
+                layout_entry(0, wgpu::BufferBindingType::Uniform, wgpu::ShaderStages::COMPUTE),
+                layout_entry(1, wgpu::BufferBindingType::Storage { read_only: true }, wgpu::ShaderStages::COMPUTE),
+                layout_entry(2, wgpu::BufferBindingType::Storage { read_only: false }, wgpu::ShaderStages::COMPUTE),
+            ],
+        });
+        let lighting_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Cluster Lighting Layout"),
+            entries: &[
+                layout_entry(0, wgpu::BufferBindingType::Uniform, wgpu::ShaderStages::FRAGMENT),
+                layout_entry(1, wgpu::BufferBindingType::Storage { read_only: true }, wgpu::ShaderStages::FRAGMENT),
+                layout_entry(2, wgpu::BufferBindingType::Storage { read_only: true }, wgpu::ShaderStages::FRAGMENT),
+            ],
+        });
+        let shader = ComputeShader::new(
+            include_str!("shaders/clusters.wgsl"),
+            &[&screen_info.layout, &compute_layout],
+            vec![&screen_info.shader_type, &ShaderType {
+                var_types: vec!["<uniform>".into(), "<storage, read>".into(), "<storage, read_write>".into()],
+                wgsl_types: vec!["ClusterParams".into(), "array<Light>".into(), "array<u32>".into()],
+            }],
+            device
+        );
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster Params Buffer"),
+            size: size_of::<ClusterParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let cluster_count = CLUSTER_GRID.iter().product::<u32>();
+        let clusters = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster Light Lists"),
+            size: (cluster_count * (MAX_CLUSTER_LIGHTS + 1)) as u64 * size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let light_capacity = 16;
+        let lights = Self::create_light_buffer(device, light_capacity);
+        let (compute_group, lighting_group) = Self::create_groups(device, &compute_layout, &lighting_layout, &params, &lights, &clusters);
+        Self {
+            shader,
+            params,
+            lights,
+            light_capacity,
+            clusters,
+            compute_layout,
+            compute_group,
+            lighting_layout,
+            lighting_group,
+            debug: false,
+            exposure: INITIAL_EXPOSURE,
+        }
+    }
+
+    fn create_light_buffer(device: &Device, capacity: usize) -> Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster Light Buffer"),
+            size: (capacity * size_of::<RawLight>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_groups(device: &Device, compute_layout: &BindGroupLayout, lighting_layout: &BindGroupLayout, params: &Buffer, lights: &Buffer, clusters: &Buffer) -> (BindGroup, BindGroup) {
+        let group = |layout: &BindGroupLayout| device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Clustered Lights"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: params.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: lights.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: clusters.as_entire_binding() },
+            ],
+        });
+        (group(compute_layout), group(lighting_layout))
+    }
+
+    pub fn shader_type() -> ShaderType {
+        ShaderType {
+            var_types: vec!["<uniform>".into(), "<storage, read>".into(), "<storage, read>".into()],
+            wgsl_types: vec!["ClusterParams".into(), "array<Light>".into(), "array<u32>".into()],
+        }
+    }
+
+    /// Uploads the lights and rebuilds the cluster lists for the camera in `screen_info`.
+    pub fn update(&mut self, lights: &[Light], camera: &Camera, screen_info: &UniformBinding<ScreenInfo>, device: &Device, queue: &Queue) {
+        if lights.len() > self.light_capacity {
+            self.light_capacity = lights.len().next_power_of_two();
+            self.lights = Self::create_light_buffer(device, self.light_capacity);
+            (self.compute_group, self.lighting_group) = Self::create_groups(device, &self.compute_layout, &self.lighting_layout, &self.params, &self.lights, &self.clusters);
+        }
+        let raw_lights = lights.iter().map(|light| light.to_raw()).collect::<Vec<_>>();
+        if !raw_lights.is_empty() {
+            queue.write_buffer(&self.lights, 0, cast_slice(&raw_lights));
+        }
+        let params = ClusterParams {
+            camera_position: camera.eye.into(),
+            near: camera.znear,
+            camera_forward: camera.get_forward_vec().normalize().into(),
+            far: camera.zfar,
+            grid: CLUSTER_GRID,
+            light_count: lights.len() as u32,
+            debug: self.debug as u32,
+            exposure: self.exposure,
+            padding: [0; 2],
+        };
+        queue.write_buffer(&self.params, 0, bytes_of(&params));
+        let groups = [CLUSTER_GRID[0].div_ceil(4), CLUSTER_GRID[1].div_ceil(4), CLUSTER_GRID[2].div_ceil(4)];
+        self.shader.run_once(vec![&screen_info.binding, &self.compute_group], groups, device, queue);
+    }
+}
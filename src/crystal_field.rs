@@ -0,0 +1,341 @@
+use bespoke_engine::{compute::ComputeShader, shader::ShaderType};
+use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use cgmath::{InnerSpace, Matrix4, Quaternion, Rad, Rotation, Rotation3, Vector3};
+use wgpu::{util::{BufferInitDescriptor, DeviceExt, DrawIndexedIndirectArgs}, BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, Buffer, Device, Queue, RenderPass};
+
+use crate::{frustum::Frustum, game::Vertex, hi_z::HiZBuffer, instance::InstanceRaw, lod::bounding_radius};
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct CrystalInstance {
+    pub position: [f32; 3],
+    pub crystal_type: u32,
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+    padding: f32,
+}
+
+impl CrystalInstance {
+    pub fn new(position: Vector3<f32>, rotation: Quaternion<f32>, scale: Vector3<f32>, crystal_type: u32) -> Self {
+        Self {
+            position: position.into(),
+            crystal_type,
+            rotation: [rotation.v.x, rotation.v.y, rotation.v.z, rotation.s],
+            scale: scale.into(),
+            padding: 0.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct CullParams {
+    planes: [[f32; 4]; 6],
+    // the camera the Hi-Z pyramid was rendered from
+    occlusion_view_proj: [[f32; 4]; 4],
+    instance_count: u32,
+    type_count: u32,
+    // zero for targets that weren't given a frustum this frame
+    enabled: u32,
+    // zero for targets that aren't tested against the pyramid
+    occlusion: u32,
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct CrystalTypeRaw {
+    radius: f32,
+}
+
+/// Where one type's survivors of one target go in the output buffer.
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct CullRegion {
+    offset: u32,
+    capacity: u32,
+}
+
+struct CrystalMesh {
+    vertices: Buffer,
+    indices: Buffer,
+    index_count: u32,
+    radius: f32,
+}
+
+/// Crystals that live entirely on the GPU. A compute pass frustum culls every instance
+/// of the storage buffer and compacts the survivors per crystal type, which is then drawn
+/// with one `draw_indexed_indirect` per type without the CPU knowing how many passed.
+/// Every view culled in a frame, the camera and the shadow faces, is a target with its own
+/// region of the output and draw arguments, and all of them are culled in one dispatch.
+/// A target's region only has room for as many crystals as it may draw, the rest it sees are dropped.
+/// The camera's target also drops crystals hidden in last frame's Hi-Z pyramid.
+pub struct CrystalField {
+    shader: ComputeShader,
+    meshes: Vec<CrystalMesh>,
+    instance_layout: BindGroupLayout,
+    params_layout: BindGroupLayout,
+    output_layout: BindGroupLayout,
+    instance_group: BindGroup,
+    params_group: BindGroup,
+    output_group: BindGroup,
+    params: Buffer,
+    output: Buffer,
+    draw_args: Buffer,
+    instance_count: u32,
+    // the most crystals each target draws
+    target_capacities: Vec<u32>,
+    // by target then type
+    regions: Vec<CullRegion>,
+    // frustums set since the last cull, by target
+    pending: Vec<Option<CullParams>>,
+}
+
+fn layout_entry(binding: u32, ty: wgpu::BufferBindingType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn bind_group(device: &Device, layout: &BindGroupLayout, buffers: &[&Buffer]) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Crystal Field"),
+        layout,
+        entries: &buffers.iter().enumerate().map(|(i, buffer)| wgpu::BindGroupEntry {
+            binding: i as u32,
+            resource: buffer.as_entire_binding(),
+        }).collect::<Vec<_>>(),
+    })
+}
+
+impl CrystalField {
+    /// `meshes` are the crystal types, `target_capacities` the most crystals each view culled every frame may draw.
+    pub fn new(meshes: Vec<(Vec<Vertex>, Vec<u32>)>, instances: Vec<CrystalInstance>, target_capacities: Vec<u32>, hi_z: &HiZBuffer, device: &Device) -> Self {
+        let meshes = meshes.into_iter().map(|(vertices, indices)| CrystalMesh {
+            radius: bounding_radius(&vertices),
+            vertices: device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Crystal Type Vertex Buffer"),
+                contents: cast_slice(&vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            }),
+            indices: device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Crystal Type Index Buffer"),
+                contents: cast_slice(&indices),
+                usage: wgpu::BufferUsages::INDEX,
+            }),
+            index_count: indices.len() as u32,
+        }).collect::<Vec<_>>();
+        let instance_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Crystal Field Instance Layout"),
+            entries: &[layout_entry(0, wgpu::BufferBindingType::Storage { read_only: true })],
+        });
+        let params_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Crystal Field Params Layout"),
+            entries: &[0, 1, 2].map(|binding| layout_entry(binding, wgpu::BufferBindingType::Storage { read_only: true })),
+        });
+        let output_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Crystal Field Output Layout"),
+            entries: &[layout_entry(0, wgpu::BufferBindingType::Storage { read_only: false }), layout_entry(1, wgpu::BufferBindingType::Storage { read_only: false })],
+        });
+        let shader = ComputeShader::new(
+            include_str!("shaders/crystal_cull.wgsl"),
+            &[&instance_layout, &params_layout, &output_layout, hi_z.layout()],
+            vec![
+                &ShaderType { var_types: vec!["<storage, read>".into()], wgsl_types: vec!["array<CrystalInstance>".into()] },
+                &ShaderType { var_types: vec!["<storage, read>".into(); 3], wgsl_types: vec!["array<CullParams>".into(), "array<CrystalType>".into(), "array<CullRegion>".into()] },
+                &ShaderType { var_types: vec!["<storage, read_write>".into(); 2], wgsl_types: vec!["array<InstanceRaw>".into(), "array<atomic<u32>>".into()] },
+                &ShaderType { var_types: vec!["".into()], wgsl_types: vec!["texture_2d<f32>".into()] },
+            ],
+            device
+        );
+        // replaced by set_instances straight away
+        let placeholder = || device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 16,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let mut field = Self {
+            shader,
+            instance_group: bind_group(device, &instance_layout, &[&placeholder()]),
+            params_group: bind_group(device, &params_layout, &[&placeholder(), &placeholder(), &placeholder()]),
+            output_group: bind_group(device, &output_layout, &[&placeholder(), &placeholder()]),
+            params: placeholder(),
+            output: placeholder(),
+            draw_args: placeholder(),
+            instance_layout,
+            params_layout,
+            output_layout,
+            meshes,
+            instance_count: 0,
+            pending: vec![None; target_capacities.len().max(1)],
+            target_capacities,
+            regions: vec![],
+        };
+        field.set_instances(instances, device);
+        field
+    }
+
+    pub fn instance_count(&self) -> u32 {
+        self.instance_count
+    }
+
+    /// Uploads a new set of crystals, reserving a region of the output per type for every target.
+    pub fn set_instances(&mut self, instances: Vec<CrystalInstance>, device: &Device) {
+        self.instance_count = instances.len() as u32;
+        let mut type_counts = vec![0; self.meshes.len()];
+        for instance in &instances {
+            if let Some(count) = type_counts.get_mut(instance.crystal_type as usize) {
+                *count += 1;
+            }
+        }
+        self.regions = vec![];
+        let mut offset = 0;
+        for &target_capacity in &self.target_capacities {
+            for &count in &type_counts {
+                // a target that can't draw every crystal shares its room between the types by how many there are
+                let capacity = if target_capacity >= self.instance_count {
+                    count
+                } else {
+                    (count as u64 * target_capacity as u64).div_ceil(self.instance_count as u64) as u32
+                };
+                self.regions.push(CullRegion { offset, capacity });
+                offset += capacity;
+            }
+        }
+        // storage bindings can't be empty
+        let empty = CrystalInstance::zeroed();
+        let instance_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Crystal Field Instance Buffer"),
+            contents: if instances.is_empty() { bytes_of(&empty) } else { cast_slice(&instances) },
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        self.instance_group = bind_group(device, &self.instance_layout, &[&instance_buffer]);
+        let types = self.meshes.iter().map(|mesh| CrystalTypeRaw { radius: mesh.radius }).collect::<Vec<_>>();
+        let types = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Crystal Field Type Buffer"),
+            contents: cast_slice(&types),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let regions = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Crystal Field Region Buffer"),
+            contents: cast_slice(&self.regions),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        self.params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Crystal Field Params Buffer"),
+            size: (self.pending.len() * size_of::<CullParams>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.output = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Crystal Field Output Buffer"),
+            size: (offset.max(1) as usize * size_of::<InstanceRaw>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        self.draw_args = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Crystal Field Draw Args Buffer"),
+            size: (self.pending.len() * self.meshes.len().max(1) * size_of::<DrawIndexedIndirectArgs>()) as u64,
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.params_group = bind_group(device, &self.params_layout, &[&self.params, &types, &regions]);
+        self.output_group = bind_group(device, &self.output_layout, &[&self.output, &self.draw_args]);
+    }
+
+    /// Sets the frustum `target` is culled against in the next `cull`. Occlusion is only
+    /// meaningful for the camera, the pyramid is built from its depth.
+    pub fn set_target(&mut self, target: usize, frustum: &Frustum, occlusion: bool) {
+        self.pending[target] = Some(CullParams {
+            planes: frustum.planes(),
+            occlusion_view_proj: Matrix4::from_scale(1.0).into(),
+            instance_count: self.instance_count,
+            type_count: self.meshes.len() as u32,
+            enabled: 1,
+            occlusion: occlusion as u32,
+        });
+    }
+
+    /// Culls every crystal for every target given a frustum since the last cull, in one dispatch.
+    /// The rest of the targets draw nothing until they are given one again.
+    pub fn cull(&mut self, hi_z: &HiZBuffer, device: &Device, queue: &Queue) {
+        let Some(last) = self.pending.iter().rposition(Option::is_some) else {
+            return;
+        };
+        let occlusion_view_proj = hi_z.view_proj();
+        let params = self.pending[..=last].iter().map(|params| {
+            let mut params = params.unwrap_or(CullParams::zeroed());
+            match occlusion_view_proj {
+                Some(view_proj) => params.occlusion_view_proj = view_proj.into(),
+                // nothing to test against until the pyramid is built
+                None => params.occlusion = 0,
+            }
+            params
+        }).collect::<Vec<_>>();
+        self.pending.fill(None);
+        let draw_args = (0..self.pending.len()).flat_map(|_| &self.meshes).flat_map(|mesh| DrawIndexedIndirectArgs {
+            index_count: mesh.index_count,
+            instance_count: 0,
+            first_index: 0,
+            base_vertex: 0,
+            first_instance: 0,
+        }.as_bytes().to_vec()).collect::<Vec<_>>();
+        queue.write_buffer(&self.draw_args, 0, &draw_args);
+        if self.instance_count == 0 {
+            return;
+        }
+        queue.write_buffer(&self.params, 0, cast_slice(&params));
+        // one row of workgroups per target
+        self.shader.run_once(vec![&self.instance_group, &self.params_group, &self.output_group, hi_z.pyramid()], [self.instance_count.div_ceil(64), params.len() as u32, 1], device, queue);
+    }
+
+    /// Draws the crystals that survived the last cull of `target` with the bound pipeline.
+    pub fn render<'a: 'b, 'b>(&'a self, render_pass: &mut RenderPass<'b>, target: usize) {
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            let region = self.regions[target * self.meshes.len() + i];
+            if region.capacity == 0 {
+                continue;
+            }
+            // instances of each type start at its region, so first_instance can stay 0
+            render_pass.set_vertex_buffer(0, mesh.vertices.slice(..));
+            render_pass.set_vertex_buffer(1, self.output.slice((region.offset as usize * size_of::<InstanceRaw>()) as u64..));
+            render_pass.set_index_buffer(mesh.indices.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.draw_indexed_indirect(&self.draw_args, ((target * self.meshes.len() + i) * size_of::<DrawIndexedIndirectArgs>()) as u64);
+        }
+    }
+}
+
+fn next_random(state: &mut u32) -> f32 {
+    // xorshift, deterministic so a geode looks the same every run
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state as f32 / u32::MAX as f32
+}
+
+/// Lines the inside of a sphere with crystals pointing towards its centre,
+/// each stretched along its length by up to `max_elongation`.
+pub fn fill_geode(center: Vector3<f32>, radius: f32, count: usize, type_count: u32, scale_range: (f32, f32), max_elongation: f32, seed: u32) -> Vec<CrystalInstance> {
+    let mut state = seed.max(1);
+    (0..count).map(|_| {
+        // uniform direction on the sphere
+        let z = next_random(&mut state) * 2.0 - 1.0;
+        let angle = next_random(&mut state) * std::f32::consts::TAU;
+        let ring = (1.0 - z * z).sqrt();
+        let direction = Vector3::new(ring * angle.cos(), z, ring * angle.sin()).normalize();
+        let spin = Quaternion::from_angle_y(Rad(next_random(&mut state) * std::f32::consts::TAU));
+        let rotation = Quaternion::between_vectors(Vector3::unit_y(), -direction) * spin;
+        let width = scale_range.0 + (scale_range.1 - scale_range.0) * next_random(&mut state);
+        let length = width * (1.0 + (max_elongation - 1.0) * next_random(&mut state));
+        let scale = Vector3::new(width, length, width);
+        let crystal_type = ((next_random(&mut state) * type_count as f32) as u32).min(type_count.saturating_sub(1));
+        CrystalInstance::new(center + direction * radius, rotation, scale, crystal_type)
+    }).collect()
+}
@@ -0,0 +1,279 @@
+use std::path::Path;
+
+use anyhow::Context;
+use bespoke_engine::{binding::UniformBinding, shader::{Shader, ShaderConfig, ShaderType}, surface_context::SurfaceCtx, texture::DepthTexture, InstanceTrait};
+use bytemuck::{cast_slice, Pod, Zeroable};
+use cgmath::{Matrix4, SquareMatrix, Vector3};
+use image::{imageops::FilterType, RgbaImage};
+use wgpu::{util::{BufferInitDescriptor, DeviceExt}, BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, CommandEncoder, Device, Queue, RenderPassTimestampWrites};
+
+use crate::{game::ScreenInfo, load_resource, scene::DecalDesc, texture_types::TextureLayer};
+
+// every decal texture is resized to this, so they fit in one array
+const DECAL_TEXTURE_SIZE: u32 = 256;
+const DECAL_MIPS: u32 = DECAL_TEXTURE_SIZE.ilog2() + 1;
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct DecalRaw {
+    transform: [[f32; 4]; 4],
+    inverse_transform: [[f32; 4]; 4],
+    tangent: [f32; 3],
+    blend: u32,
+    up: [f32; 3],
+    opacity: f32,
+    size: [f32; 2],
+    texture: u32,
+    normal_map: i32,
+    reflectivity: f32,
+    roughness: f32,
+    padding: [f32; 2],
+}
+
+/// Moss, cracks and mineral stains projected onto the cave's G-buffer after the opaque pass, so the
+/// photogrammetry doesn't have to be re-textured. Each decal draws its box twice: first copying the
+/// normals, diffuse and surface under it to `layer`, then blending itself into the G-buffer with what
+/// it covers read back from that copy.
+pub struct Decals {
+    base_shader: Shader,
+    shader: Shader,
+    bind_group: Option<BindGroup>,
+    decal_count: u32,
+    layer: UniformBinding<TextureLayer>,
+    pub enabled: bool,
+}
+
+impl Decals {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32, decals: &[DecalDesc], directory: &Path, gbuffer: &UniformBinding<TextureLayer>, depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>) -> Self {
+        let device = surface_ctx.device();
+        let layout = Self::create_layout(device);
+        let create_shader = |fragment: &str| Shader::new(
+            &format!("{}{}", include_str!("shaders/decal_box.wgsl"), fragment),
+            device,
+            vec![surface_ctx.config().format; 3],
+            vec![&gbuffer.layout, &depth_texture.layout, &screen_info.layout, &layout],
+            vec![&gbuffer.shader_type, &depth_texture.shader_type, &screen_info.shader_type, &Self::shader_type()],
+            &[],
+            // only the boxes' far sides, which are there with the camera inside them too
+            ShaderConfig { enable_depth_texture: false, face_cull: Some(wgpu::FrontFace::Cw), ..Default::default() },
+        );
+        let (bind_group, decal_count) = Self::create_bind_group(device, surface_ctx.queue(), &layout, decals, directory).unzip();
+        Self {
+            base_shader: create_shader(include_str!("shaders/decal_base.wgsl")),
+            shader: create_shader(include_str!("shaders/decals.wgsl")),
+            bind_group,
+            decal_count: decal_count.unwrap_or(0),
+            layer: Self::create_layer(surface_ctx, width, height),
+            enabled: true,
+        }
+    }
+
+    fn create_layer(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) -> UniformBinding<TextureLayer> {
+        UniformBinding::new(surface_ctx.device(), "Decal Layer", TextureLayer::new(surface_ctx, width, height), None)
+    }
+
+    pub fn resize(&mut self, surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) {
+        self.layer = Self::create_layer(surface_ctx, width, height);
+    }
+
+    /// Whether there are decals to draw this frame.
+    pub fn is_active(&self) -> bool {
+        self.enabled && self.bind_group.is_some()
+    }
+
+    fn create_layout(device: &Device) -> BindGroupLayout {
+        let array_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Decal Layout"),
+            entries: &[
+                array_entry(0),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                array_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        })
+    }
+
+    /// Loads the decals' textures into arrays alongside the decals themselves and counts them, none if no decal could be loaded.
+    fn create_bind_group(device: &Device, queue: &Queue, layout: &BindGroupLayout, decals: &[DecalDesc], directory: &Path) -> Option<(BindGroup, u32)> {
+        let mut textures = vec![];
+        let mut normal_maps = vec![];
+        let mut raw = vec![];
+        for decal in decals {
+            let texture = match load_decal_texture(&directory.join(&decal.texture)) {
+                Ok(texture) => texture,
+                Err(err) => {
+                    log::warn!("Skipping a decal: {err:?}");
+                    continue;
+                }
+            };
+            let normal_map = decal.normal_map.as_ref().and_then(|normal_map| {
+                load_decal_texture(&directory.join(normal_map)).map_err(|err| log::warn!("{err:?}")).ok()
+            });
+            let transform = decal.instance().instance_transform();
+            let axis = |axis: Vector3<f32>| (Matrix4::from(decal.rotation) * axis.extend(0.0)).truncate().into();
+            raw.push(DecalRaw {
+                transform: transform.into(),
+                inverse_transform: transform.invert().unwrap_or(Matrix4::identity()).into(),
+                tangent: axis(Vector3::unit_x()),
+                blend: decal.blend.raw(),
+                up: axis(Vector3::unit_y()),
+                opacity: decal.opacity,
+                size: [decal.size.x, decal.size.z],
+                texture: textures.len() as u32,
+                normal_map: normal_map.as_ref().map_or(-1, |_| normal_maps.len() as i32),
+                // negative keeps the surface's own
+                reflectivity: decal.reflectivity.unwrap_or(-1.0),
+                roughness: decal.roughness.unwrap_or(-1.0),
+                padding: [0.0; 2],
+            });
+            textures.push(texture);
+            normal_maps.extend(normal_map);
+        }
+        if raw.is_empty() {
+            return None;
+        }
+        let textures = create_texture_array(device, queue, "Decal Textures", wgpu::TextureFormat::Rgba8UnormSrgb, &textures);
+        let normal_maps = create_texture_array(device, queue, "Decal Normal Maps", wgpu::TextureFormat::Rgba8Unorm, &normal_maps);
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Decal Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Decal Buffer"),
+            contents: cast_slice(&raw),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Decals"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&textures) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(&sampler) },
+                wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&normal_maps) },
+                wgpu::BindGroupEntry { binding: 3, resource: buffer.as_entire_binding() },
+            ],
+        });
+        Some((bind_group, raw.len() as u32))
+    }
+
+    fn shader_type() -> ShaderType {
+        ShaderType {
+            var_types: vec!["".into(), "".into(), "".into(), "<storage, read>".into()],
+            wgsl_types: vec!["texture_2d_array<f32>".into(), "sampler".into(), "texture_2d_array<f32>".into(), "array<Decal>".into()],
+        }
+    }
+
+    /// Blends the decals into `gbuffer`'s normals, diffuse and surface.
+    pub fn render(&self, encoder: &mut CommandEncoder, gbuffer: &UniformBinding<TextureLayer>, depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>, timestamp_writes: Option<RenderPassTimestampWrites>) {
+        let Some(bind_group) = self.bind_group.as_ref().filter(|_| self.enabled) else {
+            return;
+        };
+        let attachment = |view, load| Some(wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load,
+                store: wgpu::StoreOp::Store,
+            },
+        });
+        // the two passes are timed as one
+        let (base_timestamps, timestamp_writes) = timestamp_writes.map_or((None, None), |writes| (
+            Some(RenderPassTimestampWrites { end_of_pass_write_index: None, ..writes }),
+            Some(RenderPassTimestampWrites { beginning_of_pass_write_index: None, ..writes }),
+        ));
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT);
+        // the base pass copies the G-buffer to `layer`, then the decals read that copy while drawing to the G-buffer
+        let passes = [
+            ("Decal Base Render Pass", &self.base_shader, gbuffer, &self.layer, clear, base_timestamps),
+            ("Decal Render Pass", &self.shader, &self.layer, gbuffer, wgpu::LoadOp::Load, timestamp_writes),
+        ];
+        for (label, shader, source, target, load, timestamp_writes) in passes {
+            let target = &target.value;
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(label),
+                color_attachments: &[attachment(&target.normal.view, load), attachment(&target.diffuse.view, load), attachment(&target.surface.view, load)],
+                timestamp_writes,
+                occlusion_query_set: None,
+                depth_stencil_attachment: None,
+            });
+            shader.bind(&mut render_pass);
+            render_pass.set_bind_group(0, &source.binding, &[]);
+            render_pass.set_bind_group(1, &depth_texture.binding, &[]);
+            render_pass.set_bind_group(2, &screen_info.binding, &[]);
+            render_pass.set_bind_group(3, bind_group, &[]);
+            render_pass.draw(0..36, 0..self.decal_count);
+        }
+    }
+}
+
+fn load_decal_texture(path: &Path) -> anyhow::Result<RgbaImage> {
+    let bytes = load_resource(path)?;
+    let image = image::load_from_memory(&bytes).with_context(|| format!("Failed to load {}", path.display()))?.to_rgba8();
+    Ok(image::imageops::resize(&image, DECAL_TEXTURE_SIZE, DECAL_TEXTURE_SIZE, FilterType::Triangle))
+}
+
+/// One layer per image with its mip chain, at least one layer so it can always be bound.
+fn create_texture_array(device: &Device, queue: &Queue, label: &str, format: wgpu::TextureFormat, images: &[RgbaImage]) -> wgpu::TextureView {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d { width: DECAL_TEXTURE_SIZE, height: DECAL_TEXTURE_SIZE, depth_or_array_layers: images.len().max(1) as u32 },
+        mip_level_count: DECAL_MIPS,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    for (layer, image) in images.iter().enumerate() {
+        for mip in 0..DECAL_MIPS {
+            let size = DECAL_TEXTURE_SIZE >> mip;
+            let level = image::imageops::resize(image, size, size, FilterType::Triangle);
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: mip,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: layer as u32 },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &level,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * size),
+                    rows_per_image: Some(size),
+                },
+                wgpu::Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+            );
+        }
+    }
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    })
+}
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use winit::event_loop::EventLoop;
 use runner::common_main;
 
@@ -6,16 +8,47 @@ mod instance;
 mod runner;
 mod cube;
 mod light;
-#[allow(unused)]
 mod blur;
 mod texture_types;
 mod point_shadow;
+mod antialiasing;
+mod resolution;
+mod profiler;
+mod frustum;
+mod mesh_chunks;
+mod lod;
+mod hi_z;
+mod crystal_field;
+mod clusters;
+mod shadow_atlas;
+mod fog;
+mod ssr;
+mod reflection_probe;
+mod irradiance;
+mod light_probes;
+mod scene;
+mod decals;
+mod parallax;
+mod particles;
+mod animation;
 
 include!(concat!(env!("OUT_DIR"), "/resources.rs"));
 
+// where `--bake-irradiance` writes the volume when not given a path, it is embedded from there on the next build
+const IRRADIANCE_SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/res/cave/irradiance.bin");
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    let args = std::env::args().collect::<Vec<_>>();
+    if let Some(i) = args.iter().position(|arg| arg == "--bake-irradiance") {
+        let path = args.get(i + 1).map_or(Path::new(IRRADIANCE_SOURCE), Path::new);
+        if let Err(err) = runner::bake_irradiance(path).await {
+            log::error!("{err:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
     let event_loop = EventLoop::new().unwrap();
     common_main(event_loop).await;
 }
\ No newline at end of file
@@ -0,0 +1,168 @@
+use bespoke_engine::{binding::{create_layout, UniformBinding, WgslType}, model::Render, shader::Shader, surface_context::SurfaceCtx, texture::{DepthTexture, Texture}};
+use bytemuck::{Pod, Zeroable};
+use cgmath::Vector3;
+use wgpu::{CommandEncoder, RenderPassTimestampWrites};
+
+use crate::{clusters::ClusteredLights, game::ScreenInfo, shadow_atlas::ShadowAtlas};
+
+// the scattering is smooth enough to march at half resolution
+const FOG_SCALE: u32 = 2;
+
+/// How dusty the air of a level is.
+#[derive(Clone, Copy)]
+pub struct FogSettings {
+    // extinction per metre at and below `base_height`
+    pub density: f32,
+    // how quickly the fog thins above `base_height`, per metre
+    pub height_falloff: f32,
+    pub base_height: f32,
+    // -1 scatters light back towards the lights, 1 onwards past them, humid air sits around 0.6
+    pub anisotropy: f32,
+    pub albedo: Vector3<f32>,
+    pub steps: u32,
+    // fog past this distance isn't marched, which also bounds rays that miss the cave
+    pub max_distance: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            density: 0.0,
+            height_falloff: 0.0,
+            base_height: 0.0,
+            anisotropy: 0.6,
+            albedo: Vector3::new(1.0, 1.0, 1.0),
+            steps: 24,
+            max_distance: 60.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct FogParams {
+    albedo: [f32; 3],
+    density: f32,
+    base_height: f32,
+    height_falloff: f32,
+    anisotropy: f32,
+    steps: u32,
+    max_distance: f32,
+    padding: [f32; 3],
+}
+
+impl WgslType for FogParams {
+    fn wgsl_name() -> String {
+        "FogParams".into()
+    }
+}
+
+impl FogParams {
+    fn new(settings: &FogSettings, enabled: bool) -> Self {
+        Self {
+            albedo: settings.albedo.into(),
+            density: if enabled { settings.density } else { 0.0 },
+            base_height: settings.base_height,
+            height_falloff: settings.height_falloff,
+            anisotropy: settings.anisotropy,
+            steps: settings.steps,
+            max_distance: settings.max_distance,
+            padding: [0.0; 3],
+        }
+    }
+}
+
+/// Light shafts through dusty air. The scattering is ray marched through the lights' clusters
+/// and shadow maps at half resolution and then composited over the lit scene.
+pub struct VolumetricFog {
+    march_shader: Shader,
+    composite_shader: Shader,
+    scattering: UniformBinding<Texture>,
+    params_binding: UniformBinding<FogParams>,
+    pub settings: FogSettings,
+    pub enabled: bool,
+}
+
+impl VolumetricFog {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32, settings: FogSettings, depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>, clusters: &ClusteredLights, shadow_atlas: &ShadowAtlas) -> Self {
+        let params_binding = UniformBinding::new(surface_ctx.device(), "Fog Params", FogParams::new(&settings, true), None);
+        let march_shader = Shader::new_post_process(
+            include_str!("shaders/fog.wgsl"),
+            surface_ctx.device(),
+            wgpu::TextureFormat::Rgba16Float,
+            vec![&depth_texture.layout, &screen_info.layout, &clusters.lighting_layout, &shadow_atlas.layout, &params_binding.layout],
+            vec![&depth_texture.shader_type, &screen_info.shader_type, &ClusteredLights::shader_type(), &ShadowAtlas::shader_type(), &params_binding.shader_type]
+        );
+        let composite_shader = Shader::new_post_process(
+            include_str!("shaders/fog_composite.wgsl"),
+            surface_ctx.device(),
+            surface_ctx.config().format,
+            vec![&create_layout::<Texture>(surface_ctx.device()), &create_layout::<Texture>(surface_ctx.device())],
+            vec![&Texture::shader_type(), &Texture::shader_type()]
+        );
+        Self {
+            march_shader,
+            composite_shader,
+            scattering: Self::create_scattering(surface_ctx, width, height),
+            params_binding,
+            settings,
+            enabled: true,
+        }
+    }
+
+    fn create_scattering(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) -> UniformBinding<Texture> {
+        UniformBinding::new(surface_ctx.device(), "Fog Scattering", Texture::blank_texture(surface_ctx.device(), (width / FOG_SCALE).max(1), (height / FOG_SCALE).max(1), wgpu::TextureFormat::Rgba16Float), None)
+    }
+
+    pub fn resize(&mut self, surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) {
+        self.scattering = Self::create_scattering(surface_ctx, width, height);
+    }
+
+    /// Marches the fog and composites it with `scene` into `lit`.
+    pub fn render(&mut self, surface_ctx: &dyn SurfaceCtx, encoder: &mut CommandEncoder, scene: &UniformBinding<Texture>, lit: &UniformBinding<Texture>, depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>, clusters: &ClusteredLights, shadow_atlas: &ShadowAtlas, timestamp_writes: Option<RenderPassTimestampWrites>) {
+        self.params_binding.set_data(surface_ctx.device(), FogParams::new(&self.settings, self.enabled));
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Fog March Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.scattering.value.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                timestamp_writes,
+                occlusion_query_set: None,
+                depth_stencil_attachment: None,
+            });
+            self.march_shader.bind(&mut render_pass);
+            render_pass.set_bind_group(0, &depth_texture.binding, &[]);
+            render_pass.set_bind_group(1, &screen_info.binding, &[]);
+            render_pass.set_bind_group(2, &clusters.lighting_group, &[]);
+            render_pass.set_bind_group(3, &shadow_atlas.bind_group, &[]);
+            render_pass.set_bind_group(4, &self.params_binding.binding, &[]);
+            surface_ctx.screen_model().render(&mut render_pass);
+        }
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Fog Composite Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &lit.value.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                timestamp_writes: None,
+                occlusion_query_set: None,
+                depth_stencil_attachment: None,
+            });
+            self.composite_shader.bind(&mut render_pass);
+            render_pass.set_bind_group(0, &scene.binding, &[]);
+            render_pass.set_bind_group(1, &self.scattering.binding, &[]);
+            surface_ctx.screen_model().render(&mut render_pass);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use cgmath::{InnerSpace, Matrix, Matrix4, Vector3, Vector4};
+
+/// View frustum planes extracted from a view projection matrix, used to cull
+/// instances on the CPU where the GPU culling compute can't be used.
+#[derive(Clone, Copy)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    pub fn from_matrix(matrix: Matrix4<f32>) -> Self {
+        let r0 = matrix.row(0);
+        let r1 = matrix.row(1);
+        let r2 = matrix.row(2);
+        let r3 = matrix.row(3);
+        // the near plane uses the OpenGL -w..w depth range so that matrices built with
+        // cgmath::perspective are handled too, for wgpu style matrices this is just conservative
+        let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r3 + r2, r3 - r2].map(|plane| {
+            plane / plane.truncate().magnitude()
+        });
+        Self { planes }
+    }
+
+    pub fn planes(&self) -> [[f32; 4]; 6] {
+        self.planes.map(|plane| plane.into())
+    }
+
+    pub fn intersects_sphere(&self, center: Vector3<f32>, radius: f32) -> bool {
+        self.planes.iter().all(|plane| plane.truncate().dot(center) + plane.w >= -radius)
+    }
+
+    pub fn intersects_aabb(&self, min: Vector3<f32>, max: Vector3<f32>) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane normal
+            let positive = Vector3::new(
+                if plane.x >= 0.0 { max.x } else { min.x },
+                if plane.y >= 0.0 { max.y } else { min.y },
+                if plane.z >= 0.0 { max.z } else { min.z },
+            );
+            plane.truncate().dot(positive) + plane.w >= 0.0
+        })
+    }
+}
+
+pub fn aabb_intersects_sphere(min: Vector3<f32>, max: Vector3<f32>, center: Vector3<f32>, radius: f32) -> bool {
+    let closest = Vector3::new(center.x.clamp(min.x, max.x), center.y.clamp(min.y, max.y), center.z.clamp(min.z, max.z));
+    (closest - center).magnitude2() <= radius * radius
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct CullingStats {
+    pub tested: u32,
+    pub visible: u32,
+    // in the frustum but hidden in the Hi-Z pyramid
+    pub occluded: u32,
+}
+
+impl CullingStats {
+    pub fn add(&mut self, visible: bool) {
+        self.tested += 1;
+        if visible {
+            self.visible += 1;
+        }
+    }
+
+    /// Counts something that is drawn when it is in the frustum and not occluded, returning whether it is.
+    pub fn add_occludable(&mut self, in_frustum: bool, occluded: bool) -> bool {
+        let visible = in_frustum && !occluded;
+        self.add(visible);
+        if in_frustum && occluded {
+            self.occluded += 1;
+        }
+        visible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{perspective, Deg, Point3};
+
+    use super::*;
+
+    // a right angle field of view looking down -z from the origin, 0.1 to 100 deep
+    fn frustum() -> Frustum {
+        let view = Matrix4::look_at_rh(Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, -1.0), Vector3::unit_y());
+        Frustum::from_matrix(perspective(Deg(90.0), 1.0, 0.1, 100.0) * view)
+    }
+
+    #[test]
+    fn spheres_outside_any_plane_are_culled() {
+        let frustum = frustum();
+        assert!(frustum.intersects_sphere(Vector3::new(0.0, 0.0, -10.0), 1.0));
+        // behind, past the far plane, and off to each side
+        assert!(!frustum.intersects_sphere(Vector3::new(0.0, 0.0, 10.0), 1.0));
+        assert!(!frustum.intersects_sphere(Vector3::new(0.0, 0.0, -110.0), 1.0));
+        assert!(!frustum.intersects_sphere(Vector3::new(20.0, 0.0, -10.0), 1.0));
+        assert!(!frustum.intersects_sphere(Vector3::new(-20.0, 0.0, -10.0), 1.0));
+        assert!(!frustum.intersects_sphere(Vector3::new(0.0, 20.0, -10.0), 1.0));
+        assert!(!frustum.intersects_sphere(Vector3::new(0.0, -20.0, -10.0), 1.0));
+    }
+
+    #[test]
+    fn spheres_straddling_a_plane_are_kept() {
+        let frustum = frustum();
+        // the side planes are at 45 degrees, so x = 10 is on the right plane at this depth
+        assert!(frustum.intersects_sphere(Vector3::new(10.5, 0.0, -10.0), 1.0));
+        assert!(!frustum.intersects_sphere(Vector3::new(12.0, 0.0, -10.0), 1.0));
+        assert!(frustum.intersects_sphere(Vector3::new(0.0, 0.0, -100.5), 1.0));
+        assert!(frustum.intersects_sphere(Vector3::new(0.0, 0.0, 0.5), 1.0));
+    }
+
+    #[test]
+    fn boxes_outside_any_plane_are_culled() {
+        let frustum = frustum();
+        let unit = Vector3::new(1.0, 1.0, 1.0);
+        let at = |center: Vector3<f32>| (center - unit, center + unit);
+        let inside = at(Vector3::new(0.0, 0.0, -10.0));
+        assert!(frustum.intersects_aabb(inside.0, inside.1));
+        let straddling = at(Vector3::new(10.5, 0.0, -10.0));
+        assert!(frustum.intersects_aabb(straddling.0, straddling.1));
+        // containing the whole frustum
+        assert!(frustum.intersects_aabb(Vector3::new(-200.0, -200.0, -200.0), Vector3::new(200.0, 200.0, 200.0)));
+        for center in [Vector3::new(0.0, 0.0, 10.0), Vector3::new(0.0, 0.0, -110.0), Vector3::new(20.0, 0.0, -10.0), Vector3::new(0.0, -20.0, -10.0)] {
+            let (min, max) = at(center);
+            assert!(!frustum.intersects_aabb(min, max));
+        }
+    }
+
+    #[test]
+    fn planes_are_normalized() {
+        for plane in frustum().planes {
+            assert!((plane.truncate().magnitude() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn aabb_sphere_overlap() {
+        let (min, max) = (Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0));
+        assert!(aabb_intersects_sphere(min, max, Vector3::new(0.5, 0.5, 0.5), 0.1));
+        assert!(aabb_intersects_sphere(min, max, Vector3::new(1.5, 0.5, 0.5), 0.6));
+        assert!(!aabb_intersects_sphere(min, max, Vector3::new(1.5, 0.5, 0.5), 0.4));
+        // past the corner, further than the radius though within it on each axis
+        assert!(!aabb_intersects_sphere(min, max, Vector3::new(1.5, 1.5, 1.5), 0.6));
+    }
+
+    #[test]
+    fn occluded_counts_only_what_the_frustum_kept() {
+        let mut stats = CullingStats::default();
+        assert!(stats.add_occludable(true, false));
+        assert!(!stats.add_occludable(true, true));
+        assert!(!stats.add_occludable(false, true));
+        assert_eq!((stats.tested, stats.visible, stats.occluded), (3, 1, 1));
+    }
+}
@@ -1,12 +1,92 @@
-use std::{collections::HashMap, path::Path, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::HashMap, path::Path, time::{Instant, SystemTime, UNIX_EPOCH}};
 
 use bespoke_engine::{binding::{create_layout, simple_layout_entry, Binding, Descriptor, UniformBinding}, camera::{Camera, CameraRaw}, culling::CullingCompute, mesh::{self, MeshModel, ModelVertex}, model::{Model, Render, ToRaw}, shader::{Shader, ShaderConfig, ShaderType}, surface_context::SurfaceCtx, texture::{DepthTexture, Texture}, window::{BasicVertex, WindowConfig, WindowHandler}};
 use bytemuck::{bytes_of, NoUninit};
-use cgmath::{Vector2, Vector3};
-use wgpu::{util::DeviceExt, Buffer, Color, Features, Limits, RenderPass};
+use cgmath::{Deg, InnerSpace, Matrix4, Rad, SquareMatrix, Vector2, Vector3};
+use wgpu::{Color, Features, Limits, RenderPass};
 use winit::{dpi::PhysicalPosition, event::{KeyEvent, TouchPhase}, keyboard::{KeyCode, PhysicalKey::Code}};
 
-use crate::{cube::in_front, instance::Instance, light::Light, load_resource, point_shadow::PointShadowRenderer, texture_types::{CrystalDepth, DepthCube, TextureLayer}};
+use crate::{animation::{MaterialAnimationRaw, CRYSTAL_ANIMATION}, antialiasing::{AntiAliasing, TemporalAA}, blur::{BlurCompute, BlurInput}, clusters::{ClusteredLights, INITIAL_EXPOSURE}, crystal_field::{fill_geode, CrystalField, CrystalInstance}, cube::in_front, decals::Decals, fog::{FogSettings, VolumetricFog}, frustum::{CullingStats, Frustum}, hi_z::HiZBuffer, instance::{Instance, InstanceBuffer}, light::{Light, LightKind, ShadowSettings}, load_resource, lod::{bounding_radius, build_lods, select_lod, LodModel, LOD_LEVELS}, mesh_chunks::{load_obj, merge_triangles, ChunkedMesh}, parallax::{Parallax, ParallaxMaterial}, particles::Particles, point_shadow::PointShadowRenderer, profiler::Profiler, resolution::RenderScale, shadow_atlas::{ShadowAtlas, MAX_SHADOWED_LIGHTS}, irradiance::IrradianceVolume, light_probes::LightProbes, reflection_probe::ReflectionProbes, scene::{MaterialAnimation, Scene}, ssr::{ScreenSpaceReflections, SsrSettings}, texture_types::{CrystalDepth, TextureLayer}};
+
+// bounding sphere of the crystal model around its origin
+const CRYSTAL_RADIUS: f32 = 4.5;
+const CAVE_CHUNK_SIZE: f32 = 8.0;
+const GEODE_RADIUS: f32 = 15.0;
+const GEODE_CRYSTALS: usize = 20000;
+// crystals of the geode that glow, each one a small light
+const GLOWING_CRYSTALS: usize = 48;
+const GLOW_RADIUS: f32 = 6.0;
+const GLOW_LUMENS: f32 = 25.0;
+// culling targets of the crystal field, the camera followed by six faces per shadowed light
+const FIELD_CAMERA_TARGET: usize = 0;
+const FIELD_SHADOW_TARGET: usize = 1;
+// the most field crystals one shadow face draws, past this the rest it sees cast no shadow
+const FIELD_SHADOW_CAPACITY: u32 = 4096;
+const HEADLAMP_INNER_ANGLE: f32 = 0.35;
+const HEADLAMP_OUTER_ANGLE: f32 = 0.5;
+const HEADLAMP_RANGE: f32 = 30.0;
+// a bright hiking headlamp
+const HEADLAMP_LUMENS: f32 = 300.0;
+// dust hanging low over the cave floor
+const CAVE_FOG: FogSettings = FogSettings {
+    density: 0.04,
+    height_falloff: 0.25,
+    base_height: 0.0,
+    anisotropy: 0.6,
+    albedo: Vector3::new(0.9, 0.85, 0.8),
+    steps: 24,
+    max_distance: 60.0,
+};
+// where the cave is captured for reflections, the middle of the geode and around its shell
+const REFLECTION_PROBES: [[f32; 3]; 5] = [[1.0, 0.0, 0.0], [9.0, 0.0, 0.0], [-7.0, 0.0, 0.0], [1.0, 0.0, 8.0], [1.0, 0.0, -8.0]];
+// shadow maps are low resolution, so shadow casters never need full detail
+const SHADOW_LOD_BIAS: usize = 1;
+// decals and whatever else is placed on top of the cave mesh
+const CAVE_SCENE: &str = "res/cave/cave.scene";
+const CAVE_MESH: &str = "res/cave/valdenfer_jpg_1.obj";
+// the full detail crystal and a rough one made from its coarsest level of detail
+const CRYSTAL_TYPES: u32 = 2;
+
+fn start_camera(aspect: f32) -> Camera {
+    Camera {
+        eye: Vector3::new(1.0, 0.0, 0.0),
+        aspect,
+        fovy: 70.0,
+        znear: 0.1,
+        zfar: 100.0,
+        ground: 0.0,
+        sky: 0.0,
+    }
+}
+
+fn overhead_light() -> Light {
+    Light::new(Vector3::new(0.0, 5.0, 0.0), Vector3::new(1.0, 1.0, 1.0))
+}
+
+fn geode(center: Vector3<f32>) -> Vec<CrystalInstance> {
+    fill_geode(center, GEODE_RADIUS, GEODE_CRYSTALS, CRYSTAL_TYPES, (0.05, 0.2), 2.5, 1)
+}
+
+// every few crystals of the geode glow
+fn glow_lights(geode: &[CrystalInstance], center: Vector3<f32>) -> Vec<Light> {
+    geode.iter().step_by(GEODE_CRYSTALS / GLOWING_CRYSTALS).map(|crystal| {
+        // just inside the shell so the glow isn't buried in the wall
+        let position = center + (Vector3::from(crystal.position) - center) * 0.9;
+        let color = if crystal.crystal_type == 0 { Vector3::new(0.6, 0.2, 1.0) } else { Vector3::new(0.2, 0.5, 1.0) };
+        Light::new(position, color).with_lumens(GLOW_LUMENS).with_radius(GLOW_RADIUS).with_shadow(ShadowSettings { resolution: 128, far: GLOW_RADIUS, ..Default::default() })
+    }).collect()
+}
+
+// what the Hi-Z pyramid is tested with, the cave chunks and then the crystals outside the field
+fn occlusion_boxes(cave: &ChunkedMesh, crystals: &[Instance]) -> Vec<(Vector3<f32>, Vector3<f32>)> {
+    let chunks = cave.chunks.iter().map(|chunk| (chunk.min, chunk.max));
+    let crystals = crystals.iter().map(|instance| {
+        let radius = CRYSTAL_RADIUS * instance.max_scale();
+        let extent = Vector3::new(radius, radius, radius);
+        (instance.position - extent, instance.position + extent)
+    });
+    chunks.chain(crystals).collect()
+}
 
 pub struct Game {
     camera_binding: UniformBinding<Camera>,
@@ -19,7 +99,6 @@ pub struct Game {
     moving_bc_finger: Option<u64>,
     cube: Model,
     cube_instance: Instance,
-    cube_instance_buffer: Buffer,
     cube_shader: Shader,
     cube_backface_shader: Shader,
     cube_frontface_shader: Shader,
@@ -33,19 +112,63 @@ pub struct Game {
     // frontface_depth_texture: UniformBinding<DepthTexture>,
     crystal_depth: UniformBinding<CrystalDepth>,
     depth_texture: UniformBinding<DepthTexture>,
+    hi_z: HiZBuffer,
     // backface_blur_depth_storage: UniformBinding<StorageTexture>,
     // frontface_blur_depth_storage: UniformBinding<StorageTexture>,
     light: Light,
     light_uniform: UniformBinding<Light>,
+    // a spot light following the camera
+    headlamp: Light,
+    headlamp_on: bool,
+    // a directional light shining in from above
+    sky_light: Light,
+    sky_light_on: bool,
+    // lights placed in the scene, the glowing crystals
+    scene_lights: Vec<Light>,
+    // pulses the crystals' glow and `scene_lights` with it
+    crystal_animation: MaterialAnimation,
+    crystal_animation_binding: UniformBinding<MaterialAnimationRaw>,
+    // every light this frame, `light` followed by whichever of the headlamp and sky light are switched on and `scene_lights`
+    lights: Vec<Light>,
+    clusters: ClusteredLights,
+    fog: VolumetricFog,
+    // reflection probes and the irradiance volume
+    light_probes: LightProbes,
+    reflections: ScreenSpaceReflections,
+    decals: Decals,
+    parallax: Parallax,
+    particles: Particles,
     banana_model: MeshModel,
-    // blur: BlurCompute,
+    crystal_instances: Vec<Instance>,
+    // indices of the crystals drawn at full detail through banana_model
+    crystal_full_detail: Vec<usize>,
+    crystal_lods: LodModel,
+    // one buffer per simplified level, index 0 is level 1
+    crystal_lod_buffers: Vec<InstanceBuffer>,
+    shadow_instance_buffers: Vec<InstanceBuffer>,
+    crystal_field: CrystalField,
+    crystal_blur: BlurCompute,
     culling: CullingCompute,
-    cave_model: MeshModel,
+    cave: ChunkedMesh,
     cave_shader: Shader,
     layers: Vec<UniformBinding<TextureLayer>>,
     default_layer: UniformBinding<TextureLayer>,
     point_shadows: PointShadowRenderer,
-    depth_cube: UniformBinding<DepthCube>,
+    shadow_atlas: ShadowAtlas,
+    anti_aliasing: AntiAliasing,
+    aa_mode_binding: UniformBinding<u32>,
+    taa: TemporalAA,
+    // the lighting pass' output, before reflections and fog
+    lighting_texture: UniformBinding<Texture>,
+    lit_texture: UniformBinding<Texture>,
+    prev_camera_raw: CameraRaw,
+    // where last frame's depth buffer was rendered from, particles collide with it before this frame's is drawn
+    prev_view_proj: Matrix4<f32>,
+    prev_eye: Vector3<f32>,
+    jitter: [f32; 2],
+    render_scale: RenderScale,
+    render_size: (u32, u32),
+    profiler: Profiler,
 }
 
 #[repr(C)]
@@ -100,91 +223,126 @@ impl ToRaw for Vertex {
 impl Game {
     pub fn new(surface_ctx: &dyn SurfaceCtx) -> Self {
         let screen_size = [surface_ctx.size().0 as f32, surface_ctx.size().1 as f32];
-        let camera = Camera {
-            eye: Vector3::new(1.0, 0.0, 0.0),
-            aspect: screen_size[0] / screen_size[1],
-            fovy: 70.0,
-            znear: 0.1,
-            zfar: 100.0,
-            ground: 0.0,
-            sky: 0.0,
-        };
-        let screen_info_binding = UniformBinding::new(surface_ctx.device(), "Screen Info", ScreenInfo::new(screen_size, 0.0, camera.to_raw()), None);
+        let render_scale = RenderScale::new(1.0, cfg!(target_os = "android"));
+        let render_size = render_scale.render_size(surface_ctx.size());
+        let camera = start_camera(screen_size[0] / screen_size[1]);
+        let screen_info_binding = UniformBinding::new(surface_ctx.device(), "Screen Info", ScreenInfo::new([render_size.0 as f32, render_size.1 as f32], 0.0, camera.to_raw(), camera.to_raw(), [0.0; 2]), None);
         let camera_binding = UniformBinding::new(surface_ctx.device(), "Camera", camera.clone(), None);
         let start_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
         let (cube, cube_instance) = in_front(surface_ctx.device(), &camera);
-        let cube_instance_buffer = surface_ctx.device().create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Cube Instance Buffer"),
-                contents: &cube_instance.to_raw(),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
-            }
-        );
         // let material_buffer = Texture::blank_texture(surface_ctx.device(), surface_ctx.size().0, surface_ctx.size().1, surface_ctx.config().format);
         // let material_texture_binding = UniformBinding::new(surface_ctx.device(), "Material Storage Binding", material_buffer, None);
         // let normal_buffer = Texture::blank_texture(surface_ctx.device(), surface_ctx.size().0, surface_ctx.size().1, surface_ctx.config().format);
         // let normal_texture_binding = UniformBinding::new(surface_ctx.device(), "Normal Storage Binding", normal_buffer, None);
-        let default_layer = UniformBinding::new(surface_ctx.device(), "Default Layer", TextureLayer::new(surface_ctx), None);
-        let light = Light::new(Vector3::new(0.0, 5.0, 0.0), Vector3::new(1.0, 1.0, 1.0));
+        let default_layer = UniformBinding::new(surface_ctx.device(), "Default Layer", TextureLayer::new(surface_ctx, render_size.0, render_size.1), None);
+        let light = overhead_light();
         let light_uniform = UniformBinding::new(surface_ctx.device(), "Light", light, None);
-        let cube_shader = Shader::new_uniform(include_str!("shaders/cube.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 2], vec![&camera_binding, &screen_info_binding, &light_uniform], &[mesh::ModelVertex::desc() /*cube::Vertex::desc()*/, Instance::desc()], ShaderConfig::default());
-        let cube_backface_shader = Shader::new_uniform(include_str!("shaders/cube.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 2], vec![&camera_binding, &screen_info_binding, &light_uniform], &[mesh::ModelVertex::desc() /*cube::Vertex::desc()*/, Instance::desc()], ShaderConfig { face_cull: Some(wgpu::FrontFace::Cw), depth_only: true, depth_compare: wgpu::CompareFunction::Greater, ..Default::default() });
-        let cube_frontface_shader = Shader::new_uniform(include_str!("shaders/cube.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 2], vec![&camera_binding, &screen_info_binding, &light_uniform], &[mesh::ModelVertex::desc() /*cube::Vertex::desc()*/, Instance::desc()], ShaderConfig { depth_only: true, ..Default::default() });
+        let scene_path = Path::new(CAVE_SCENE);
+        let scene = Scene::load(scene_path);
+        let crystal_animation = scene.animations.get(CRYSTAL_ANIMATION).copied().unwrap_or_default();
+        // crystals glow at full strength unless the scene animates them
+        let crystal_animation_binding = UniformBinding::new(surface_ctx.device(), "Crystal Animation", crystal_animation.to_raw(1.0), None);
+        let cube_shader = Shader::new_uniform(include_str!("shaders/cube.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 3], vec![&camera_binding, &screen_info_binding, &light_uniform, &crystal_animation_binding], &[mesh::ModelVertex::desc() /*cube::Vertex::desc()*/, Instance::desc()], ShaderConfig::default());
+        let cube_backface_shader = Shader::new_uniform(include_str!("shaders/cube.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 2], vec![&camera_binding, &screen_info_binding, &light_uniform, &crystal_animation_binding], &[mesh::ModelVertex::desc() /*cube::Vertex::desc()*/, Instance::desc()], ShaderConfig { face_cull: Some(wgpu::FrontFace::Cw), depth_only: true, depth_compare: wgpu::CompareFunction::Greater, ..Default::default() });
+        let cube_frontface_shader = Shader::new_uniform(include_str!("shaders/cube.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 2], vec![&camera_binding, &screen_info_binding, &light_uniform, &crystal_animation_binding], &[mesh::ModelVertex::desc() /*cube::Vertex::desc()*/, Instance::desc()], ShaderConfig { depth_only: true, ..Default::default() });
         // let backface_depth_texture = DepthTexture::create_depth_texture(surface_ctx.device(), screen_size[0] as u32, screen_size[1] as u32, "Backface Depth Texture");
         // let backface_depth_texture = UniformBinding::new(surface_ctx.device(), "Backface Depth Texture", backface_depth_texture, None);
         // let frontface_depth_texture = DepthTexture::create_depth_texture(surface_ctx.device(), screen_size[0] as u32, screen_size[1] as u32, "Frontface Depth Texture");
         // let frontface_depth_texture = UniformBinding::new(surface_ctx.device(), "Frontface Depth Texture", frontface_depth_texture, None);
-        let crystal_depth = UniformBinding::new(surface_ctx.device(), "Crystal Depth", CrystalDepth::new(surface_ctx), None);
-        let depth_texture = DepthTexture::create_depth_texture(surface_ctx.device(), screen_size[0] as u32, screen_size[1] as u32, "Depth Texture");
+        let crystal_depth = UniformBinding::new(surface_ctx.device(), "Crystal Depth", CrystalDepth::new(surface_ctx, render_size.0, render_size.1), None);
+        let depth_texture = DepthTexture::create_depth_texture(surface_ctx.device(), render_size.0, render_size.1, "Depth Texture");
         let depth_texture = UniformBinding::new(surface_ctx.device(), "Depth Texture", depth_texture, None);
+        let mut hi_z = HiZBuffer::new(surface_ctx, &depth_texture, render_size);
         let mut banana_model = MeshModel::load_model(Some("Banana".into()), Path::new("res/Banana_OBJ/Banana.obj"), load_resource, surface_ctx.device(), surface_ctx.queue(), &create_layout::<Texture>(surface_ctx.device())).unwrap();
         banana_model.enable_material_binding = false;
+        let crystal_instances = vec![cube_instance.clone()];
         for model in &mut banana_model.models {
-            model.update_instances(vec![cube_instance.clone()], surface_ctx.device());
+            model.update_instances(crystal_instances.clone(), surface_ctx.device());
         }
+        let crystal_full_detail = (0..crystal_instances.len()).collect();
+        let crystal_lods = LodModel::load(Path::new("res/Banana_OBJ/Banana.obj"), surface_ctx.device()).unwrap();
+        let crystal_lod_buffers = (1..LOD_LEVELS).map(|level| InstanceBuffer::new(surface_ctx.device(), &format!("Crystal LOD {level} Instance Buffer"), crystal_instances.len())).collect();
+        let (crystal_vertices, crystal_indices) = merge_triangles(&load_obj(Path::new("res/Banana_OBJ/Banana.obj")).unwrap());
+        let crystal_extent = bounding_radius(&crystal_vertices) * 2.0;
+        let mut crystal_types = build_lods(crystal_vertices, crystal_indices, crystal_extent);
+        // the coarsest level doubles as a rough, faceted crystal type
+        let rough_crystal = crystal_types.pop().unwrap();
+        let crystal_types = vec![crystal_types.swap_remove(0), rough_crystal];
+        let geode = geode(camera.eye);
+        let headlamp = Light::spot(camera.eye, camera.get_forward_vec(), Vector3::new(1.0, 1.0, 1.0), HEADLAMP_INNER_ANGLE, HEADLAMP_OUTER_ANGLE).with_temperature(4000.0).with_lumens(HEADLAMP_LUMENS).with_radius(HEADLAMP_RANGE).with_shadow(ShadowSettings { far: HEADLAMP_RANGE, ..Default::default() });
+        let sky_light = Light::directional(Vector3::new(0.3, -1.0, 0.2), Vector3::new(1.0, 1.0, 1.0)).with_temperature(9000.0).with_intensity(0.5);
+        let scene_lights = glow_lights(&geode, camera.eye);
+        // the camera may see every crystal
+        let field_capacities = [vec![u32::MAX], vec![FIELD_SHADOW_CAPACITY; 6 * MAX_SHADOWED_LIGHTS]].concat();
+        let crystal_field = CrystalField::new(crystal_types, geode, field_capacities, &hi_z, surface_ctx.device());
+        let shadow_instance_buffers = (0..6 * MAX_SHADOWED_LIGHTS).map(|i| InstanceBuffer::new(surface_ctx.device(), &format!("Shadow Face {i} Instance Buffer"), crystal_instances.len())).collect();
         
         // let backface_blur_depth_storage = UniformBinding::new(surface_ctx.device(), "Backface Blur Depth Storage", StorageTexture::from_texture(Texture::blank_texture(surface_ctx.device(), screen_size[0] as u32 / 4, screen_size[1] as u32 / 4, wgpu::TextureFormat::Rgba32Float)), None);
         // let frontface_blur_depth_storage = UniformBinding::new(surface_ctx.device(), "Frontface Blur Depth Storage", StorageTexture::from_texture(Texture::blank_texture(surface_ctx.device(), screen_size[0] as u32 / 4, screen_size[1] as u32 / 4, wgpu::TextureFormat::Rgba32Float)), None);
-        // let blur = BlurCompute::new(include_str!("shaders/blur.wgsl"), &material_texture_binding.layout, &material_texture_binding.shader_type, surface_ctx.device());
+        // binding 2 is what the deferred pass reads as t_frontface_depth, its thickness is the backface depth minus this
+        let crystal_blur = BlurCompute::new(BlurInput::Depth(2), &crystal_depth.layout, &crystal_depth.shader_type, render_size, 2, 3, 2.0, surface_ctx.device());
         
+        let anti_aliasing = AntiAliasing::Fxaa;
+        let aa_mode_binding = UniformBinding::new(surface_ctx.device(), "Anti Aliasing Mode", anti_aliasing.final_pass_mode(), None);
         let post_process_shader = Shader::new_post_process(
             include_str!("shaders/post_process.wgsl"),
             surface_ctx.device(),
             surface_ctx.config().format,
-            vec![&create_layout::<Texture>(surface_ctx.device())], 
-            vec![&Texture::shader_type()]
+            vec![&create_layout::<Texture>(surface_ctx.device()), &aa_mode_binding.layout], 
+            vec![&Texture::shader_type(), &aa_mode_binding.shader_type]
         );
+        let taa = TemporalAA::new(surface_ctx, render_size.0, render_size.1, &depth_texture, &screen_info_binding);
+        let lighting_texture = UniformBinding::new(surface_ctx.device(), "Lighting Texture", Texture::blank_texture(surface_ctx.device(), render_size.0, render_size.1, surface_ctx.config().format), None);
+        let lit_texture = UniformBinding::new(surface_ctx.device(), "Lit Texture", Texture::blank_texture(surface_ctx.device(), render_size.0, render_size.1, surface_ctx.config().format), None);
+        let prev_camera_raw = camera.to_raw();
+        let prev_view_proj = camera.build_inverse_matrix().invert().unwrap();
+        let prev_eye = camera.eye;
+        let profiler = Profiler::new(surface_ctx);
         let combine_post_process_shader = Shader::new_uniform(
             include_str!("shaders/combine.wgsl"),
             surface_ctx.device(),
-            vec![surface_ctx.config().format; 3],
+            vec![surface_ctx.config().format; 5],
             vec![&default_layer], 
             &[BasicVertex::desc()],
             ShaderConfig { enable_depth_texture: false, ..Default::default() }
         );
         
-        let culling = CullingCompute::new("struct Instance { model_matrix: mat4x4<f32> }", "model_matrix", surface_ctx.device());
-        let point_shadows = PointShadowRenderer::new(surface_ctx, &[ModelVertex::desc(), Instance::desc()]);
-        let depth_cube = UniformBinding::new(surface_ctx.device(), "Depth Cube", DepthCube::new(surface_ctx.device(), 500, 500), None);
+        let culling = CullingCompute::new("struct Instance { model_matrix: mat4x4<f32>, normal_matrix: mat3x3<f32> }", "model_matrix", surface_ctx.device());
+        let point_shadows = PointShadowRenderer::new(surface_ctx, vec![ModelVertex::desc(), Instance::desc()]);
+        let shadow_atlas = ShadowAtlas::new(surface_ctx.device());
         
+        let clusters = ClusteredLights::new(&screen_info_binding, surface_ctx.device());
+        let cluster_shader_type = ClusteredLights::shader_type();
+        let cave = ChunkedMesh::load(Path::new(CAVE_MESH), CAVE_CHUNK_SIZE, &cube_instance, &scene.animations, surface_ctx.device(), surface_ctx.queue()).unwrap();
+        hi_z.set_boxes(&occlusion_boxes(&cave, &crystal_instances), surface_ctx.device());
+        // baked with the lights that are on when the level starts
+        let baked_lights = [&[light][..], &scene_lights].concat();
+        let reflection_probes = ReflectionProbes::bake(surface_ctx, &REFLECTION_PROBES.map(Vector3::from), &cave, &baked_lights, clusters.exposure);
+        let light_probes = LightProbes::new(surface_ctx.device(), &reflection_probes, &IrradianceVolume::load());
         let deferred_post_process_shader = Shader::new_post_process(
             include_str!("shaders/deferred_post_process.wgsl"),
             surface_ctx.device(),
             surface_ctx.config().format,
-            vec![&create_layout::<Texture>(surface_ctx.device()), &depth_texture.layout, &default_layer.layout, &screen_info_binding.layout, &crystal_depth.layout, &light_uniform.layout], 
-            vec![&Texture::shader_type(), &depth_texture.shader_type, &default_layer.shader_type, &screen_info_binding.shader_type, &crystal_depth.shader_type, &light_uniform.shader_type]
+            vec![&create_layout::<Texture>(surface_ctx.device()), &depth_texture.layout, &default_layer.layout, &screen_info_binding.layout, &crystal_depth.layout, &clusters.lighting_layout, &crystal_blur.output.layout, &light_probes.layout], 
+            vec![&Texture::shader_type(), &depth_texture.shader_type, &default_layer.shader_type, &screen_info_binding.shader_type, &crystal_depth.shader_type, &cluster_shader_type, &crystal_blur.output.shader_type, &LightProbes::shader_type()]
         );
 
+        let fog = VolumetricFog::new(surface_ctx, render_size.0, render_size.1, CAVE_FOG, &depth_texture, &screen_info_binding, &clusters, &shadow_atlas);
+
         let shadows_post_process_shader = Shader::new_post_process(
             include_str!("shaders/shadows.wgsl"),
             surface_ctx.device(),
             surface_ctx.config().format,
-            vec![&depth_texture.layout, &light_uniform.layout, &depth_cube.layout, &camera_binding.layout, &point_shadows.camera_layout], 
-            vec![&depth_texture.shader_type, &light_uniform.shader_type, &depth_cube.shader_type, &camera_binding.shader_type, &ShaderType::buffer_type(false, "mat4x4f".into())]
+            vec![&depth_texture.layout, &shadow_atlas.layout, &camera_binding.layout], 
+            vec![&depth_texture.shader_type, &ShadowAtlas::shader_type(), &camera_binding.shader_type]
         );
 
-        let cave_shader = Shader::new(include_str!("shaders/model.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 3], vec![&create_layout::<Texture>(surface_ctx.device()), &camera_binding.layout, &screen_info_binding.layout, &light_uniform.layout], vec![&Texture::shader_type(), &camera_binding.shader_type, &screen_info_binding.shader_type, &light_uniform.shader_type], &[mesh::ModelVertex::desc(), Instance::desc()], ShaderConfig::default());
-        let cave_model = MeshModel::load_model(Some("Cave".into()), Path::new("res/cave/valdenfer_jpg_1.obj"), load_resource, surface_ctx.device(), surface_ctx.queue(), &create_layout::<Texture>(surface_ctx.device())).unwrap();
+        let parallax = Parallax::new(surface_ctx.device());
+        // each material's animation goes in group 7, laid out the same as the crystals' one
+        let cave_shader = Shader::new(include_str!("shaders/model.wgsl"), surface_ctx.device(), vec![surface_ctx.config().format; 5], vec![&create_layout::<Texture>(surface_ctx.device()), &camera_binding.layout, &screen_info_binding.layout, &light_uniform.layout, &create_layout::<Texture>(surface_ctx.device()), &create_layout::<ParallaxMaterial>(surface_ctx.device()), &parallax.binding.layout, &crystal_animation_binding.layout], vec![&Texture::shader_type(), &camera_binding.shader_type, &screen_info_binding.shader_type, &light_uniform.shader_type, &Texture::shader_type(), &ParallaxMaterial::shader_type(), &parallax.binding.shader_type, &crystal_animation_binding.shader_type], &[mesh::ModelVertex::desc(), Instance::desc()], ShaderConfig::default());
+        let reflections = ScreenSpaceReflections::new(surface_ctx, render_size.0, render_size.1, SsrSettings::default(), &depth_texture, &default_layer, &screen_info_binding, &light_probes);
+        let decals = Decals::new(surface_ctx, render_size.0, render_size.1, &scene.decals, scene_path.parent().unwrap_or(Path::new("")), &default_layer, &depth_texture, &screen_info_binding);
+        let particles = Particles::new(surface_ctx, render_size.0, render_size.1, &scene.emitters, &scene_lights, &depth_texture, &screen_info_binding, &clusters);
         Self {
             camera_binding,
             camera,
@@ -196,7 +354,6 @@ impl Game {
             moving_bc_finger: None,
             cube,
             cube_instance,
-            cube_instance_buffer,
             cube_shader,
             cube_backface_shader,
             cube_frontface_shader,
@@ -210,19 +367,53 @@ impl Game {
             // frontface_depth_texture,
             crystal_depth,
             depth_texture,
+            hi_z,
             light,
             light_uniform,
+            headlamp,
+            headlamp_on: false,
+            sky_light,
+            sky_light_on: false,
+            scene_lights,
+            crystal_animation,
+            crystal_animation_binding,
+            lights: vec![],
+            clusters,
+            fog,
+            light_probes,
+            reflections,
+            decals,
+            parallax,
+            particles,
             banana_model,
+            crystal_instances,
+            crystal_full_detail,
+            crystal_lods,
+            crystal_lod_buffers,
+            shadow_instance_buffers,
+            crystal_field,
             // frontface_blur_depth_storage,
             // backface_blur_depth_storage,
-            // blur,
+            crystal_blur,
             culling,
-            cave_model,
+            cave,
             cave_shader,
             layers: vec![],
             default_layer,
             point_shadows,
-            depth_cube,
+            shadow_atlas,
+            anti_aliasing,
+            aa_mode_binding,
+            taa,
+            lighting_texture,
+            lit_texture,
+            prev_camera_raw,
+            prev_view_proj,
+            prev_eye,
+            jitter: [0.0; 2],
+            render_scale,
+            render_size,
+            profiler,
         }
     }
 }
@@ -231,18 +422,31 @@ impl WindowHandler for Game {
     fn resize(&mut self, surface_ctx: &dyn SurfaceCtx, new_size: Vector2<u32>) {
         self.camera.aspect = new_size.x as f32 / new_size.y as f32;
         self.screen_size = [new_size.x as f32, new_size.y as f32];
+        self.resize_render_targets(surface_ctx, (new_size.x, new_size.y));
+        self.profiler.resize(surface_ctx, new_size.x, new_size.y);
         // self.backface_depth_texture.set_data(surface_ctx.device(), DepthTexture::create_depth_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, "Back face Depth Texture"));
-        self.depth_texture.set_data(surface_ctx.device(), DepthTexture::create_depth_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, "Back face Depth Texture"));
         // self.backface_depth_texture.set_data(surface_ctx.device(), DepthTexture::create_depth_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, "Back face Depth Texture"));
         // self.frontface_depth_texture.set_data(surface_ctx.device(), DepthTexture::create_depth_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, "Back face Depth Texture"));
-        self.crystal_depth.set_data(surface_ctx.device(), CrystalDepth::new(surface_ctx));
-        self.default_layer.set_data(surface_ctx.device(), TextureLayer::new(surface_ctx));
         // self.material_texture_binding.set_data(surface_ctx.device(), Texture::blank_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, self.material_texture_binding.value.format));
         // self.normal_texture_binding.set_data(surface_ctx.device(), Texture::blank_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, self.normal_texture_binding.value.format));
     }
 
     fn render<'a: 'b, 'b>(&'a mut self, surface_ctx: &dyn SurfaceCtx, _render_pass: & mut RenderPass<'b>, delta: f64) {
+        self.profiler.begin_frame(surface_ctx);
+        self.hi_z.poll(surface_ctx.device());
+        // the simulation runs before this frame's depth buffer is drawn, so it collides with last frame's
+        let particles_start = Instant::now();
+        self.particles.simulate(self.camera.eye, self.prev_view_proj, self.prev_eye, delta as f32 / 1000.0, &self.depth_texture, surface_ctx.device(), surface_ctx.queue());
+        self.profiler.record_cpu("Particle Simulation", particles_start);
         self.update(delta);
+        if self.render_scale.update(delta) {
+            self.resize_render_targets(surface_ctx, surface_ctx.size());
+        }
+        self.jitter = if self.anti_aliasing == AntiAliasing::Taa {
+            self.taa.next_jitter(self.render_size_f32())
+        } else {
+            [0.0; 2]
+        };
         let mut encoder = surface_ctx.device().create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
         // {
         //     let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -262,6 +466,7 @@ impl WindowHandler for Game {
         //     self._render(surface_ctx, &mut render_pass, true, delta);
         // }
         // let default_layer = TextureLayer::new(surface_ctx);
+        let deferred_start = Instant::now();
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Deferred Render Pass"),
@@ -286,8 +491,22 @@ impl WindowHandler for Game {
                         load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
                         store: wgpu::StoreOp::Store,
                     },
+                }), Some(wgpu::RenderPassColorAttachment {
+                    view: &self.default_layer.value.surface.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                }), Some(wgpu::RenderPassColorAttachment {
+                    view: &self.default_layer.value.baked.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
                 })],
-                timestamp_writes: None,
+                timestamp_writes: self.profiler.pass_timestamps("Deferred"),
                 occlusion_query_set: None,
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: &self.depth_texture.value.view,
@@ -300,22 +519,82 @@ impl WindowHandler for Game {
             });
             self._render(surface_ctx, &mut render_pass, false, delta);
         }
+        self.profiler.record_cpu("Deferred", deferred_start);
+        if self.decals.is_active() {
+            let decals_start = Instant::now();
+            self.decals.render(&mut encoder, &self.default_layer, &self.depth_texture, &self.screen_info_binding, self.profiler.pass_timestamps("Decals"));
+            self.profiler.record_cpu("Decals", decals_start);
+        }
+        let clusters_start = Instant::now();
+        if let LightKind::Spot { direction, .. } = &mut self.headlamp.kind {
+            *direction = self.camera.get_forward_vec().normalize();
+        }
+        self.headlamp.position = self.camera.eye;
+        self.lights.clear();
+        self.lights.push(self.light);
+        if self.headlamp_on {
+            self.lights.push(self.headlamp);
+        }
+        if self.sky_light_on {
+            self.lights.push(self.sky_light);
+        }
+        let glow = self.crystal_animation.emission.map_or(1.0, |curve| curve.evaluate(self.time()).max(0.0));
+        self.lights.extend(self.scene_lights.iter().map(|light| light.with_intensity(light.intensity * glow)));
+        let frustum = self.camera_frustum();
+        let allocations = self.shadow_atlas.allocate(&self.lights, &frustum, self.camera.eye);
+        for (slot, allocation) in allocations.iter().enumerate() {
+            self.lights[allocation.light].shadow_slot = Some(slot as u32);
+        }
+        self.profiler.set_counter("Shadowed lights", allocations.len().to_string());
+        self.clusters.update(&self.lights, &self.camera, &self.screen_info_binding, surface_ctx.device(), surface_ctx.queue());
+        self.profiler.record_cpu("Light Clusters", clusters_start);
+        self.profiler.set_counter("Lights", self.lights.len().to_string());
         // self.layers.push(default_layer);
+        let camera_stats = self.select_crystal_lods(surface_ctx);
+        // an estimate, the full detail crystals are culled again on the GPU by `render_culled`, whose result never comes back
+        // to the CPU, and its frustum test may keep a few this one didn't
+        self.profiler.set_counter("Crystals drawn (estimate)", format!("{}/{}, {} occluded", camera_stats.visible, camera_stats.tested, camera_stats.occluded));
+        // the shadow faces are placed up front so the field culls for every view in one go
+        let shadowed_lights = self.shadow_atlas.allocations.iter().map(|allocation| self.lights[allocation.light]).collect::<Vec<_>>();
+        self.point_shadows.set_lights(&shadowed_lights, &self.camera, surface_ctx);
+        self.shadow_atlas.upload(&self.lights, &self.point_shadows.faces, surface_ctx.queue());
+        self.crystal_field.set_target(FIELD_CAMERA_TARGET, &self.camera_frustum(), true);
+        for i in 0..self.point_shadows.faces.len() {
+            self.crystal_field.set_target(FIELD_SHADOW_TARGET + i, &self.point_shadows.face_frustum(i), false);
+        }
+        self.crystal_field.cull(&self.hi_z, surface_ctx.device(), surface_ctx.queue());
+        self.profiler.set_counter("Field crystals", self.crystal_field.instance_count().to_string());
+        let crystal_start = Instant::now();
         let crystal_layer = UniformBinding::new(surface_ctx.device(), "Crystal Layer", self.render_crystal(surface_ctx, delta), None);
         self.layers.push(crystal_layer);
-        // self.blur.blur(&self.material_texture_binding, &self.frontface_blur_depth_storage, self.material_texture_binding.value.texture.size(), 0.25, surface_ctx.device(), surface_ctx.queue());
-
-        self.point_shadows.set_light(&self.light, surface_ctx);
+        self.profiler.record_cpu("Crystals", crystal_start);
+        let blur_start = Instant::now();
+        self.crystal_blur.blur(&self.crystal_depth, surface_ctx.device(), surface_ctx.queue());
+        self.profiler.record_cpu("Crystal Blur", blur_start);
 
-        self.cave_model.enable_material_binding = false;
-        for i in 0..6 {
-            let mut render_pass = self.point_shadows.setup_render(&self.depth_cube.value, surface_ctx, &mut encoder, i);
-            self.cave_model.render_instances(&mut render_pass, &self.cube_instance_buffer, 0..1);
-            self.banana_model.render(&mut render_pass);
+        let shadows_start = Instant::now();
+        self.cull_shadow_crystals(surface_ctx);
+        let mut chunk_stats = CullingStats::default();
+        for (slot, light) in shadowed_lights.iter().enumerate() {
+            let allocation = &self.shadow_atlas.allocations[slot];
+            for (face, &tile) in allocation.tiles.iter().enumerate() {
+                let i = allocation.first_face + face;
+                let (origin, range) = (self.point_shadows.faces[i].origin, self.point_shadows.faces[i].range);
+                let chunks = self.cave.visible_chunks_in_range(&self.point_shadows.face_frustum(i), origin, range, &mut chunk_stats);
+                let chunks = self.cave.select_lods(chunks, origin, std::f32::consts::FRAC_PI_2, SHADOW_LOD_BIAS);
+                let mut render_pass = self.point_shadows.setup_render(&self.shadow_atlas.depth, tile, &light.shadow, i == 0, surface_ctx, &mut encoder, i, self.profiler.pass_timestamps("Point Shadows"));
+                self.cave.render_chunks(&mut render_pass, &chunks, false, None, None, None);
+                let instances = &self.shadow_instance_buffers[i];
+                if instances.count > 0 {
+                    self.crystal_lods.render_instances(&mut render_pass, SHADOW_LOD_BIAS, &instances.buffer, 0..instances.count);
+                }
+                self.crystal_field.render(&mut render_pass, FIELD_SHADOW_TARGET + i);
+            }
         }
-        self.cave_model.enable_material_binding = true;
+        self.profiler.set_counter("Shadow chunks", format!("{}/{}", chunk_stats.visible, chunk_stats.tested));
 
         surface_ctx.queue().submit([encoder.finish()]);
+        self.profiler.record_cpu("Point Shadows", shadows_start);
         // self._render(surface_ctx, render_pass, false, delta);
     }
 
@@ -327,12 +606,66 @@ impl WindowHandler for Game {
 
     }
     
-    fn input_event(&mut self, _surface_ctx: &dyn SurfaceCtx, input_event: &KeyEvent) {
+    fn input_event(&mut self, surface_ctx: &dyn SurfaceCtx, input_event: &KeyEvent) {
         if let Code(code) = input_event.physical_key {
             if input_event.state.is_pressed() {
                 if !self.keys_down.contains(&code) {
                     self.keys_down.push(code);
                 }
+                if code == KeyCode::KeyT && !input_event.repeat {
+                    self.anti_aliasing = self.anti_aliasing.next();
+                    self.taa.reset();
+                    log::info!("Anti-aliasing: {:?}", self.anti_aliasing);
+                }
+                if code == KeyCode::KeyP && !input_event.repeat {
+                    self.profiler.visible = !self.profiler.visible;
+                }
+                if code == KeyCode::KeyH && !input_event.repeat {
+                    self.clusters.debug = !self.clusters.debug;
+                }
+                if code == KeyCode::KeyF && !input_event.repeat {
+                    self.headlamp_on = !self.headlamp_on;
+                }
+                if code == KeyCode::KeyG && !input_event.repeat {
+                    self.sky_light_on = !self.sky_light_on;
+                }
+                if code == KeyCode::KeyV && !input_event.repeat {
+                    self.fog.enabled = !self.fog.enabled;
+                }
+                if code == KeyCode::KeyJ && !input_event.repeat {
+                    self.reflections.enabled = !self.reflections.enabled;
+                }
+                if code == KeyCode::KeyK && !input_event.repeat {
+                    self.decals.enabled = !self.decals.enabled;
+                }
+                if code == KeyCode::KeyN && !input_event.repeat {
+                    self.particles.enabled = !self.particles.enabled;
+                }
+                if code == KeyCode::KeyL && !input_event.repeat {
+                    self.parallax.quality = self.parallax.quality.next();
+                    self.parallax.update(surface_ctx.device());
+                    log::info!("Parallax quality: {:?}", self.parallax.quality);
+                }
+                if code == KeyCode::KeyU && !input_event.repeat {
+                    self.parallax.self_shadowing = !self.parallax.self_shadowing;
+                    self.parallax.update(surface_ctx.device());
+                    log::info!("Parallax self-shadowing: {}", self.parallax.self_shadowing);
+                }
+                if code == KeyCode::KeyO && !input_event.repeat {
+                    self.hi_z.debug = !self.hi_z.debug;
+                }
+                if code == KeyCode::KeyR && !input_event.repeat {
+                    self.render_scale.auto = !self.render_scale.auto;
+                    log::info!("Automatic render scale: {}", self.render_scale.auto);
+                }
+                if (code == KeyCode::Minus || code == KeyCode::Equal) && !input_event.repeat {
+                    let step = if code == KeyCode::Minus { -0.1 } else { 0.1 };
+                    self.render_scale.auto = false;
+                    if self.render_scale.set_scale(self.render_scale.scale + step) {
+                        self.resize_render_targets(surface_ctx, surface_ctx.size());
+                    }
+                    log::info!("Render scale: {:.2}", self.render_scale.scale);
+                }
             } else {
                 if let Some(i) = self.keys_down.iter().position(|x| x == &code) {
                     self.keys_down.remove(i);
@@ -374,7 +707,8 @@ impl WindowHandler for Game {
     
     fn post_process_render<'a: 'b, 'c: 'b, 'b>(&'a mut self, surface_ctx: &'c dyn SurfaceCtx, render_pass: & mut RenderPass<'b>, surface_texture: &'c UniformBinding<Texture>) {
         // self.blur.blur(&self.backface_depth_texture, &self.backface_blur_depth_storage, self.backface_depth_texture.value.texture.size(), surface_context.device(), surface_context.queue());
-        let combined_layer = UniformBinding::new(surface_ctx.device(), "Combined Value", TextureLayer::new(surface_ctx), None);
+        let post_process_start = Instant::now();
+        let combined_layer = UniformBinding::new(surface_ctx.device(), "Combined Value", TextureLayer::new(surface_ctx, self.render_size.0, self.render_size.1), None);
         let mut encoder = surface_ctx.device().create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
         {
             let mut layers = vec![];
@@ -402,8 +736,22 @@ impl WindowHandler for Game {
                         load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
                         store: wgpu::StoreOp::Store,
                     },
+                }), Some(wgpu::RenderPassColorAttachment {
+                    view: &combined_layer.value.surface.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                }), Some(wgpu::RenderPassColorAttachment {
+                    view: &combined_layer.value.baked.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
                 })],
-                timestamp_writes: None,
+                timestamp_writes: self.profiler.pass_timestamps("Combine"),
                 occlusion_query_set: None,
                 depth_stencil_attachment: None,
             });
@@ -432,39 +780,90 @@ impl WindowHandler for Game {
                         store: wgpu::StoreOp::Store,
                     },
                 })],
-                timestamp_writes: None,
+                timestamp_writes: self.profiler.pass_timestamps("Shadows"),
                 occlusion_query_set: None,
                 depth_stencil_attachment: None,
             });
             self.shadows_post_process_shader.bind(&mut render_pass);
             render_pass.set_bind_group(0, &self.depth_texture.binding, &[]);
-            render_pass.set_bind_group(1, &self.light_uniform.binding, &[]);
-            render_pass.set_bind_group(2, &self.depth_cube.binding, &[]);
-            render_pass.set_bind_group(3, &self.camera_binding.binding, &[]);
-            render_pass.set_bind_group(4, &self.point_shadows.camera_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.shadow_atlas.bind_group, &[]);
+            render_pass.set_bind_group(2, &self.camera_binding.binding, &[]);
             surface_ctx.screen_model().render(&mut render_pass);
         }
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Lighting Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.lighting_texture.value.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                timestamp_writes: self.profiler.pass_timestamps("Lighting"),
+                occlusion_query_set: None,
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(&self.deferred_post_process_shader.pipeline);
+            render_pass.set_bind_group(0, &surface_texture.binding, &[]);
+            render_pass.set_bind_group(1, &self.depth_texture.binding, &[]);
+            render_pass.set_bind_group(2, &combined_layer.binding, &[]);
+            render_pass.set_bind_group(3, &self.screen_info_binding.binding, &[]);
+            render_pass.set_bind_group(4, &self.crystal_depth.binding, &[]);
+            
+            // render_pass.set_bind_group(6, &self.frontface_depth_texture.binding, &[]);
+            // render_pass.set_bind_group(6, &combined_layer.normal.binding, &[]);
+            render_pass.set_bind_group(5, &self.clusters.lighting_group, &[]);
+            render_pass.set_bind_group(6, &self.crystal_blur.output.binding, &[]);
+            render_pass.set_bind_group(7, &self.light_probes.bind_group, &[]);
+            // render_pass.set_bind_group(7, &self.point_shadows.camera_bind_group, &[]);
+            
+            surface_ctx.screen_model().render(&mut render_pass);
+        }
+        let scene = if self.reflections.enabled {
+            self.reflections.render(surface_ctx, &mut encoder, &self.lighting_texture, &self.depth_texture, &combined_layer, &self.screen_info_binding, &self.light_probes, self.profiler.pass_timestamps("SSR"));
+            &self.reflections.output
+        } else {
+            &self.lighting_texture
+        };
+        let scene = if self.particles.is_active() {
+            self.particles.render(surface_ctx, &mut encoder, scene, &self.depth_texture, &self.screen_info_binding, &self.clusters, self.profiler.pass_timestamps("Particles"));
+            &self.particles.output
+        } else {
+            scene
+        };
+        self.fog.render(surface_ctx, &mut encoder, scene, &self.lit_texture, &self.depth_texture, &self.screen_info_binding, &self.clusters, &self.shadow_atlas, self.profiler.pass_timestamps("Fog"));
+        if self.anti_aliasing == AntiAliasing::Taa {
+            self.taa.resolve(surface_ctx, &mut encoder, &self.lit_texture, &self.depth_texture, &self.screen_info_binding, self.profiler.pass_timestamps("TAA"));
+        }
         surface_ctx.queue().submit([encoder.finish()]);
+        self.profiler.record_cpu("Post Process", post_process_start);
+        let hi_z_start = Instant::now();
+        self.hi_z.build(&self.depth_texture, self.camera_view_proj(), surface_ctx.device(), surface_ctx.queue());
+        self.profiler.record_cpu("Hi-Z", hi_z_start);
+        self.profiler.end_frame(surface_ctx);
 
-        render_pass.set_pipeline(&self.deferred_post_process_shader.pipeline);
-        render_pass.set_bind_group(0, &surface_texture.binding, &[]);
-        render_pass.set_bind_group(1, &self.depth_texture.binding, &[]);
-        render_pass.set_bind_group(2, &combined_layer.binding, &[]);
-        render_pass.set_bind_group(3, &self.screen_info_binding.binding, &[]);
-        render_pass.set_bind_group(4, &self.crystal_depth.binding, &[]);
-        
-        // render_pass.set_bind_group(6, &self.frontface_depth_texture.binding, &[]);
-        // render_pass.set_bind_group(6, &combined_layer.normal.binding, &[]);
-        render_pass.set_bind_group(5, &self.light_uniform.binding, &[]);
-        // render_pass.set_bind_group(6, &self.depth_cube.binding, &[]);
-        // render_pass.set_bind_group(7, &self.point_shadows.camera_bind_group, &[]);
-        
+        self.aa_mode_binding.set_data(surface_ctx.device(), self.anti_aliasing.final_pass_mode());
+        self.prev_camera_raw = self.camera.to_raw();
+        self.prev_view_proj = self.camera_view_proj();
+        self.prev_eye = self.camera.eye;
+        let final_texture = if self.anti_aliasing == AntiAliasing::Taa {
+            self.taa.output()
+        } else {
+            &self.lit_texture
+        };
+        render_pass.set_pipeline(&self.post_process_shader.pipeline);
+        render_pass.set_bind_group(0, &final_texture.binding, &[]);
+        render_pass.set_bind_group(1, &self.aa_mode_binding.binding, &[]);
         surface_ctx.screen_model().render(render_pass);
+        self.hi_z.draw_debug(surface_ctx, render_pass);
+        self.profiler.draw(surface_ctx, render_pass);
     }
     
     fn limits() -> wgpu::Limits {
         Limits {
-            max_bind_groups: 7,
+            max_bind_groups: 8,
             max_texture_dimension_2d: 8976,
             ..Default::default()
         }
@@ -478,6 +877,11 @@ impl WindowHandler for Game {
         Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
     }
 
+    // enabled where the adapter has them, the profiler falls back to CPU timings everywhere else
+    fn optional_features() -> wgpu::Features {
+        Features::TIMESTAMP_QUERY
+    }
+
     fn surface_config() -> Option<bespoke_engine::window::SurfaceConfig> {
         None
     }
@@ -488,6 +892,110 @@ impl WindowHandler for Game {
 }
 
 impl Game {
+    /// Bakes the irradiance volume with the lights the level starts with, on a device without a window.
+    #[cfg(not(target_os = "android"))]
+    pub fn bake_irradiance(device: &wgpu::Device, queue: &wgpu::Queue) -> anyhow::Result<IrradianceVolume> {
+        let camera = start_camera(1.0);
+        let (_, cube_instance) = in_front(device, &camera);
+        let scene = Scene::load(Path::new(CAVE_SCENE));
+        let cave = ChunkedMesh::load(Path::new(CAVE_MESH), CAVE_CHUNK_SIZE, &cube_instance, &scene.animations, device, queue)?;
+        let lights = [&[overhead_light()][..], &glow_lights(&geode(camera.eye), camera.eye)].concat();
+        Ok(IrradianceVolume::bake(device, queue, &cave, &lights, INITIAL_EXPOSURE))
+    }
+
+    fn render_size_f32(&self) -> [f32; 2] {
+        [self.render_size.0 as f32, self.render_size.1 as f32]
+    }
+
+    // seconds since the game started, what the shaders animate by
+    fn time(&self) -> f32 {
+        (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() - self.start_time) as f32 / 1000.0
+    }
+
+    // every screen sized target is rendered at the scaled size and upscaled in the final pass
+    fn resize_render_targets(&mut self, surface_ctx: &dyn SurfaceCtx, surface_size: (u32, u32)) {
+        self.render_size = self.render_scale.render_size(surface_size);
+        let (width, height) = self.render_size;
+        self.depth_texture.set_data(surface_ctx.device(), DepthTexture::create_depth_texture(surface_ctx.device(), width, height, "Depth Texture"));
+        self.crystal_depth.set_data(surface_ctx.device(), CrystalDepth::new(surface_ctx, width, height));
+        self.crystal_blur.resize(self.render_size, surface_ctx.device());
+        self.default_layer.set_data(surface_ctx.device(), TextureLayer::new(surface_ctx, width, height));
+        self.lighting_texture.set_data(surface_ctx.device(), Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format));
+        self.lit_texture.set_data(surface_ctx.device(), Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format));
+        self.taa.resize(surface_ctx, width, height);
+        self.fog.resize(surface_ctx, width, height);
+        self.reflections.resize(surface_ctx, width, height);
+        self.decals.resize(surface_ctx, width, height);
+        self.particles.resize(surface_ctx, width, height);
+        self.hi_z.resize(self.render_size, surface_ctx.device());
+    }
+
+    // what the CPU culls cave chunks and coarse crystals against, and estimates the GPU culled crystals with
+    fn camera_frustum(&self) -> Frustum {
+        Frustum::from_matrix(self.camera_view_proj())
+    }
+
+    fn camera_view_proj(&self) -> Matrix4<f32> {
+        self.camera.build_inverse_matrix().invert().unwrap()
+    }
+
+    fn camera_fovy(&self) -> f32 {
+        Rad::from(Deg(self.camera.fovy)).0
+    }
+
+    /// Picks a level of detail for every crystal. Full detail crystals stay on the GPU culled
+    /// banana_model path, coarser ones are frustum culled here into one instance buffer per level.
+    /// Crystals hidden in the last pyramid tested are left out of both.
+    fn select_crystal_lods(&mut self, surface_ctx: &dyn SurfaceCtx) -> CullingStats {
+        let frustum = self.camera_frustum();
+        let fovy = self.camera_fovy();
+        let mut stats = CullingStats::default();
+        let mut full_detail = vec![];
+        let mut levels = vec![vec![]; LOD_LEVELS - 1];
+        for (i, instance) in self.crystal_instances.iter().enumerate() {
+            let radius = CRYSTAL_RADIUS * instance.max_scale();
+            let in_frustum = frustum.intersects_sphere(instance.position, radius);
+            // their boxes follow the cave chunks', see `occlusion_boxes`
+            let occluded = self.hi_z.occluded(self.cave.chunks.len() + i);
+            let visible = stats.add_occludable(in_frustum, occluded);
+            match select_lod(instance.position, radius, self.camera.eye, fovy, 0) {
+                // full detail crystals are still frustum culled on the GPU
+                0 if !occluded => full_detail.push(i),
+                level if level > 0 && visible => levels[level - 1].push(instance.raw()),
+                _ => {}
+            }
+        }
+        // rebuilding the culled instance list is only worth it when crystals change level
+        if full_detail != self.crystal_full_detail {
+            let instances = full_detail.iter().map(|i| self.crystal_instances[*i].clone()).collect::<Vec<_>>();
+            for model in &mut self.banana_model.models {
+                model.update_instances(instances.clone(), surface_ctx.device());
+            }
+            self.crystal_full_detail = full_detail;
+        }
+        for (buffer, instances) in self.crystal_lod_buffers.iter_mut().zip(levels) {
+            buffer.write(surface_ctx.device(), surface_ctx.queue(), &instances);
+        }
+        stats
+    }
+
+    /// Compacts the crystals visible from each face of the shadowed lights into that face's instance buffer, on the CPU
+    /// since there are too few of them to be worth a dispatch per face. The crystal field culls its faces on the GPU.
+    fn cull_shadow_crystals(&mut self, surface_ctx: &dyn SurfaceCtx) {
+        let mut stats = CullingStats::default();
+        for i in 0..self.point_shadows.faces.len() {
+            let frustum = self.point_shadows.face_frustum(i);
+            let visible = self.crystal_instances.iter().filter(|instance| {
+                let visible = frustum.intersects_sphere(instance.position, CRYSTAL_RADIUS * instance.max_scale());
+                stats.add(visible);
+                visible
+            }).map(|instance| instance.raw()).collect::<Vec<_>>();
+            self.shadow_instance_buffers[i].write(surface_ctx.device(), surface_ctx.queue(), &visible);
+        }
+        // exact, the shadow faces draw these buffers as they are
+        self.profiler.set_counter("Shadow crystals (CPU)", format!("{}/{}", stats.visible, stats.tested));
+    }
+
     fn update(&mut self, delta: f64) {
         let speed = 0.005 * delta as f32;
         if self.keys_down.contains(&KeyCode::KeyW) || self.moving_bc_finger.is_some() {
@@ -520,8 +1028,7 @@ impl Game {
         // self.material_storage_binding.set_data(surface_ctx.device(), StorageTexture::from_texture(Texture::blank_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, TextureFormat::Rgba32Float)));
         // self.normal_storage_binding.set_data(surface_ctx.device(), StorageTexture::from_texture(Texture::blank_texture(surface_ctx.device(), self.screen_size[0] as u32, self.screen_size[1] as u32, TextureFormat::Rgba32Float)));
         self.camera_binding.set_data(&surface_ctx.device(), self.camera.clone());
-        let time = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()-self.start_time) as f32 / 1000.0;
-        self.screen_info_binding.set_data(&surface_ctx.device(), ScreenInfo::new(self.screen_size, time, self.camera.to_raw()));
+        self.screen_info_binding.set_data(&surface_ctx.device(), ScreenInfo::new(self.render_size_f32(), self.time(), self.camera.to_raw(), self.prev_camera_raw, self.jitter));
         self.light_uniform.set_data(surface_ctx.device(), self.light);
 
         // self.cube = in_front(&surface_ctx.device(), &self.camera);
@@ -542,7 +1049,12 @@ impl Game {
         render_pass.set_bind_group(1, &self.camera_binding.binding, &[]);
         render_pass.set_bind_group(2, &self.screen_info_binding.binding, &[]);
         render_pass.set_bind_group(3, &self.light_uniform.binding, &[]);
-        self.cave_model.render_instances(render_pass, &self.cube_instance_buffer, 0..1);
+        render_pass.set_bind_group(6, &self.parallax.binding.binding, &[]);
+        let mut chunk_stats = CullingStats::default();
+        let chunks = self.cave.visible_chunks(&self.camera_frustum(), |i| self.hi_z.occluded(i), &mut chunk_stats);
+        let chunks = self.cave.select_lods(chunks, self.camera.eye, self.camera_fovy(), 0);
+        self.profiler.set_counter("Cave chunks", format!("{}/{}, {} occluded", chunk_stats.visible, chunk_stats.tested, chunk_stats.occluded));
+        self.cave.render_chunks(render_pass, &chunks, true, Some(4), Some(5), Some(7));
     }
 
     fn render_crystal(&mut self, surface_ctx: &dyn SurfaceCtx, delta: f64) -> TextureLayer {
@@ -555,7 +1067,7 @@ impl Game {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Crystal Back Shadow Render Pass"),
                 color_attachments: &[],
-                timestamp_writes: None,
+                timestamp_writes: self.profiler.pass_timestamps("Crystal Back"),
                 occlusion_query_set: None,
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: &self.crystal_depth.value.back.view,
@@ -573,7 +1085,7 @@ impl Game {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Crystal Front Shadow Render Pass"),
                 color_attachments: &[],
-                timestamp_writes: None,
+                timestamp_writes: self.profiler.pass_timestamps("Crystal Front"),
                 occlusion_query_set: None,
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: &self.crystal_depth.value.front.view,
@@ -587,7 +1099,7 @@ impl Game {
             render_pass.set_pipeline(&self.cube_frontface_shader.pipeline);
             self.render_crystal_inner(surface_ctx, &mut render_pass, false, delta);
         }
-        let crystal_layer = TextureLayer::new(surface_ctx);
+        let crystal_layer = TextureLayer::new(surface_ctx, self.render_size.0, self.render_size.1);
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Crystal Deferred Render Pass"),
@@ -605,8 +1117,15 @@ impl Game {
                         load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
                         store: wgpu::StoreOp::Store,
                     },
+                }), Some(wgpu::RenderPassColorAttachment {
+                    view: &crystal_layer.surface.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
                 })],
-                timestamp_writes: None,
+                timestamp_writes: self.profiler.pass_timestamps("Crystal Deferred"),
                 occlusion_query_set: None,
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: &self.depth_texture.value.view,
@@ -660,10 +1179,18 @@ impl Game {
         render_pass.set_bind_group(0, &self.camera_binding.binding, &[]);
         render_pass.set_bind_group(1, &self.screen_info_binding.binding, &[]);
         render_pass.set_bind_group(2, &self.light_uniform.binding, &[]);
+        render_pass.set_bind_group(3, &self.crystal_animation_binding.binding, &[]);
         
         // self.cube.render(render_pass);
-        self.banana_model.render(render_pass);
-        // self.banana_model.render_culled(&self.camera_binding, render_pass, &mut self.culling, surface_ctx);
+        if !self.crystal_full_detail.is_empty() {
+            self.banana_model.render_culled(&self.camera_binding, render_pass, &mut self.culling, surface_ctx);
+        }
+        for (i, instances) in self.crystal_lod_buffers.iter().enumerate() {
+            if instances.count > 0 {
+                self.crystal_lods.render_instances(render_pass, i + 1, &instances.buffer, 0..instances.count);
+            }
+        }
+        self.crystal_field.render(render_pass, FIELD_CAMERA_TARGET);
     }
 }
 
@@ -674,15 +1201,21 @@ pub struct ScreenInfo {
     time: f32,
     padding: f32,
     camera_raw: CameraRaw,
+    prev_camera_raw: CameraRaw,
+    jitter: [f32; 2],
+    padding2: [f32; 2],
 }
 
 impl ScreenInfo {
-    pub fn new(screen_size: [f32; 2], time: f32, camera_raw: CameraRaw) -> Self {
+    pub fn new(screen_size: [f32; 2], time: f32, camera_raw: CameraRaw, prev_camera_raw: CameraRaw, jitter: [f32; 2]) -> Self {
         Self {
             screen_size,
             time,
             padding: 0.0,
             camera_raw,
+            prev_camera_raw,
+            jitter,
+            padding2: [0.0; 2],
         }
     }
 }
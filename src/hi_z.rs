@@ -0,0 +1,390 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+
+use bespoke_engine::{binding::{create_layout, Uniform, UniformBinding, WgslType}, compute::ComputeShader, model::Render, shader::{Shader, ShaderType}, surface_context::SurfaceCtx, texture::DepthTexture};
+use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use cgmath::{Matrix4, Vector3};
+use wgpu::{util::{BufferInitDescriptor, DeviceExt}, BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, Buffer, Device, Queue, RenderPass, TextureFormat};
+
+const HI_Z_FORMAT: TextureFormat = TextureFormat::R32Float;
+// the debug view shows the first level at most this wide
+const DEBUG_WIDTH: u32 = 64;
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct HiZParams {
+    pub input_size: [u32; 2],
+    pub output_size: [u32; 2],
+}
+
+impl WgslType for HiZParams {
+    fn wgsl_name() -> String {
+        "Params".into()
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct OcclusionBox {
+    min: [f32; 3],
+    padding: f32,
+    max: [f32; 3],
+    padding2: f32,
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct OcclusionParams {
+    view_proj: [[f32; 4]; 4],
+    box_count: u32,
+    padding: [u32; 3],
+}
+
+/// The boxes tested against each pyramid and the readback of which were hidden.
+struct BoxQuery {
+    group: BindGroup,
+    results: Buffer,
+    readback: Buffer,
+    box_count: u32,
+    mapped: Arc<AtomicBool>,
+    // a test is in flight, no other is started until it has been read
+    pending: bool,
+}
+
+/// One mip of the pyramid.
+struct HiZLevel {
+    size: [u32; 2],
+    params: UniformBinding<HiZParams>,
+    // this mip alone, read by the next level and the debug view
+    sample: BindGroup,
+    storage: BindGroup,
+}
+
+/// Hierarchical depth pyramid built from the depth texture at the end of each frame, one
+/// mip per level. Next frame the crystal field's cull compute projects its crystals with
+/// the camera the pyramid was rendered from and tests them against it on the GPU.
+/// Whatever the CPU culls, cave chunks and coarse crystals, is registered as boxes that are
+/// tested right after the pyramid is built and read back a frame or two later.
+pub struct HiZBuffer {
+    first: ComputeShader,
+    reduce: ComputeShader,
+    sample_layout: BindGroupLayout,
+    storage_layout: BindGroupLayout,
+    levels: Vec<HiZLevel>,
+    // every mip, for the tests
+    pyramid: BindGroup,
+    view_proj: Matrix4<f32>,
+    // false until the pyramid has been built at the current size
+    built: bool,
+    occlusion: ComputeShader,
+    occlusion_layout: BindGroupLayout,
+    occlusion_params: Buffer,
+    query: Option<BoxQuery>,
+    // by box, from the last test that came back
+    occluded: Vec<bool>,
+    debug_shader: Shader,
+    pub debug: bool,
+}
+
+impl HiZBuffer {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, depth_texture: &UniformBinding<DepthTexture>, size: (u32, u32)) -> Self {
+        let device = surface_ctx.device();
+        let sample_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Hi-Z Sample Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            }],
+        });
+        let storage_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Hi-Z Storage Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: HI_Z_FORMAT,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            }],
+        });
+        let sample_shader_type = ShaderType {
+            var_types: vec!["".into()],
+            wgsl_types: vec!["texture_2d<f32>".into()],
+        };
+        let storage_shader_type = ShaderType {
+            var_types: vec!["".into()],
+            wgsl_types: vec!["texture_storage_2d<r32float, write>".into()],
+        };
+        let first_source = format!("{}
+t_input: $0,0;
+s_input: $0,1;
+
+fn load_input(coords: vec2u) -> f32 {{
+    return textureLoad(t_input, coords, 0);
+}}
+", include_str!("shaders/hi_z.wgsl"));
+        let first = ComputeShader::new(
+            &first_source,
+            &[&depth_texture.layout, &create_layout::<HiZParams>(device), &storage_layout],
+            vec![&depth_texture.shader_type, &HiZParams::shader_type(), &storage_shader_type],
+            device
+        );
+        let reduce_source = format!("{}
+t_input: $0,0;
+
+fn load_input(coords: vec2u) -> f32 {{
+    return textureLoad(t_input, coords, 0).r;
+}}
+", include_str!("shaders/hi_z.wgsl"));
+        let reduce = ComputeShader::new(
+            &reduce_source,
+            &[&sample_layout, &create_layout::<HiZParams>(device), &storage_layout],
+            vec![&sample_shader_type, &HiZParams::shader_type(), &storage_shader_type],
+            device
+        );
+        let debug_shader = Shader::new_post_process(
+            include_str!("shaders/hi_z_debug.wgsl"),
+            device,
+            surface_ctx.config().format,
+            vec![&sample_layout],
+            vec![&sample_shader_type]
+        );
+        let buffer_entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer { ty, has_dynamic_offset: false, min_binding_size: None },
+            count: None,
+        };
+        let occlusion_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Hi-Z Occlusion Layout"),
+            entries: &[
+                buffer_entry(0, wgpu::BufferBindingType::Storage { read_only: true }),
+                buffer_entry(1, wgpu::BufferBindingType::Uniform),
+                buffer_entry(2, wgpu::BufferBindingType::Storage { read_only: false }),
+            ],
+        });
+        let occlusion = ComputeShader::new(
+            include_str!("shaders/hi_z_occlusion.wgsl"),
+            &[&occlusion_layout, &sample_layout],
+            vec![
+                &ShaderType {
+                    var_types: vec!["<storage, read>".into(), "<uniform>".into(), "<storage, read_write>".into()],
+                    wgsl_types: vec!["array<OcclusionBox>".into(), "OcclusionParams".into(), "array<u32>".into()],
+                },
+                &sample_shader_type,
+            ],
+            device
+        );
+        let occlusion_params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Hi-Z Occlusion Params"),
+            size: size_of::<OcclusionParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let (levels, pyramid) = Self::create_levels(device, &sample_layout, &storage_layout, size);
+        Self {
+            first,
+            reduce,
+            sample_layout,
+            storage_layout,
+            levels,
+            pyramid,
+            view_proj: Matrix4::from_scale(1.0),
+            built: false,
+            occlusion,
+            occlusion_layout,
+            occlusion_params,
+            query: None,
+            occluded: vec![],
+            debug_shader,
+            debug: false,
+        }
+    }
+
+    fn create_levels(device: &Device, sample_layout: &BindGroupLayout, storage_layout: &BindGroupLayout, size: (u32, u32)) -> (Vec<HiZLevel>, BindGroup) {
+        let render_size = [size.0.max(1), size.1.max(1)];
+        let size = [render_size[0].div_ceil(2), render_size[1].div_ceil(2)];
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Hi-Z Pyramid"),
+            size: wgpu::Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
+            mip_level_count: u32::BITS - size[0].max(size[1]).leading_zeros(),
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HI_Z_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        });
+        let bind_group = |layout: &BindGroupLayout, view: &wgpu::TextureView| device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Hi-Z Level"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(view),
+            }],
+        });
+        let mut input_size = render_size;
+        let levels = (0..texture.mip_level_count()).map(|mip| {
+            let view = texture.create_view(&wgpu::TextureViewDescriptor {
+                base_mip_level: mip,
+                mip_level_count: Some(1),
+                ..Default::default()
+            });
+            // mips round down, odd sized inputs make some texels cover three input texels
+            let output_size = [(size[0] >> mip).max(1), (size[1] >> mip).max(1)];
+            let level = HiZLevel {
+                params: UniformBinding::new(device, "Hi-Z Params", HiZParams { input_size, output_size }, None),
+                sample: bind_group(sample_layout, &view),
+                storage: bind_group(storage_layout, &view),
+                size: output_size,
+            };
+            input_size = output_size;
+            level
+        }).collect::<Vec<_>>();
+        let pyramid = bind_group(sample_layout, &texture.create_view(&wgpu::TextureViewDescriptor::default()));
+        (levels, pyramid)
+    }
+
+    pub fn resize(&mut self, size: (u32, u32), device: &Device) {
+        (self.levels, self.pyramid) = Self::create_levels(device, &self.sample_layout, &self.storage_layout, size);
+        self.built = false;
+        // a test in flight still reads back, it was against the camera as it was then
+        self.occluded.fill(false);
+    }
+
+    /// Sets the world space boxes tested against every pyramid, each then looked up by its index with `occluded`.
+    pub fn set_boxes(&mut self, boxes: &[(Vector3<f32>, Vector3<f32>)], device: &Device) {
+        self.occluded = vec![false; boxes.len()];
+        if boxes.is_empty() {
+            self.query = None;
+            return;
+        }
+        let boxes = boxes.iter().map(|(min, max)| OcclusionBox { min: (*min).into(), padding: 0.0, max: (*max).into(), padding2: 0.0 }).collect::<Vec<_>>();
+        let boxes = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Hi-Z Occlusion Boxes"),
+            contents: cast_slice(&boxes),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let size = (self.occluded.len() * size_of::<u32>()) as u64;
+        let results = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Hi-Z Occlusion Results"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Hi-Z Occlusion Readback"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Hi-Z Occlusion"),
+            layout: &self.occlusion_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: boxes.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: self.occlusion_params.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: results.as_entire_binding() },
+            ],
+        });
+        // a readback of the old buffers may still complete, it is simply dropped
+        self.query = Some(BoxQuery { group, results, readback, box_count: self.occluded.len() as u32, mapped: Arc::new(AtomicBool::new(false)), pending: false });
+    }
+
+    /// Picks up the last box test if it has finished.
+    pub fn poll(&mut self, device: &Device) {
+        let Some(query) = &mut self.query else {
+            return;
+        };
+        if !query.pending {
+            return;
+        }
+        device.poll(wgpu::Maintain::Poll);
+        if !query.mapped.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        {
+            let data = query.readback.slice(..).get_mapped_range();
+            let results: &[u32] = cast_slice(&data);
+            self.occluded = results.iter().map(|result| *result != 0).collect();
+        }
+        query.readback.unmap();
+        query.pending = false;
+    }
+
+    /// Whether box `index` was hidden in the last pyramid tested, false until one has been.
+    pub fn occluded(&self, index: usize) -> bool {
+        self.occluded.get(index).copied().unwrap_or(false)
+    }
+
+    fn test_boxes(&mut self, device: &Device, queue: &Queue) {
+        let Some(query) = &mut self.query else {
+            return;
+        };
+        if query.pending {
+            return;
+        }
+        let params = OcclusionParams { view_proj: self.view_proj.into(), box_count: query.box_count, padding: [0; 3] };
+        queue.write_buffer(&self.occlusion_params, 0, bytes_of(&params));
+        self.occlusion.run_once(vec![&query.group, &self.pyramid], [query.box_count.div_ceil(64), 1, 1], device, queue);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Hi-Z Occlusion Encoder") });
+        encoder.copy_buffer_to_buffer(&query.results, 0, &query.readback, 0, query.readback.size());
+        queue.submit([encoder.finish()]);
+        query.pending = true;
+        let mapped = query.mapped.clone();
+        query.readback.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            if result.is_ok() {
+                mapped.store(true, Ordering::Release);
+            }
+        });
+    }
+
+    /// Builds the pyramid from the finished depth texture rendered with `view_proj` and tests the boxes against it.
+    pub fn build(&mut self, depth_texture: &dyn Uniform, view_proj: Matrix4<f32>, device: &Device, queue: &Queue) {
+        for (i, level) in self.levels.iter().enumerate() {
+            let groups = [level.size[0].div_ceil(8), level.size[1].div_ceil(8), 1];
+            if i == 0 {
+                self.first.run_once(vec![&depth_texture.binding(), &level.params.binding, &level.storage], groups, device, queue);
+            } else {
+                self.reduce.run_once(vec![&self.levels[i - 1].sample, &level.params.binding, &level.storage], groups, device, queue);
+            }
+        }
+        self.view_proj = view_proj;
+        self.built = true;
+        self.test_boxes(device, queue);
+    }
+
+    /// Layout of `pyramid`, a non-filterable `texture_2d<f32>` with every level as a mip.
+    pub fn layout(&self) -> &BindGroupLayout {
+        &self.sample_layout
+    }
+
+    pub fn pyramid(&self) -> &BindGroup {
+        &self.pyramid
+    }
+
+    /// The camera the pyramid was rendered from, or `None` before there is one to test against.
+    pub fn view_proj(&self) -> Option<Matrix4<f32>> {
+        self.built.then_some(self.view_proj)
+    }
+
+    /// Shows a coarse level of the pyramid in the bottom right corner of the screen.
+    pub fn draw_debug<'a: 'b, 'b>(&'a self, surface_ctx: &'b dyn SurfaceCtx, render_pass: &mut RenderPass<'b>) {
+        if !self.debug {
+            return;
+        }
+        let (width, height) = (surface_ctx.config().width as f32, surface_ctx.config().height as f32);
+        render_pass.set_viewport(width * 0.7, height * 0.7, width * 0.3, height * 0.3, 0.0, 1.0);
+        render_pass.set_pipeline(&self.debug_shader.pipeline);
+        let level = self.levels.iter().find(|level| level.size[0] <= DEBUG_WIDTH).unwrap_or(&self.levels[self.levels.len() - 1]);
+        render_pass.set_bind_group(0, &level.sample, &[]);
+        surface_ctx.screen_model().render(render_pass);
+        render_pass.set_viewport(0.0, 0.0, width, height, 0.0, 1.0);
+    }
+}
@@ -1,28 +1,43 @@
 use bespoke_engine::{binding::Descriptor, model::ToRaw, InstanceTrait};
-use bytemuck::bytes_of;
-use cgmath::{Deg, Quaternion, Rotation3, Vector3};
+use bytemuck::{bytes_of, cast_slice};
+use cgmath::{Deg, Matrix, Matrix3, Quaternion, Rotation3, SquareMatrix, Vector3};
+use wgpu::{Buffer, Device, Queue};
 
 #[derive(Clone)]
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
 }
 
 impl InstanceTrait for Instance {
     fn instance_transform(&self) -> cgmath::Matrix4<f32> {
-        cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)
+        cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation) * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
     }
 }
 
 impl Instance {
     pub fn raw(&self) -> InstanceRaw {
-        InstanceRaw {model: self.instance_transform().into() }
+        let model = self.instance_transform();
+        InstanceRaw { model: model.into(), normal: normal_matrix(Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate())) }
     }
+
+    /// The largest scale on any axis, for growing bounding spheres.
+    pub fn max_scale(&self) -> f32 {
+        self.scale.x.abs().max(self.scale.y.abs()).max(self.scale.z.abs())
+    }
+}
+
+/// Inverse transpose of the model's upper 3x3, which keeps normals perpendicular under non-uniform scale.
+/// Columns are padded to vec4 to match mat3x3 in storage buffers.
+pub fn normal_matrix(model: Matrix3<f32>) -> [[f32; 4]; 3] {
+    let normal = model.invert().map(|inverse| inverse.transpose()).unwrap_or(Matrix3::identity());
+    [normal.x.extend(0.0).into(), normal.y.extend(0.0).into(), normal.z.extend(0.0).into()]
 }
 
 impl Default for Instance {
     fn default() -> Self {
-        Self { position: Vector3::new(0.0, 0.0, 0.0), rotation: Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0)) }
+        Self { position: Vector3::new(0.0, 0.0, 0.0), rotation: Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0)), scale: Vector3::new(1.0, 1.0, 1.0) }
     }
 }
 
@@ -30,6 +45,46 @@ impl Default for Instance {
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Debug)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
+    normal: [[f32; 4]; 3],
+}
+
+/// A vertex buffer of instances rewritten every frame, growing when it runs out of room.
+pub struct InstanceBuffer {
+    pub buffer: Buffer,
+    pub count: u32,
+    capacity: usize,
+    label: String,
+}
+
+impl InstanceBuffer {
+    pub fn new(device: &Device, label: &str, capacity: usize) -> Self {
+        Self {
+            buffer: Self::create_buffer(device, label, capacity),
+            count: 0,
+            capacity,
+            label: label.into(),
+        }
+    }
+
+    fn create_buffer(device: &Device, label: &str, capacity: usize) -> Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (capacity.max(1) * size_of::<InstanceRaw>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn write(&mut self, device: &Device, queue: &Queue, instances: &[InstanceRaw]) {
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, &self.label, self.capacity);
+        }
+        if !instances.is_empty() {
+            queue.write_buffer(&self.buffer, 0, cast_slice(instances));
+        }
+        self.count = instances.len() as u32;
+    }
 }
 
 impl ToRaw for Instance {
@@ -73,6 +128,22 @@ impl Descriptor for Instance {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                // the normal matrix columns are padded to vec4, only xyz is read
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 20]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 24]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
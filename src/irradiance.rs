@@ -0,0 +1,314 @@
+use std::path::Path;
+
+use anyhow::bail;
+use bespoke_engine::binding::WgslType;
+use bytemuck::{Pod, Zeroable};
+
+use crate::load_resource;
+
+// where the baked volume is embedded from
+const IRRADIANCE_RESOURCE: &str = "res/cave/irradiance.bin";
+const FILE_MAGIC: &[u8; 4] = b"IRR1";
+// the constant ambient the lighting pass had before, kept as a floor under the bounce light
+const AMBIENT_FLOOR: f32 = 0.01;
+
+// second order spherical harmonics, 9 coefficients
+pub type ShCoefficients = [[f32; 4]; 9];
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy, Debug)]
+pub struct IrradianceGrid {
+    origin: [f32; 3],
+    padding_0: f32,
+    cell_size: [f32; 3],
+    padding_1: f32,
+    dims: [u32; 3],
+    padding_2: u32,
+}
+
+impl WgslType for IrradianceGrid {
+    fn wgsl_name() -> String {
+        "IrradianceGrid".into()
+    }
+}
+
+impl IrradianceGrid {
+    fn probe_count(&self) -> usize {
+        (self.dims[0] * self.dims[1] * self.dims[2]) as usize
+    }
+}
+
+/// Light bouncing around the cave, stored as spherical harmonics probes on a grid over the cave's bounds.
+/// Each probe's coefficients are premultiplied by the cosine lobe and divided by pi, so evaluating them
+/// in a direction gives the light a diffuse surface facing that way receives, the same units as the
+/// lighting pass' ambient. The alpha of the first coefficient is how much the probe can be trusted.
+pub struct IrradianceVolume {
+    pub grid: IrradianceGrid,
+    pub probes: Vec<ShCoefficients>,
+}
+
+impl IrradianceVolume {
+    /// A single probe of the constant ambient, what the cave looked like before any bake.
+    pub fn flat() -> Self {
+        Self {
+            grid: IrradianceGrid { origin: [0.0; 3], padding_0: 0.0, cell_size: [1.0; 3], padding_1: 0.0, dims: [1; 3], padding_2: 0 },
+            probes: vec![floor_coefficients()],
+        }
+    }
+
+    /// The baked volume embedded with the resources, or the flat ambient if there isn't one yet.
+    pub fn load() -> Self {
+        match load_resource(Path::new(IRRADIANCE_RESOURCE)).and_then(|bytes| Self::from_bytes(&bytes)) {
+            Ok(volume) => volume,
+            Err(err) => {
+                log::warn!("No baked irradiance, falling back to flat ambient: {err:?}");
+                Self::flat()
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let header = FILE_MAGIC.len() + std::mem::size_of::<IrradianceGrid>() + 4;
+        if bytes.len() < header || &bytes[..FILE_MAGIC.len()] != FILE_MAGIC {
+            bail!("Not an irradiance volume");
+        }
+        let grid: IrradianceGrid = bytemuck::pod_read_unaligned(&bytes[FILE_MAGIC.len()..header - 4]);
+        let count = u32::from_le_bytes(bytes[header - 4..header].try_into()?) as usize;
+        if count != grid.probe_count() || bytes.len() != header + count * std::mem::size_of::<ShCoefficients>() {
+            bail!("Irradiance volume of {count} probes doesn't match its {:?} grid", grid.dims);
+        }
+        let probes = bytes[header..].chunks_exact(std::mem::size_of::<ShCoefficients>()).map(bytemuck::pod_read_unaligned).collect();
+        Ok(Self { grid, probes })
+    }
+}
+
+/// Uniform light of the ambient floor, the coefficients of an unlit probe.
+fn floor_coefficients() -> ShCoefficients {
+    let mut coefficients = [[0.0; 4]; 9];
+    coefficients[0] = [AMBIENT_FLOOR / 0.282095, AMBIENT_FLOOR / 0.282095, AMBIENT_FLOOR / 0.282095, 0.0];
+    coefficients
+}
+
+// Baking needs a device that can read its captures back, which only the desktop build makes.
+#[cfg(not(target_os = "android"))]
+mod bake {
+    use std::path::Path;
+
+    use anyhow::Context;
+    use bytemuck::bytes_of;
+    use cgmath::{InnerSpace, SquareMatrix, Vector3, Vector4};
+    use wgpu::{Device, Queue};
+
+    use crate::{light::Light, mesh_chunks::ChunkedMesh, reflection_probe::{probe_faces, ProbeCapturer, PROBE_FORMAT}};
+
+    use super::{floor_coefficients, IrradianceGrid, IrradianceVolume, ShCoefficients, FILE_MAGIC};
+
+    // metres between probes, fewer where the cave would need more than the maximum
+    const PROBE_SPACING: f32 = 2.0;
+    const MAX_PROBES_PER_AXIS: u32 = 16;
+    // irradiance only needs the low frequencies of a capture, and Rgba16Float rows of 32 texels fill the 256 byte copy alignment
+    const CAPTURE_SIZE: u32 = 32;
+    // probes captured into one texture before it is read back
+    const CAPTURE_BATCH: u32 = 32;
+    // probes that see less of the inside of the cave than this are buried in rock
+    const MIN_COVERAGE: f32 = 0.5;
+
+    impl IrradianceGrid {
+        /// A grid spanning `min` to `max` with a probe on every corner.
+        fn fit(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+            let extent = max - min;
+            let dims = [extent.x, extent.y, extent.z].map(|extent| ((extent / PROBE_SPACING).ceil() as u32 + 1).clamp(2, MAX_PROBES_PER_AXIS));
+            Self {
+                origin: min.into(),
+                padding_0: 0.0,
+                cell_size: [extent.x / (dims[0] - 1) as f32, extent.y / (dims[1] - 1) as f32, extent.z / (dims[2] - 1) as f32],
+                padding_1: 0.0,
+                dims,
+                padding_2: 0,
+            }
+        }
+
+        /// Position of the probe at `index`, x varies fastest then y then z.
+        fn position(&self, index: usize) -> Vector3<f32> {
+            let index = index as u32;
+            let cell = [index % self.dims[0], index / self.dims[0] % self.dims[1], index / (self.dims[0] * self.dims[1])];
+            Vector3::new(
+                self.origin[0] + cell[0] as f32 * self.cell_size[0],
+                self.origin[1] + cell[1] as f32 * self.cell_size[1],
+                self.origin[2] + cell[2] as f32 * self.cell_size[2],
+            )
+        }
+    }
+
+    impl IrradianceVolume {
+        /// Captures the lit cave from every probe of a grid fitted to its bounds and projects the captures
+        /// onto spherical harmonics. Glowing crystals are among `lights`, so the walls they light bounce their colour.
+        pub fn bake(device: &Device, queue: &Queue, cave: &ChunkedMesh, lights: &[Light], exposure: f32) -> Self {
+            if cave.chunks.is_empty() {
+                return Self::flat();
+            }
+            let min = cave.chunks.iter().fold(Vector3::new(f32::MAX, f32::MAX, f32::MAX), |min, chunk| Vector3::new(min.x.min(chunk.min.x), min.y.min(chunk.min.y), min.z.min(chunk.min.z)));
+            let max = cave.chunks.iter().fold(Vector3::new(f32::MIN, f32::MIN, f32::MIN), |max, chunk| Vector3::new(max.x.max(chunk.max.x), max.y.max(chunk.max.y), max.z.max(chunk.max.z)));
+            let grid = IrradianceGrid::fit(min, max);
+            let directions = capture_directions();
+            let mut capturer = ProbeCapturer::new(device, CAPTURE_SIZE, lights, exposure);
+            let capture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Irradiance Capture"),
+                size: wgpu::Extent3d { width: CAPTURE_SIZE, height: CAPTURE_SIZE, depth_or_array_layers: 6 * CAPTURE_BATCH },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: PROBE_FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            });
+            let bytes_per_row = CAPTURE_SIZE * 8;
+            let face_size = (bytes_per_row * CAPTURE_SIZE) as u64;
+            let readback = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Irradiance Readback Buffer"),
+                size: face_size * 6 * CAPTURE_BATCH as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            });
+
+            let mut probes = Vec::with_capacity(grid.probe_count());
+            while probes.len() < grid.probe_count() {
+                let batch = (grid.probe_count() - probes.len()).min(CAPTURE_BATCH as usize);
+                let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Irradiance Bake") });
+                for i in 0..batch {
+                    capturer.capture(device, &mut encoder, &capture, i as u32 * 6, grid.position(probes.len() + i), cave);
+                }
+                encoder.copy_texture_to_buffer(
+                    wgpu::ImageCopyTexture {
+                        texture: &capture,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d::ZERO,
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    wgpu::ImageCopyBuffer {
+                        buffer: &readback,
+                        layout: wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: Some(bytes_per_row),
+                            rows_per_image: Some(CAPTURE_SIZE),
+                        },
+                    },
+                    wgpu::Extent3d { width: CAPTURE_SIZE, height: CAPTURE_SIZE, depth_or_array_layers: 6 * batch as u32 },
+                );
+                queue.submit([encoder.finish()]);
+
+                let slice = readback.slice(..face_size * 6 * batch as u64);
+                slice.map_async(wgpu::MapMode::Read, |_| {});
+                device.poll(wgpu::Maintain::Wait);
+                {
+                    let data = slice.get_mapped_range();
+                    for probe in data.chunks_exact(face_size as usize * 6) {
+                        probes.push(project(probe, &directions));
+                    }
+                }
+                readback.unmap();
+                log::info!("Baked {}/{} irradiance probes", probes.len(), grid.probe_count());
+            }
+            Self { grid, probes }
+        }
+
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes = FILE_MAGIC.to_vec();
+            bytes.extend_from_slice(bytes_of(&self.grid));
+            bytes.extend_from_slice(&(self.probes.len() as u32).to_le_bytes());
+            for probe in &self.probes {
+                bytes.extend_from_slice(bytes_of(probe));
+            }
+            bytes
+        }
+
+        /// Writes the volume to `path`, it is embedded on the next build when that is the resource folder's copy.
+        pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+            std::fs::write(path, self.to_bytes()).with_context(|| format!("Failed to write {}", path.display()))
+        }
+    }
+
+    /// The direction and solid angle of every texel of a capture, face by face.
+    fn capture_directions() -> Vec<(Vector3<f32>, f32)> {
+        let inverse_faces = probe_faces().map(|face| face.invert().unwrap());
+        let mut directions = Vec::with_capacity((6 * CAPTURE_SIZE * CAPTURE_SIZE) as usize);
+        for inverse in inverse_faces {
+            for y in 0..CAPTURE_SIZE {
+                for x in 0..CAPTURE_SIZE {
+                    let u = (x as f32 + 0.5) / CAPTURE_SIZE as f32 * 2.0 - 1.0;
+                    // texture rows go down, clip space up
+                    let v = 1.0 - (y as f32 + 0.5) / CAPTURE_SIZE as f32 * 2.0;
+                    let point = inverse * Vector4::new(u, v, 1.0, 1.0);
+                    let direction = point.truncate() / point.w;
+                    let texel_area = (2.0 / CAPTURE_SIZE as f32).powi(2);
+                    let solid_angle = texel_area / (1.0 + u * u + v * v).powf(1.5);
+                    directions.push((direction.normalize(), solid_angle));
+                }
+            }
+        }
+        directions
+    }
+
+    fn sh_basis(direction: Vector3<f32>) -> [f32; 9] {
+        let Vector3 { x, y, z } = direction;
+        [
+            0.282095,
+            0.488603 * y,
+            0.488603 * z,
+            0.488603 * x,
+            1.092548 * x * y,
+            1.092548 * y * z,
+            0.315392 * (3.0 * z * z - 1.0),
+            1.092548 * x * z,
+            0.546274 * (x * x - y * y),
+        ]
+    }
+
+    // the cosine lobe convolved into each band, divided by pi
+    const BAND_SCALE: [f32; 9] = [1.0, 2.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0, 0.25, 0.25, 0.25, 0.25, 0.25];
+
+    /// Projects the six Rgba16Float faces of one probe's capture onto spherical harmonics.
+    /// The capture's alpha marks the front of the cave, a probe that mostly sees rock from behind
+    /// keeps only the floor and zero trust so its neighbours are used instead.
+    fn project(capture: &[u8], directions: &[(Vector3<f32>, f32)]) -> ShCoefficients {
+        let mut coefficients = [[0.0f32; 4]; 9];
+        let mut total_weight = 0.0;
+        let mut coverage = 0.0;
+        for (texel, (direction, weight)) in capture.chunks_exact(8).zip(directions) {
+            let channel = |i: usize| f16_to_f32(u16::from_le_bytes([texel[i * 2], texel[i * 2 + 1]]));
+            let radiance = [channel(0), channel(1), channel(2)];
+            coverage += channel(3) * weight;
+            total_weight += weight;
+            for (coefficient, basis) in coefficients.iter_mut().zip(sh_basis(*direction)) {
+                for c in 0..3 {
+                    coefficient[c] += radiance[c] * basis * weight;
+                }
+            }
+        }
+        let coverage = coverage / total_weight;
+        if coverage < MIN_COVERAGE {
+            return floor_coefficients();
+        }
+        // the texel solid angles only approximately sum to the sphere
+        let normalize = 4.0 * std::f32::consts::PI / total_weight;
+        let floor = floor_coefficients();
+        for (i, coefficient) in coefficients.iter_mut().enumerate() {
+            for c in 0..3 {
+                coefficient[c] = coefficient[c] * normalize * BAND_SCALE[i] + floor[i][c];
+            }
+        }
+        coefficients[0][3] = coverage;
+        coefficients
+    }
+
+    fn f16_to_f32(bits: u16) -> f32 {
+        let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+        let exponent = (bits >> 10) & 0x1f;
+        let mantissa = (bits & 0x3ff) as f32;
+        match exponent {
+            0 => sign * mantissa * 2.0f32.powi(-24),
+            0x1f => if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN },
+            _ => sign * (1.0 + mantissa / 1024.0) * 2.0f32.powi(exponent as i32 - 15),
+        }
+    }
+}
@@ -1,39 +1,202 @@
 use bespoke_engine::{binding::{simple_layout_entry, Binding}, shader::ShaderType};
 use bytemuck::{bytes_of, NoUninit};
-use cgmath::Vector3;
+use std::f32::consts::PI;
+
+use cgmath::{InnerSpace, Vector3, Zero};
+
+// how far the original single light reached
+pub const DEFAULT_LIGHT_RADIUS: f32 = 50.0;
+// a 100 W incandescent bulb, about 1250 lumens
+pub const DEFAULT_LIGHT_INTENSITY: f32 = 100.0;
+
+/// How a light renders and samples its shadow map.
+#[derive(Clone, Copy)]
+pub struct ShadowSettings {
+    // texels along each face edge at full coverage, the atlas may hand out less
+    pub resolution: u32,
+    pub near: f32,
+    pub far: f32,
+    // depth bias of the shadow pipeline, in units of the depth format's precision
+    pub constant_bias: i32,
+    pub slope_bias: f32,
+    // how many shadow map texels the sampled position is pushed along the surface normal
+    pub normal_offset: f32,
+    // how far the screen space ray toward the light reaches in world units, catching the
+    // small occluders the shadow map is too coarse for, zero turns it off
+    pub contact_length: f32,
+    // how deep in world units a surface the ray passes behind is assumed to be, anything
+    // further behind doesn't occlude the ray
+    pub contact_thickness: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 512,
+            near: 0.1,
+            far: 100.0,
+            constant_bias: 2,
+            slope_bias: 2.0,
+            normal_offset: 1.0,
+            contact_length: 0.5,
+            contact_thickness: 0.15,
+        }
+    }
+}
+
+// cascades of a directional light's shadow, each one further from the camera
+pub const SHADOW_CASCADES: usize = 3;
+
+#[derive(Clone, Copy)]
+pub enum LightKind {
+    Point,
+    // angles from the direction to the edges of the full and fading cone, in radians
+    Spot { direction: Vector3<f32>, inner_angle: f32, outer_angle: f32 },
+    // the position is ignored, the light reaches everywhere
+    Directional { direction: Vector3<f32> },
+}
+
+impl LightKind {
+    /// How many shadow map faces a light of this kind renders.
+    pub fn shadow_faces(&self) -> usize {
+        match self {
+            LightKind::Point => 6,
+            LightKind::Spot { .. } => 1,
+            LightKind::Directional { .. } => SHADOW_CASCADES,
+        }
+    }
+
+    // must match the LIGHT_ constants in custom_shader_types.wgsl
+    pub fn raw_kind(&self) -> u32 {
+        match self {
+            LightKind::Point => 0,
+            LightKind::Spot { .. } => 1,
+            LightKind::Directional { .. } => 2,
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
-#[repr(C)]
 pub struct Light {
+    pub kind: LightKind,
     pub position: Vector3<f32>,
+    // only the tint, how bright the light is comes from `intensity`
     pub color: Vector3<f32>,
+    // candela for point and spot lights, lux for directional ones
+    pub intensity: f32,
+    // the falloff is windowed to reach zero here, which is what lets lights be assigned to clusters
+    pub radius: f32,
+    // which slot of the shadow atlas this light was given this frame, if any
+    pub shadow_slot: Option<u32>,
+    pub shadow: ShadowSettings,
 }
 
 impl Light {
     pub fn new(position: Vector3<f32>, color: Vector3<f32>) -> Self {
         Self {
+            kind: LightKind::Point,
             position,
             color,
+            intensity: DEFAULT_LIGHT_INTENSITY,
+            radius: DEFAULT_LIGHT_RADIUS,
+            shadow_slot: None,
+            shadow: ShadowSettings::default(),
         }
     }
 
-    fn to_raw(&self) -> RawLight {
+    pub fn spot(position: Vector3<f32>, direction: Vector3<f32>, color: Vector3<f32>, inner_angle: f32, outer_angle: f32) -> Self {
+        Self {
+            kind: LightKind::Spot { direction: direction.normalize(), inner_angle, outer_angle },
+            ..Self::new(position, color)
+        }
+    }
+
+    pub fn directional(direction: Vector3<f32>, color: Vector3<f32>) -> Self {
+        Self {
+            kind: LightKind::Directional { direction: direction.normalize() },
+            ..Self::new(Vector3::zero(), color)
+        }
+    }
+
+    pub fn direction(&self) -> Vector3<f32> {
+        match self.kind {
+            LightKind::Point => Vector3::zero(),
+            LightKind::Spot { direction, .. } | LightKind::Directional { direction } => direction,
+        }
+    }
+
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    /// Sets the intensity directly, in candela or for directional lights in lux.
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    /// Sets the intensity from the total luminous flux. A spot light spreads it over π steradians
+    /// whatever its cone, a quarter of a point light's sphere, so narrowing the cone doesn't brighten it.
+    /// Directional lights have no flux, use `with_intensity` for them.
+    pub fn with_lumens(mut self, lumens: f32) -> Self {
+        match self.kind {
+            LightKind::Point => self.intensity = lumens / (4.0 * PI),
+            LightKind::Spot { .. } => self.intensity = lumens / PI,
+            LightKind::Directional { .. } => {},
+        }
+        self
+    }
+
+    /// Sets the color to that of a black body at `kelvin`, candle flames are about 1900 K and daylight 6500 K.
+    pub fn with_temperature(mut self, kelvin: f32) -> Self {
+        self.color = kelvin_to_rgb(kelvin);
+        self
+    }
+
+    pub fn with_shadow(mut self, shadow: ShadowSettings) -> Self {
+        self.shadow = shadow;
+        self
+    }
+
+    pub fn to_raw(&self) -> RawLight {
         RawLight {
             position: self.position.into(),
+            radius: self.radius,
             color: self.color.into(),
-            padding1: 0.0,
-            padding2: 0.0,
+            shadow_slot: self.shadow_slot.map_or(-1, |slot| slot as i32),
+            direction: self.direction().into(),
+            kind: self.kind.raw_kind(),
+            cos_inner: match self.kind { LightKind::Spot { inner_angle, .. } => inner_angle.cos(), _ => -1.0 },
+            cos_outer: match self.kind { LightKind::Spot { outer_angle, .. } => outer_angle.cos(), _ => -1.0 },
+            intensity: self.intensity,
+            padding: 0.0,
         }
     }
 }
 
 #[derive(NoUninit, Clone, Copy)]
 #[repr(C)]
-struct RawLight {
+pub struct RawLight {
     position: [f32; 3],
-    padding1: f32,
+    radius: f32,
     color: [f32; 3],
-    padding2: f32,
+    shadow_slot: i32,
+    direction: [f32; 3],
+    kind: u32,
+    cos_inner: f32,
+    cos_outer: f32,
+    intensity: f32,
+    padding: f32,
+}
+
+/// Approximate color of a black body, fitted to the blackbody tables by Tanner Helland.
+pub fn kelvin_to_rgb(kelvin: f32) -> Vector3<f32> {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let red = if t <= 66.0 { 255.0 } else { 329.69873 * (t - 60.0).powf(-0.13320476) };
+    let green = if t <= 66.0 { 99.4708 * t.ln() - 161.11957 } else { 288.12217 * (t - 60.0).powf(-0.07551485) };
+    let blue = if t >= 66.0 { 255.0 } else if t <= 19.0 { 0.0 } else { 138.51773 * (t - 10.0).ln() - 305.0448 };
+    Vector3::new(red, green, blue).map(|channel| channel.clamp(0.0, 255.0) / 255.0)
 }
 
 impl Binding for Light {
@@ -51,4 +214,49 @@ impl Binding for Light {
             wgsl_types: vec!["Light".into()]
         }    
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daylight_is_white() {
+        let color = kelvin_to_rgb(6600.0);
+        for channel in [color.x, color.y, color.z] {
+            assert!(channel > 0.99, "{color:?}");
+        }
+    }
+
+    #[test]
+    fn candlelight_has_no_blue() {
+        let color = kelvin_to_rgb(1900.0);
+        assert_eq!(color.x, 1.0);
+        assert!(color.y > 0.4 && color.y < 0.6, "{color:?}");
+        assert_eq!(color.z, 0.0);
+    }
+
+    #[test]
+    fn warmer_is_redder_and_cooler_is_bluer() {
+        let warm = kelvin_to_rgb(2700.0);
+        assert!(warm.x > warm.y && warm.y > warm.z, "{warm:?}");
+        let cool = kelvin_to_rgb(12000.0);
+        assert!(cool.z > cool.y && cool.y > cool.x, "{cool:?}");
+        for pair in [1000.0, 2000.0, 3000.0, 4500.0, 6000.0].windows(2) {
+            assert!(kelvin_to_rgb(pair[1]).z >= kelvin_to_rgb(pair[0]).z);
+        }
+        for pair in [7000.0, 10000.0, 20000.0, 40000.0].windows(2) {
+            assert!(kelvin_to_rgb(pair[1]).x <= kelvin_to_rgb(pair[0]).x);
+        }
+    }
+
+    #[test]
+    fn temperatures_outside_the_fit_are_clamped() {
+        assert_eq!(kelvin_to_rgb(100.0), kelvin_to_rgb(1000.0));
+        assert_eq!(kelvin_to_rgb(100000.0), kelvin_to_rgb(40000.0));
+        for kelvin in [0.0, 1000.0, 5000.0, 40000.0, f32::MAX] {
+            let color = kelvin_to_rgb(kelvin);
+            assert!([color.x, color.y, color.z].iter().all(|channel| (0.0..=1.0).contains(channel)), "{color:?}");
+        }
+    }
+}
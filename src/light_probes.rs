@@ -0,0 +1,63 @@
+use bespoke_engine::shader::ShaderType;
+use bytemuck::{bytes_of, cast_slice};
+use wgpu::{util::{BufferInitDescriptor, DeviceExt}, BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, Device};
+
+use crate::{irradiance::IrradianceVolume, reflection_probe::ReflectionProbes};
+
+/// The baked reflection probes and irradiance volume in one bind group, every pass that
+/// samples them is already at the bind group limit.
+pub struct LightProbes {
+    pub layout: BindGroupLayout,
+    pub bind_group: BindGroup,
+}
+
+impl LightProbes {
+    pub fn new(device: &Device, reflections: &ReflectionProbes, irradiance: &IrradianceVolume) -> Self {
+        let mut entries = ReflectionProbes::texture_entries(wgpu::ShaderStages::FRAGMENT).to_vec();
+        for (binding, ty) in [(2, wgpu::BufferBindingType::Uniform), (3, wgpu::BufferBindingType::Storage { read_only: true }), (4, wgpu::BufferBindingType::Uniform)] {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            });
+        }
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Light Probe Layout"),
+            entries: &entries,
+        });
+        let probe_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Irradiance Probe Buffer"),
+            contents: cast_slice(&irradiance.probes),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let grid_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Irradiance Grid Buffer"),
+            contents: bytes_of(&irradiance.grid),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light Probes"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&reflections.view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(&reflections.sampler) },
+                wgpu::BindGroupEntry { binding: 2, resource: reflections.params.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 3, resource: probe_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 4, resource: grid_buffer.as_entire_binding() },
+            ],
+        });
+        Self { layout, bind_group }
+    }
+
+    pub fn shader_type() -> ShaderType {
+        ShaderType {
+            var_types: vec!["".into(), "".into(), "<uniform>".into(), "<storage, read>".into(), "<uniform>".into()],
+            wgsl_types: vec!["texture_2d_array<f32>".into(), "sampler".into(), "ReflectionProbeParams".into(), "array<IrradianceProbe>".into(), "IrradianceGrid".into()],
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::{collections::HashMap, ops::Range, path::Path};
+
+use bespoke_engine::{culling::AABB, model::Model};
+use cgmath::{InnerSpace, Vector2, Vector3};
+use wgpu::{Buffer, Device, RenderPass};
+
+use crate::{game::Vertex, instance::Instance, mesh_chunks::{load_obj, merge_triangles}};
+
+pub const LOD_LEVELS: usize = 4;
+// cells across the mesh extent for the first simplified level, halved for each level after it
+const BASE_CLUSTER_RESOLUTION: f32 = 64.0;
+
+// summed position, texture coordinates and normal of a cluster's vertices, and how many there are
+type ClusterSum = (Vector3<f32>, Vector2<f32>, Vector3<f32>, f32);
+
+/// Vertex clustering simplification: vertices are snapped to a grid of `cell_size`,
+/// merged per cell and triangles that collapse are dropped.
+pub fn simplify(vertices: &[Vertex], indices: &[u32], cell_size: f32) -> (Vec<Vertex>, Vec<u32>) {
+    let mut clusters: HashMap<[i32; 3], usize> = HashMap::new();
+    let mut sums: Vec<ClusterSum> = vec![];
+    let remap = vertices.iter().map(|vertex| {
+        let position = Vector3::from(vertex.position);
+        let key = (position / cell_size).map(|x| x.floor() as i32).into();
+        let cluster = *clusters.entry(key).or_insert_with(|| {
+            sums.push((Vector3::new(0.0, 0.0, 0.0), Vector2::new(0.0, 0.0), Vector3::new(0.0, 0.0, 0.0), 0.0));
+            sums.len() - 1
+        });
+        let sum = &mut sums[cluster];
+        sum.0 += position;
+        sum.1 += Vector2::from(vertex.tex_pos);
+        sum.2 += Vector3::from(vertex.normal);
+        sum.3 += 1.0;
+        cluster as u32
+    }).collect::<Vec<_>>();
+    let simplified_vertices = sums.into_iter().map(|(position, tex_pos, normal, count)| {
+        let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { normal };
+        Vertex {
+            position: (position / count).into(),
+            tex_pos: (tex_pos / count).into(),
+            normal: normal.into(),
+        }
+    }).collect();
+    let simplified_indices = indices.chunks_exact(3).filter_map(|triangle| {
+        let [a, b, c] = [remap[triangle[0] as usize], remap[triangle[1] as usize], remap[triangle[2] as usize]];
+        (a != b && b != c && a != c).then_some([a, b, c])
+    }).flatten().collect();
+    (simplified_vertices, simplified_indices)
+}
+
+/// Builds `LOD_LEVELS` levels from the full detail mesh, level 0 being the mesh itself.
+/// `extent` is the size of the mesh bounds, each level clusters twice as coarsely as the last.
+pub fn build_lods(vertices: Vec<Vertex>, indices: Vec<u32>, extent: f32) -> Vec<(Vec<Vertex>, Vec<u32>)> {
+    let mut levels = vec![];
+    for level in 1..LOD_LEVELS {
+        let cell_size = extent / (BASE_CLUSTER_RESOLUTION / (1 << (level - 1)) as f32);
+        let simplified = simplify(&vertices, &indices, cell_size.max(f32::EPSILON));
+        levels.push(simplified);
+    }
+    let mut lods = vec![(vertices, indices)];
+    for simplified in levels {
+        // a level that collapsed completely reuses the previous one
+        if simplified.1.is_empty() {
+            let previous = lods.last().unwrap().clone();
+            lods.push(previous);
+        } else {
+            lods.push(simplified);
+        }
+    }
+    lods
+}
+
+/// Picks a level from the fraction of the screen height the bounding sphere covers,
+/// `bias` pushes the choice towards coarser levels.
+pub fn select_lod(center: Vector3<f32>, radius: f32, eye: Vector3<f32>, fovy: f32, bias: usize) -> usize {
+    let distance = (center - eye).magnitude().max(0.001);
+    let screen_fraction = radius / (distance * (fovy / 2.0).tan());
+    let mut lod = 0;
+    let mut threshold = 0.5;
+    while lod + 1 < LOD_LEVELS && screen_fraction < threshold {
+        lod += 1;
+        threshold /= 2.0;
+    }
+    (lod + bias).min(LOD_LEVELS - 1)
+}
+
+/// Radius of the bounding sphere around the model origin.
+pub fn bounding_radius(vertices: &[Vertex]) -> f32 {
+    vertices.iter().map(|vertex| Vector3::from(vertex.position).magnitude()).fold(0.0, f32::max)
+}
+
+/// Levels of detail of a small instanced model such as a crystal, drawn from external instance buffers.
+pub struct LodModel {
+    pub levels: Vec<Model>,
+    pub radius: f32,
+}
+
+impl LodModel {
+    pub fn load(path: &Path, device: &Device) -> anyhow::Result<Self> {
+        // materials are not bound for instanced models, so everything is merged into one mesh
+        let (vertices, indices) = merge_triangles(&load_obj(path)?);
+        let radius = bounding_radius(&vertices);
+        let levels = build_lods(vertices, indices, radius * 2.0).into_iter().map(|(vertices, indices)| {
+            Model::new_instances(vertices, &indices, vec![Instance::default()], AABB { dimensions: [radius; 3] }, device)
+        }).collect();
+        Ok(Self { levels, radius })
+    }
+
+    pub fn render_instances<'a: 'b, 'b>(&'a self, render_pass: &mut RenderPass<'b>, level: usize, instances: &'a Buffer, range: Range<u32>) {
+        self.levels[level.min(self.levels.len() - 1)].render_instances(render_pass, instances, range);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    // a flat square of `cells` by `cells` quads, each 0.1 across
+    fn grid(cells: u32) -> (Vec<Vertex>, Vec<u32>) {
+        let vertices = (0..=cells).flat_map(|z| (0..=cells).map(move |x| Vertex {
+            position: [x as f32 * 0.1, 0.0, z as f32 * 0.1],
+            tex_pos: [x as f32 / cells as f32, z as f32 / cells as f32],
+            normal: [0.0, 1.0, 0.0],
+        })).collect();
+        let indices = (0..cells).flat_map(|z| (0..cells).flat_map(move |x| {
+            let corner = z * (cells + 1) + x;
+            [corner, corner + cells + 1, corner + 1, corner + 1, corner + cells + 1, corner + cells + 2]
+        })).collect();
+        (vertices, indices)
+    }
+
+    #[test]
+    fn simplify_keeps_a_mesh_finer_than_its_cells() {
+        let (vertices, indices) = grid(8);
+        let (simplified_vertices, simplified_indices) = simplify(&vertices, &indices, 0.01);
+        assert_eq!(simplified_vertices.len(), vertices.len());
+        assert_eq!(simplified_indices.len(), indices.len());
+    }
+
+    #[test]
+    fn simplify_merges_vertices_per_cell() {
+        let (vertices, indices) = grid(8);
+        let (simplified_vertices, simplified_indices) = simplify(&vertices, &indices, 0.2);
+        assert!(simplified_vertices.len() < vertices.len());
+        assert!(!simplified_indices.is_empty() && simplified_indices.len() < indices.len());
+        assert_eq!(simplified_indices.len() % 3, 0);
+        for triangle in simplified_indices.chunks_exact(3) {
+            assert!(triangle.iter().all(|&index| (index as usize) < simplified_vertices.len()));
+            assert!(triangle[0] != triangle[1] && triangle[1] != triangle[2] && triangle[0] != triangle[2]);
+        }
+        for vertex in &simplified_vertices {
+            assert_eq!(vertex.normal, [0.0, 1.0, 0.0]);
+            assert!(vertex.position.iter().all(|&x| (0.0..=0.8).contains(&x)));
+        }
+    }
+
+    #[test]
+    fn simplify_drops_triangles_inside_one_cell() {
+        let (vertices, indices) = grid(2);
+        let (simplified_vertices, simplified_indices) = simplify(&vertices, &indices, 1.0);
+        assert_eq!(simplified_vertices.len(), 1);
+        let center = Vector3::from(simplified_vertices[0].position) - Vector3::new(0.1, 0.0, 0.1);
+        assert!(center.magnitude() < 1e-6);
+        assert!(simplified_indices.is_empty());
+    }
+
+    #[test]
+    fn collapsed_levels_reuse_the_one_before() {
+        let (vertices, indices) = grid(2);
+        // the grid is 0.2 across, so every simplified level clusters it into a cell or few
+        let lods = build_lods(vertices.clone(), indices.clone(), 100.0);
+        assert_eq!(lods.len(), LOD_LEVELS);
+        for (level_vertices, level_indices) in &lods {
+            assert_eq!(level_vertices.len(), vertices.len());
+            assert_eq!(level_indices, &indices);
+        }
+    }
+
+    #[test]
+    fn select_lod_coarsens_with_distance() {
+        // a right angle field of view, so the screen is as tall as twice the distance
+        let eye = Vector3::new(0.0, 0.0, 0.0);
+        let at = |distance: f32| Vector3::new(0.0, 0.0, -distance);
+        assert_eq!(select_lod(at(1.0), 1.0, eye, FRAC_PI_2, 0), 0);
+        assert_eq!(select_lod(at(3.0), 1.0, eye, FRAC_PI_2, 0), 1);
+        assert_eq!(select_lod(at(5.0), 1.0, eye, FRAC_PI_2, 0), 2);
+        assert_eq!(select_lod(at(100.0), 1.0, eye, FRAC_PI_2, 0), LOD_LEVELS - 1);
+        // the eye inside the sphere
+        assert_eq!(select_lod(eye, 1.0, eye, FRAC_PI_2, 0), 0);
+    }
+
+    #[test]
+    fn select_lod_bias_stops_at_the_coarsest_level() {
+        let eye = Vector3::new(0.0, 0.0, 0.0);
+        let center = Vector3::new(0.0, 0.0, -1.0);
+        assert_eq!(select_lod(center, 1.0, eye, FRAC_PI_2, 1), 1);
+        assert_eq!(select_lod(center, 1.0, eye, FRAC_PI_2, LOD_LEVELS + 2), LOD_LEVELS - 1);
+    }
+}
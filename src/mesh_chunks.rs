@@ -0,0 +1,256 @@
+use std::{collections::{BTreeMap, HashMap}, path::Path};
+
+use anyhow::Context;
+use bespoke_engine::{binding::UniformBinding, culling::AABB, model::{Model, Render}, texture::Texture, InstanceTrait};
+use cave_mesh::obj::{lightmap_name, ObjMesh};
+use cgmath::{InnerSpace, Vector3};
+use wgpu::{Device, Queue, RenderPass};
+
+use crate::{animation::MaterialAnimationRaw, frustum::{aabb_intersects_sphere, CullingStats, Frustum}, game::Vertex, instance::Instance, load_resource, lod::{build_lods, select_lod}, parallax::ParallaxMaterial, scene::MaterialAnimation};
+
+/// Loads an OBJ resource along with the texture names from its MTL file.
+pub fn load_obj(path: &Path) -> anyhow::Result<ObjMesh> {
+    let source = String::from_utf8(load_resource(path)?)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mtl_source = source.lines()
+        .find_map(|line| line.strip_prefix("mtllib "))
+        .and_then(|mtl| load_resource(&directory.join(mtl.trim())).ok())
+        .and_then(|bytes| String::from_utf8(bytes).ok());
+    Ok(ObjMesh::parse(&source, mtl_source.as_deref()))
+}
+
+/// All of the mesh's triangles as a single mesh, for models drawn without binding materials.
+pub fn merge_triangles(mesh: &ObjMesh) -> (Vec<Vertex>, Vec<u32>) {
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    for chunk in split_into_chunks(mesh, f32::INFINITY) {
+        for (_, part_vertices, part_indices) in chunk.parts {
+            let offset = vertices.len() as u32;
+            vertices.extend(part_vertices);
+            indices.extend(part_indices.into_iter().map(|index| index + offset));
+        }
+    }
+    (vertices, indices)
+}
+
+/// The geometry of one spatial chunk, per material.
+pub struct ChunkData {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+    pub parts: Vec<(usize, Vec<Vertex>, Vec<u32>)>,
+}
+
+/// Splits the mesh into a grid of `chunk_size` cells, assigning each triangle to the
+/// cell containing its centroid. Chunk bounds grow to fit the triangles they own.
+/// Triangles without a position at every corner or without any area are dropped.
+pub fn split_into_chunks(mesh: &ObjMesh, chunk_size: f32) -> Vec<ChunkData> {
+    let (mesh_min, _) = mesh.bounds();
+    let mut cells: BTreeMap<[i32; 3], BTreeMap<usize, Vec<[[Option<usize>; 3]; 3]>>> = BTreeMap::new();
+    for (material, triangles) in mesh.triangles.iter().enumerate() {
+        for triangle in triangles {
+            let (Some(a), Some(b), Some(c)) = (triangle[0][0], triangle[1][0], triangle[2][0]) else {
+                continue;
+            };
+            let [a, b, c] = [a, b, c].map(|i| mesh.position(i));
+            // its face normal would be NaN
+            if (b - a).cross(c - a).magnitude2() == 0.0 {
+                continue;
+            }
+            let cell = ((a + b + c) / 3.0 - mesh_min) / chunk_size;
+            cells.entry([cell.x.floor() as i32, cell.y.floor() as i32, cell.z.floor() as i32])
+                .or_default()
+                .entry(material)
+                .or_default()
+                .push(*triangle);
+        }
+    }
+    cells.into_values().map(|materials| {
+        let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+        let parts = materials.into_iter().map(|(material, triangles)| {
+            let mut vertices = vec![];
+            let mut indices = vec![];
+            let mut lookup: HashMap<[Option<usize>; 3], u32> = HashMap::new();
+            for triangle in triangles {
+                let [a, b, c] = triangle.map(|corner| mesh.position(corner[0].unwrap()));
+                let face_normal = (b - a).cross(c - a).normalize();
+                for corner in triangle {
+                    let index = *lookup.entry(corner).or_insert_with(|| {
+                        let position = mesh.positions[corner[0].unwrap()];
+                        min = Vector3::new(min.x.min(position[0]), min.y.min(position[1]), min.z.min(position[2]));
+                        max = Vector3::new(max.x.max(position[0]), max.y.max(position[1]), max.z.max(position[2]));
+                        vertices.push(Vertex {
+                            position,
+                            tex_pos: corner[1].map(|i| mesh.tex_coords[i]).unwrap_or([0.0; 2]),
+                            normal: corner[2].map(|i| mesh.normals[i]).unwrap_or(face_normal.into()),
+                        });
+                        vertices.len() as u32 - 1
+                    });
+                    indices.push(index);
+                }
+            }
+            (material, vertices, indices)
+        }).collect();
+        ChunkData { min, max, parts }
+    }).collect()
+}
+
+pub struct MeshChunk {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+    pub center: Vector3<f32>,
+    pub radius: f32,
+    // one model per level of detail for each material
+    pub parts: Vec<(usize, Vec<Model>)>,
+}
+
+/// A large static mesh split into spatial chunks so each pass only draws what it can see.
+pub struct ChunkedMesh {
+    pub chunks: Vec<MeshChunk>,
+    pub materials: Vec<Option<UniformBinding<Texture>>>,
+    // baked static light in rgb and ambient occlusion in alpha, one per material
+    pub lightmaps: Vec<UniformBinding<Texture>>,
+    // height maps for parallax occlusion mapping, flat for materials without one
+    pub height_maps: Vec<UniformBinding<ParallaxMaterial>>,
+    // from the scene, materials it doesn't animate stand still and don't glow
+    pub animations: Vec<UniformBinding<MaterialAnimationRaw>>,
+}
+
+impl ChunkedMesh {
+    pub fn load(path: &Path, chunk_size: f32, instance: &Instance, animations: &HashMap<String, MaterialAnimation>, device: &Device, queue: &Queue) -> anyhow::Result<Self> {
+        let mesh = load_obj(path)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let materials = mesh.material_names.iter().map(|name| {
+            let texture = mesh.material_textures.get(name)?;
+            let bytes = load_resource(&directory.join(texture)).ok()?;
+            let texture = Texture::from_bytes(device, queue, &bytes, texture).with_context(|| format!("Failed to load {texture}"));
+            match texture {
+                Ok(texture) => Some(UniformBinding::new(device, name, texture, None)),
+                Err(err) => {
+                    log::warn!("{err:?}");
+                    None
+                }
+            }
+        }).collect();
+        let lightmaps = mesh.material_names.iter().map(|name| {
+            let lightmap = mesh.material_textures.get(name).and_then(|texture| {
+                let lightmap = lightmap_name(texture);
+                let bytes = load_resource(&directory.join(&lightmap)).ok()?;
+                Texture::from_bytes(device, queue, &bytes, &lightmap).with_context(|| format!("Failed to load {lightmap}")).map_err(|err| log::warn!("{err:?}")).ok()
+            });
+            match lightmap {
+                Some(lightmap) => Ok(lightmap),
+                None => unbaked_lightmap(device, queue),
+            }.map(|lightmap| UniformBinding::new(device, &format!("{name} Lightmap"), lightmap, None))
+        }).collect::<anyhow::Result<_>>()?;
+        // the shader marches in world units
+        let scale = (instance.scale.x + instance.scale.y + instance.scale.z) / 3.0;
+        let height_maps = mesh.material_names.iter().map(|name| {
+            let height_map = mesh.material_heights.get(name).and_then(|height| {
+                let bytes = load_resource(&directory.join(&height.texture)).ok()?;
+                let texture = Texture::from_bytes(device, queue, &bytes, &height.texture).with_context(|| format!("Failed to load {}", height.texture));
+                texture.map_err(|err| log::warn!("{err:?}")).ok().map(|texture| (texture, height.depth * scale))
+            });
+            match height_map {
+                Some((texture, depth)) => Ok(ParallaxMaterial::new(texture, depth)),
+                None => flat_height_map(device, queue).map(|texture| ParallaxMaterial::new(texture, 0.0)),
+            }.map(|height_map| UniformBinding::new(device, &format!("{name} Height Map"), height_map, None))
+        }).collect::<anyhow::Result<_>>()?;
+        let animations = mesh.material_names.iter().map(|name| {
+            let animation = animations.get(name).copied().unwrap_or_default();
+            UniformBinding::new(device, &format!("{name} Animation"), animation.to_raw(0.0), None)
+        }).collect();
+        Ok(Self::from_chunks(split_into_chunks(&mesh, chunk_size), materials, lightmaps, height_maps, animations, instance, device))
+    }
+
+    pub fn from_chunks(chunks: Vec<ChunkData>, materials: Vec<Option<UniformBinding<Texture>>>, lightmaps: Vec<UniformBinding<Texture>>, height_maps: Vec<UniformBinding<ParallaxMaterial>>, animations: Vec<UniformBinding<MaterialAnimationRaw>>, instance: &Instance, device: &Device) -> Self {
+        let transform = instance.instance_transform();
+        let chunks = chunks.into_iter().map(|chunk| {
+            let dimensions = (chunk.max - chunk.min) / 2.0;
+            // world space bounds of the transformed corners
+            let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+            let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+            for i in 0..8 {
+                let corner = Vector3::new(
+                    if i & 1 == 0 { chunk.min.x } else { chunk.max.x },
+                    if i & 2 == 0 { chunk.min.y } else { chunk.max.y },
+                    if i & 4 == 0 { chunk.min.z } else { chunk.max.z },
+                );
+                let world = (transform * corner.extend(1.0)).truncate();
+                min = Vector3::new(min.x.min(world.x), min.y.min(world.y), min.z.min(world.z));
+                max = Vector3::new(max.x.max(world.x), max.y.max(world.y), max.z.max(world.z));
+            }
+            let extent = (chunk.max - chunk.min).magnitude();
+            let parts = chunk.parts.into_iter().map(|(material, vertices, indices)| {
+                let lods = build_lods(vertices, indices, extent).into_iter().map(|(vertices, indices)| {
+                    Model::new_instances(vertices, &indices, vec![instance.clone()], AABB { dimensions: dimensions.into() }, device)
+                }).collect();
+                (material, lods)
+            }).collect();
+            MeshChunk { min, max, center: (min + max) / 2.0, radius: (max - min).magnitude() / 2.0, parts }
+        }).collect();
+        Self { chunks, materials, lightmaps, height_maps, animations }
+    }
+
+    /// Chunks inside the frustum that `occluded` doesn't rule out by index.
+    pub fn visible_chunks(&self, frustum: &Frustum, occluded: impl Fn(usize) -> bool, stats: &mut CullingStats) -> Vec<usize> {
+        (0..self.chunks.len()).filter(|i| {
+            stats.add_occludable(frustum.intersects_aabb(self.chunks[*i].min, self.chunks[*i].max), occluded(*i))
+        }).collect()
+    }
+
+    /// Chunks inside a shadow face frustum that are also within the light's reach.
+    pub fn visible_chunks_in_range(&self, frustum: &Frustum, center: Vector3<f32>, radius: f32, stats: &mut CullingStats) -> Vec<usize> {
+        (0..self.chunks.len()).filter(|i| {
+            let chunk = &self.chunks[*i];
+            let visible = aabb_intersects_sphere(chunk.min, chunk.max, center, radius) && frustum.intersects_aabb(chunk.min, chunk.max);
+            stats.add(visible);
+            visible
+        }).collect()
+    }
+
+    /// Pairs each chunk with the level of detail to draw it at when seen from `eye`.
+    pub fn select_lods(&self, chunks: Vec<usize>, eye: Vector3<f32>, fovy: f32, bias: usize) -> Vec<(usize, usize)> {
+        chunks.into_iter().map(|i| (i, select_lod(self.chunks[i].center, self.chunks[i].radius, eye, fovy, bias))).collect()
+    }
+
+    /// Draws the given (chunk, level of detail) pairs, binding each part's diffuse texture to group 0 when `bind_materials`
+    /// is set, its lightmap to `lightmap_group`, its height map to `height_map_group` and its animation to `animation_group`
+    /// when they are given.
+    pub fn render_chunks<'a: 'b, 'b>(&'a self, render_pass: &mut RenderPass<'b>, chunks: &[(usize, usize)], bind_materials: bool, lightmap_group: Option<u32>, height_map_group: Option<u32>, animation_group: Option<u32>) {
+        for (i, lod) in chunks {
+            for (material, lods) in &self.chunks[*i].parts {
+                if bind_materials {
+                    let Some(Some(texture)) = self.materials.get(*material) else {
+                        continue;
+                    };
+                    render_pass.set_bind_group(0, &texture.binding, &[]);
+                }
+                if let Some(group) = lightmap_group {
+                    render_pass.set_bind_group(group, &self.lightmaps[*material].binding, &[]);
+                }
+                if let Some(group) = height_map_group {
+                    render_pass.set_bind_group(group, &self.height_maps[*material].binding, &[]);
+                }
+                if let Some(group) = animation_group {
+                    render_pass.set_bind_group(group, &self.animations[*material].binding, &[]);
+                }
+                lods[*lod].render(render_pass);
+            }
+        }
+    }
+}
+
+/// A lightmap for materials that haven't been baked, no static light and nothing occluded.
+fn unbaked_lightmap(device: &Device, queue: &Queue) -> anyhow::Result<Texture> {
+    let mut bytes = vec![];
+    image::RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 0, 255])).write_to(&mut std::io::Cursor::new(&mut bytes), image::ImageFormat::Png)?;
+    Texture::from_bytes(device, queue, &bytes, "Unbaked Lightmap")
+}
+
+/// A height map for materials without one, drawn flat since their depth is zero.
+fn flat_height_map(device: &Device, queue: &Queue) -> anyhow::Result<Texture> {
+    let mut bytes = vec![];
+    image::GrayImage::from_pixel(1, 1, image::Luma([255])).write_to(&mut std::io::Cursor::new(&mut bytes), image::ImageFormat::Png)?;
+    Texture::from_bytes(device, queue, &bytes, "Flat Height Map")
+}
@@ -0,0 +1,133 @@
+use bespoke_engine::{binding::{simple_layout_entry, Binding, Resource, UniformBinding, WgslType}, shader::ShaderType, texture::Texture};
+use bytemuck::{bytes_of, Pod, Zeroable};
+use wgpu::Device;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ParallaxQuality {
+    Off,
+    Low,
+    High,
+}
+
+impl ParallaxQuality {
+    pub fn next(&self) -> Self {
+        match self {
+            ParallaxQuality::Off => ParallaxQuality::Low,
+            ParallaxQuality::Low => ParallaxQuality::High,
+            ParallaxQuality::High => ParallaxQuality::Off,
+        }
+    }
+
+    // (steps looking straight at a surface, steps at grazing angles, steps toward the light)
+    fn steps(&self) -> (u32, u32, u32) {
+        match self {
+            ParallaxQuality::Off => (0, 0, 0),
+            ParallaxQuality::Low => (4, 12, 4),
+            ParallaxQuality::High => (8, 32, 12),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct ParallaxSettingsRaw {
+    min_steps: u32,
+    max_steps: u32,
+    // zero without self-shadowing
+    shadow_steps: u32,
+    padding: u32,
+}
+
+impl WgslType for ParallaxSettingsRaw {
+    fn wgsl_name() -> String {
+        "ParallaxSettings".into()
+    }
+}
+
+/// Parallax occlusion mapping of the cave's height maps, shared by every material.
+pub struct Parallax {
+    pub quality: ParallaxQuality,
+    // darkens texels that the height map hides from the main light
+    pub self_shadowing: bool,
+    pub binding: UniformBinding<ParallaxSettingsRaw>,
+}
+
+impl Parallax {
+    pub fn new(device: &Device) -> Self {
+        let quality = ParallaxQuality::High;
+        let self_shadowing = true;
+        Self {
+            quality,
+            self_shadowing,
+            binding: UniformBinding::new(device, "Parallax Settings", Self::to_raw(quality, self_shadowing), None),
+        }
+    }
+
+    fn to_raw(quality: ParallaxQuality, self_shadowing: bool) -> ParallaxSettingsRaw {
+        let (min_steps, max_steps, shadow_steps) = quality.steps();
+        ParallaxSettingsRaw { min_steps, max_steps, shadow_steps: if self_shadowing { shadow_steps } else { 0 }, padding: 0 }
+    }
+
+    pub fn update(&mut self, device: &Device) {
+        self.binding.set_data(device, Self::to_raw(self.quality, self.self_shadowing));
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct HeightParams {
+    // world units from the height map's white to its black, zero draws the material flat
+    depth: f32,
+    padding: [f32; 3],
+}
+
+/// A material's height map, bound alongside its diffuse texture.
+pub struct ParallaxMaterial {
+    pub height_map: Texture,
+    params: HeightParams,
+}
+
+impl ParallaxMaterial {
+    pub fn new(height_map: Texture, depth: f32) -> Self {
+        Self { height_map, params: HeightParams { depth, padding: [0.0; 3] } }
+    }
+}
+
+impl Binding for ParallaxMaterial {
+    fn layout(_ty: Option<wgpu::BindingType>) -> Vec<wgpu::BindGroupLayoutEntry> {
+        vec![
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            simple_layout_entry(2),
+        ]
+    }
+
+    fn create_resources<'a>(&'a self) -> Vec<Resource> {
+        vec![
+            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.height_map.view)),
+            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.height_map.sampler)),
+            Resource::Simple(bytes_of(&self.params).to_vec()),
+        ]
+    }
+
+    fn shader_type() -> ShaderType {
+        ShaderType {
+            var_types: vec!["".into(), "".into(), "<uniform>".into()],
+            wgsl_types: vec!["texture_2d<f32>".into(), "sampler".into(), "HeightParams".into()],
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use bespoke_engine::{binding::{create_layout, UniformBinding}, compute::ComputeShader, model::Render, shader::{Shader, ShaderConfig, ShaderType}, surface_context::SurfaceCtx, texture::{DepthTexture, Texture}};
+use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use cgmath::{Matrix4, SquareMatrix, Vector3};
+use wgpu::{util::{BufferInitDescriptor, DeviceExt}, BindGroup, BindGroupLayoutDescriptor, Buffer, CommandEncoder, Device, Queue, RenderPassTimestampWrites};
+
+use crate::{clusters::ClusteredLights, game::ScreenInfo, light::Light, scene::{EmitterAttachment, EmitterDesc, ParticleKind}};
+
+// shared by every emitter, the ones past it are dropped
+const MAX_PARTICLES: u32 = 8192;
+const SIMULATE_WORKGROUP: u32 = 64;
+
+/// How one kind of particle looks and moves.
+struct ParticlePreset {
+    // particles a second
+    rate: f32,
+    // seconds, each particle lives between three quarters and five quarters of it
+    lifetime: f32,
+    velocity: Vector3<f32>,
+    // random speed added in any direction at spawn
+    spread: f32,
+    // fraction of gravity
+    gravity: f32,
+    // fraction of velocity lost a second
+    drag: f32,
+    // strength of the slow swirl that keeps floating particles drifting
+    wander: f32,
+    // world radius of the billboard
+    size: f32,
+    // how much longer the billboard gets along its velocity, per metre a second
+    stretch: f32,
+    // lit by the cave's lights
+    albedo: Vector3<f32>,
+    // light of its own, tinted by the crystal for crystal emitters
+    emission: Vector3<f32>,
+    opacity: f32,
+    // fraction of speed kept when hitting the cave, negative dies instead
+    bounce: f32,
+}
+
+impl ParticleKind {
+    fn preset(&self) -> ParticlePreset {
+        match self {
+            // motes hanging in the air, only seen where a light catches them
+            ParticleKind::Dust => ParticlePreset {
+                rate: 60.0,
+                lifetime: 10.0,
+                velocity: Vector3::new(0.0, 0.0, 0.0),
+                spread: 0.02,
+                gravity: 0.002,
+                drag: 1.0,
+                wander: 0.05,
+                size: 0.012,
+                stretch: 0.0,
+                albedo: Vector3::new(0.9, 0.85, 0.8),
+                emission: Vector3::new(0.0, 0.0, 0.0),
+                opacity: 0.6,
+                bounce: 0.2,
+            },
+            // glints rising off glowing crystals
+            ParticleKind::Sparkle => ParticlePreset {
+                rate: 2.0,
+                lifetime: 1.2,
+                velocity: Vector3::new(0.0, 0.05, 0.0),
+                spread: 0.05,
+                gravity: 0.0,
+                drag: 0.5,
+                wander: 0.02,
+                size: 0.06,
+                stretch: 0.0,
+                albedo: Vector3::new(0.0, 0.0, 0.0),
+                emission: Vector3::new(2.0, 2.0, 2.0),
+                opacity: 1.0,
+                bounce: 0.3,
+            },
+            // water falling from the ceiling, gone where it lands
+            ParticleKind::Drip => ParticlePreset {
+                rate: 2.0,
+                lifetime: 4.0,
+                velocity: Vector3::new(0.0, 0.0, 0.0),
+                spread: 0.0,
+                gravity: 1.0,
+                drag: 0.0,
+                wander: 0.0,
+                size: 0.01,
+                stretch: 0.03,
+                albedo: Vector3::new(0.6, 0.7, 0.8),
+                emission: Vector3::new(0.0, 0.0, 0.0),
+                opacity: 0.8,
+                bounce: -1.0,
+            },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct EmitterRaw {
+    position: [f32; 3],
+    lifetime: f32,
+    size: [f32; 3],
+    spread: f32,
+    velocity: [f32; 3],
+    gravity: f32,
+    albedo: [f32; 3],
+    drag: f32,
+    emission: [f32; 3],
+    wander: f32,
+    first: u32,
+    count: u32,
+    particle_size: f32,
+    stretch: f32,
+    opacity: f32,
+    bounce: f32,
+    padding: [f32; 2],
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct ParticleRaw {
+    position: [f32; 3],
+    // negative until it is first spawned
+    age: f32,
+    velocity: [f32; 3],
+    lifetime: f32,
+    emitter: u32,
+    padding: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct ParticleParams {
+    // the camera the depth buffer was rendered from
+    view_proj: [[f32; 4]; 4],
+    inverse_view_proj: [[f32; 4]; 4],
+    camera_position: [f32; 3],
+    delta: f32,
+    time: f32,
+    particle_count: u32,
+    padding: [u32; 2],
+}
+
+/// Dust, sparkles and drips. A compute pass moves the particles and bounces them off the depth buffer,
+/// then they are drawn as soft billboards lit by the light clusters into a layer composited over the lit scene.
+/// Emitters come from the scene and can follow the lantern or sit on every glowing crystal.
+pub struct Particles {
+    simulate_shader: ComputeShader,
+    render_shader: Shader,
+    composite_shader: Shader,
+    params: Buffer,
+    emitters: Buffer,
+    emitter_raw: Vec<EmitterRaw>,
+    // where each emitter is relative to what it follows
+    attachments: Vec<(EmitterAttachment, Vector3<f32>)>,
+    particle_count: u32,
+    simulate_group: BindGroup,
+    render_group: BindGroup,
+    layer: UniformBinding<Texture>,
+    pub output: UniformBinding<Texture>,
+    time: f32,
+    pub enabled: bool,
+}
+
+fn layout_entry(binding: u32, ty: wgpu::BufferBindingType, visibility: wgpu::ShaderStages) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+impl Particles {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32, emitters: &[EmitterDesc], crystals: &[Light], depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>, clusters: &ClusteredLights) -> Self {
+        let device = surface_ctx.device();
+        let (emitter_raw, attachments) = Self::build_emitters(emitters, crystals);
+        let particle_count = emitter_raw.last().map_or(0, |emitter| emitter.first + emitter.count);
+        // staggered so an emitter starts out spawning at its rate rather than all at once
+        let mut particles = emitter_raw.iter().enumerate().flat_map(|(i, emitter)| (0..emitter.count).map(move |slot| ParticleRaw {
+            position: emitter.position,
+            age: -emitter.lifetime * slot as f32 / emitter.count as f32,
+            velocity: [0.0; 3],
+            lifetime: 0.0,
+            emitter: i as u32,
+            padding: [0; 3],
+        })).collect::<Vec<_>>();
+        // storage buffers can't be empty
+        if particles.is_empty() {
+            particles.push(ParticleRaw::zeroed());
+        }
+        let emitter_contents = if emitter_raw.is_empty() { vec![EmitterRaw::zeroed()] } else { emitter_raw.clone() };
+        let particle_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Particle Buffer"),
+            contents: cast_slice(&particles),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let emitter_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Particle Emitter Buffer"),
+            contents: cast_slice(&emitter_contents),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Params Buffer"),
+            size: size_of::<ParticleParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let simulate_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Particle Simulate Layout"),
+            entries: &[
+                layout_entry(0, wgpu::BufferBindingType::Uniform, wgpu::ShaderStages::COMPUTE),
+                layout_entry(1, wgpu::BufferBindingType::Storage { read_only: true }, wgpu::ShaderStages::COMPUTE),
+                layout_entry(2, wgpu::BufferBindingType::Storage { read_only: false }, wgpu::ShaderStages::COMPUTE),
+            ],
+        });
+        let render_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Particle Render Layout"),
+            entries: &[
+                layout_entry(0, wgpu::BufferBindingType::Storage { read_only: true }, wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT),
+                layout_entry(1, wgpu::BufferBindingType::Storage { read_only: true }, wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT),
+            ],
+        });
+        let simulate_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Particle Simulate Group"),
+            layout: &simulate_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: params.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: emitter_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: particle_buffer.as_entire_binding() },
+            ],
+        });
+        let render_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Particle Render Group"),
+            layout: &render_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: emitter_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: particle_buffer.as_entire_binding() },
+            ],
+        });
+
+        let simulate_shader = ComputeShader::new(
+            include_str!("shaders/particle_simulate.wgsl"),
+            &[&depth_texture.layout, &simulate_layout],
+            vec![&depth_texture.shader_type, &ShaderType {
+                var_types: vec!["<uniform>".into(), "<storage, read>".into(), "<storage, read_write>".into()],
+                wgsl_types: vec!["ParticleParams".into(), "array<ParticleEmitter>".into(), "array<Particle>".into()],
+            }],
+            device
+        );
+        let render_shader = Shader::new(
+            include_str!("shaders/particles.wgsl"),
+            device,
+            vec![wgpu::TextureFormat::Rgba16Float],
+            vec![&depth_texture.layout, &screen_info.layout, &clusters.lighting_layout, &render_layout],
+            vec![&depth_texture.shader_type, &screen_info.shader_type, &ClusteredLights::shader_type(), &ShaderType {
+                var_types: vec!["<storage, read>".into(), "<storage, read>".into()],
+                wgsl_types: vec!["array<ParticleEmitter>".into(), "array<Particle>".into()],
+            }],
+            &[],
+            ShaderConfig { enable_depth_texture: false, face_cull: None, ..Default::default() },
+        );
+        let composite_shader = Shader::new_post_process(
+            include_str!("shaders/particle_composite.wgsl"),
+            device,
+            surface_ctx.config().format,
+            vec![&create_layout::<Texture>(device), &create_layout::<Texture>(device)],
+            vec![&Texture::shader_type(), &Texture::shader_type()]
+        );
+        let (layer, output) = Self::create_targets(surface_ctx, width, height);
+        Self {
+            simulate_shader,
+            render_shader,
+            composite_shader,
+            params,
+            emitters: emitter_buffer,
+            emitter_raw,
+            attachments,
+            particle_count,
+            simulate_group,
+            render_group,
+            layer,
+            output,
+            time: 0.0,
+            enabled: true,
+        }
+    }
+
+    /// One emitter per scene emitter, or per crystal for those on the crystals, each given its slots of the particle buffer.
+    fn build_emitters(emitters: &[EmitterDesc], crystals: &[Light]) -> (Vec<EmitterRaw>, Vec<(EmitterAttachment, Vector3<f32>)>) {
+        let mut raw = vec![];
+        let mut attachments = vec![];
+        let mut first = 0;
+        for emitter in emitters {
+            let preset = emitter.kind.preset();
+            let placements = match emitter.attachment {
+                EmitterAttachment::Crystals => crystals.iter().map(|crystal| (crystal.position + emitter.position, crystal.color)).collect(),
+                _ => vec![(emitter.position, Vector3::new(1.0, 1.0, 1.0))],
+            };
+            let count = (emitter.rate.unwrap_or(preset.rate) * preset.lifetime).ceil().max(1.0) as u32;
+            for (position, tint) in placements {
+                if first + count > MAX_PARTICLES {
+                    log::warn!("Dropping a {:?} emitter, the particle budget of {MAX_PARTICLES} is spent", emitter.kind);
+                    break;
+                }
+                raw.push(EmitterRaw {
+                    position: position.into(),
+                    lifetime: preset.lifetime,
+                    size: emitter.size.into(),
+                    spread: preset.spread,
+                    velocity: preset.velocity.into(),
+                    gravity: preset.gravity,
+                    albedo: preset.albedo.into(),
+                    drag: preset.drag,
+                    emission: preset.emission.zip(tint, |emission, tint| emission * tint).into(),
+                    wander: preset.wander,
+                    first,
+                    count,
+                    particle_size: preset.size,
+                    stretch: preset.stretch,
+                    opacity: preset.opacity,
+                    bounce: preset.bounce,
+                    padding: [0.0; 2],
+                });
+                attachments.push((emitter.attachment, emitter.position));
+                first += count;
+            }
+        }
+        (raw, attachments)
+    }
+
+    fn create_targets(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) -> (UniformBinding<Texture>, UniformBinding<Texture>) {
+        let layer = UniformBinding::new(surface_ctx.device(), "Particle Layer", Texture::blank_texture(surface_ctx.device(), width, height, wgpu::TextureFormat::Rgba16Float), None);
+        let output = UniformBinding::new(surface_ctx.device(), "Particle Output", Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format), None);
+        (layer, output)
+    }
+
+    pub fn resize(&mut self, surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) {
+        (self.layer, self.output) = Self::create_targets(surface_ctx, width, height);
+    }
+
+    /// Whether `render` draws anything, the lit scene is used as it is otherwise.
+    pub fn is_active(&self) -> bool {
+        self.enabled && self.particle_count > 0
+    }
+
+    /// Moves the lantern's emitters to it and steps every particle by `delta` seconds, colliding them with
+    /// `depth_texture` as seen from `view_proj`.
+    pub fn simulate(&mut self, lantern: Vector3<f32>, view_proj: Matrix4<f32>, camera_position: Vector3<f32>, delta: f32, depth_texture: &UniformBinding<DepthTexture>, device: &Device, queue: &Queue) {
+        if !self.is_active() {
+            return;
+        }
+        self.time += delta;
+        for (emitter, (attachment, offset)) in self.emitter_raw.iter_mut().zip(&self.attachments) {
+            if *attachment == EmitterAttachment::Lantern {
+                emitter.position = (lantern + offset).into();
+            }
+        }
+        queue.write_buffer(&self.emitters, 0, cast_slice(&self.emitter_raw));
+        let params = ParticleParams {
+            view_proj: view_proj.into(),
+            inverse_view_proj: view_proj.invert().unwrap_or(Matrix4::identity()).into(),
+            camera_position: camera_position.into(),
+            // a long hitch would fling particles through walls
+            delta: delta.min(0.1),
+            time: self.time,
+            particle_count: self.particle_count,
+            padding: [0; 2],
+        };
+        queue.write_buffer(&self.params, 0, bytes_of(&params));
+        self.simulate_shader.run_once(vec![&depth_texture.binding, &self.simulate_group], [self.particle_count.div_ceil(SIMULATE_WORKGROUP), 1, 1], device, queue);
+    }
+
+    /// Draws the particles and composites them over `scene` into `output`.
+    pub fn render(&self, surface_ctx: &dyn SurfaceCtx, encoder: &mut CommandEncoder, scene: &UniformBinding<Texture>, depth_texture: &UniformBinding<DepthTexture>, screen_info: &UniformBinding<ScreenInfo>, clusters: &ClusteredLights, timestamp_writes: Option<RenderPassTimestampWrites>) {
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Particle Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.layer.value.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                timestamp_writes,
+                occlusion_query_set: None,
+                depth_stencil_attachment: None,
+            });
+            self.render_shader.bind(&mut render_pass);
+            render_pass.set_bind_group(0, &depth_texture.binding, &[]);
+            render_pass.set_bind_group(1, &screen_info.binding, &[]);
+            render_pass.set_bind_group(2, &clusters.lighting_group, &[]);
+            render_pass.set_bind_group(3, &self.render_group, &[]);
+            // a quad per particle, built in the vertex shader
+            render_pass.draw(0..6, 0..self.particle_count);
+        }
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Particle Composite Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.output.value.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                timestamp_writes: None,
+                occlusion_query_set: None,
+                depth_stencil_attachment: None,
+            });
+            self.composite_shader.bind(&mut render_pass);
+            render_pass.set_bind_group(0, &scene.binding, &[]);
+            render_pass.set_bind_group(1, &self.layer.binding, &[]);
+            surface_ctx.screen_model().render(&mut render_pass);
+        }
+    }
+}
@@ -1,19 +1,40 @@
-use bespoke_engine::{binding::UniformBinding, camera::vec_to_point, shader::{Shader, ShaderConfig, ShaderType}, surface_context::SurfaceCtx};
-use bytemuck::bytes_of;
-use cgmath::{vec3, Vector3};
-use wgpu::{util::{BufferInitDescriptor, DeviceExt}, BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, CommandEncoder, RenderPass, VertexBufferLayout};
+use std::collections::HashMap;
 
-use crate::{light::Light, texture_types::DepthCube};
+use bespoke_engine::{binding::UniformBinding, camera::{vec_to_point, Camera}, shader::{Shader, ShaderConfig, ShaderType}, surface_context::SurfaceCtx, texture::DepthTexture};
+use bytemuck::{bytes_of, cast_slice};
+use cgmath::{vec3, InnerSpace, Matrix4, Vector3};
+use wgpu::{util::{BufferInitDescriptor, DeviceExt}, BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, CommandEncoder, RenderPass, RenderPassTimestampWrites, VertexBufferLayout};
+
+use crate::{frustum::Frustum, light::{Light, LightKind, ShadowSettings, SHADOW_CASCADES}, shadow_atlas::ShadowTile};
+
+// each cube face covers exactly a quarter turn
+const FACE_FOV: f32 = 90.0;
+// view distances the directional cascades split the camera frustum at, the first is the camera's near plane
+const CASCADE_SPLITS: [f32; SHADOW_CASCADES + 1] = [0.0, 8.0, 24.0, 64.0];
 
 pub struct PointShadowRenderer {
     pub camera_bind_group: BindGroup,
     pub camera_layout: BindGroupLayout,
     pub index_uniform: UniformBinding<u32>,
-    pub shader: Shader,
+    // depth bias is fixed per pipeline, so there is one for each constant and slope bias in use
+    shaders: HashMap<(i32, u32), Shader>,
+    vertex_layout: Vec<VertexBufferLayout<'static>>,
+    // every shadowed light's faces, in the order the lights were set
+    pub faces: Vec<ShadowFace>,
+}
+
+/// One shadow map view of a light.
+pub struct ShadowFace {
+    pub view_proj: Matrix4<f32>,
+    // the sphere shadow casters have to be in
+    pub origin: Vector3<f32>,
+    pub range: f32,
+    // world size across the face, at unit distance for perspective faces
+    pub span: f32,
 }
 
 impl PointShadowRenderer {
-    pub fn new(surface_ctx: &dyn SurfaceCtx, vertex_layout: &[VertexBufferLayout]) -> Self {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, vertex_layout: Vec<VertexBufferLayout<'static>>) -> Self {
         let camera_layout = 
             surface_ctx.device().create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: None,
@@ -48,29 +69,43 @@ impl PointShadowRenderer {
             }],
         });
         let index_uniform = UniformBinding::new(surface_ctx.device(), "Point Light Index", 0, None);
-        let shader = Shader::new(include_str!("shaders/point_shadow.wgsl"), surface_ctx.device(), vec![], vec![&camera_layout, &index_uniform.layout], vec![&ShaderType::buffer_type(false, "mat4x4f".into()), &index_uniform.shader_type], vertex_layout, ShaderConfig { depth_only: true, ..Default::default() });
         Self {
             camera_bind_group,
             camera_layout,
-            shader,
+            shaders: HashMap::new(),
+            vertex_layout,
             index_uniform,
+            faces: vec![],
         }
     }
 
-    pub fn set_light(&mut self, light: &Light, surface_ctx: &dyn SurfaceCtx) {
-        let cameras: [[[f32; 4]; 4]; 6] = [[1,0,0], [-1,0,0], [0,1,0], [0,-1,0], [0,0,1], [0,0,-1]].map(|dir| {
-            let up = match dir {
-                [0,1,0] => vec3(0.0, 0.0, 1.0),
-                [0,-1,0] => vec3(0.0, 0.0, -1.0),
-                _ => vec3(0.0, 1.0, 0.0),
-            };
-            let view = cgmath::Matrix4::look_at_rh(vec_to_point(light.position), vec_to_point(light.position+vec3(dir[0] as f32, dir[1] as f32, dir[2] as f32)), up);
-            let proj = cgmath::perspective(cgmath::Deg(90.0), 1.0, 0.1, 100.0);
-            (proj * view).into()
-        });
+    fn bias_key(settings: &ShadowSettings) -> (i32, u32) {
+        (settings.constant_bias, settings.slope_bias.to_bits())
+    }
+
+    /// Builds the faces of every shadowed light, directional cascades are fitted to `camera`.
+    pub fn set_lights(&mut self, lights: &[Light], camera: &Camera, surface_ctx: &dyn SurfaceCtx) {
+        for light in lights {
+            let key = Self::bias_key(&light.shadow);
+            if !self.shaders.contains_key(&key) {
+                let shader = Shader::new(include_str!("shaders/point_shadow.wgsl"), surface_ctx.device(), vec![], vec![&self.camera_layout, &self.index_uniform.layout], vec![&ShaderType::buffer_type(false, "mat4x4f".into()), &self.index_uniform.shader_type], &self.vertex_layout, ShaderConfig {
+                    depth_only: true,
+                    depth_bias: wgpu::DepthBiasState {
+                        constant: light.shadow.constant_bias,
+                        slope_scale: light.shadow.slope_bias,
+                        clamp: 0.0,
+                    },
+                    ..Default::default()
+                });
+                self.shaders.insert(key, shader);
+            }
+        }
+        self.faces = lights.iter().flat_map(|light| Self::light_faces(light, camera)).collect();
+        let cameras: Vec<[[f32; 4]; 4]> = self.faces.iter().map(|face| face.view_proj.into()).collect();
         let camera_buffer =
             surface_ctx.device().create_buffer_init(&BufferInitDescriptor {
-                contents: bytes_of(&cameras),
+                // storage bindings can't be empty
+                contents: if cameras.is_empty() { bytes_of(&[[0.0f32; 4]; 4]) } else { cast_slice(&cameras) },
                 label: Some(&format!("Point Shadow Camera Buffer")),
                 usage: wgpu::BufferUsages::STORAGE,
             });
@@ -88,24 +123,82 @@ impl PointShadowRenderer {
         });
     }
 
-    pub fn setup_render<'a>(&'a mut self, outputs: &DepthCube, surface_ctx: &dyn SurfaceCtx, encoder: &'a mut CommandEncoder, i: usize) -> RenderPass<'a> {
+    fn light_faces(light: &Light, camera: &Camera) -> Vec<ShadowFace> {
+        let range = light.radius.min(light.shadow.far);
+        match light.kind {
+            LightKind::Point => {
+                Self::cube_faces(light.position, light.shadow.near, light.shadow.far).map(|view_proj| {
+                    ShadowFace { view_proj, origin: light.position, range, span: 2.0 * (FACE_FOV.to_radians() / 2.0).tan() }
+                }).to_vec()
+            }
+            LightKind::Spot { direction, outer_angle, .. } => {
+                let view = cgmath::Matrix4::look_at_rh(vec_to_point(light.position), vec_to_point(light.position + direction), Self::up_for(direction));
+                let proj = cgmath::perspective(cgmath::Rad(outer_angle * 2.0), 1.0, light.shadow.near, light.shadow.far);
+                vec![ShadowFace { view_proj: proj * view, origin: light.position, range, span: 2.0 * outer_angle.tan() }]
+            }
+            LightKind::Directional { direction } => {
+                // each cascade is a box around the bounding sphere of a slice of the camera frustum
+                let forward = camera.get_forward_vec().normalize();
+                let tan_half_fovy = (camera.fovy.to_radians() / 2.0).tan();
+                let mut splits = CASCADE_SPLITS;
+                splits[0] = camera.znear;
+                (0..SHADOW_CASCADES).map(|cascade| {
+                    let (near, far) = (splits[cascade], splits[cascade + 1]);
+                    let center = camera.eye + forward * ((near + far) / 2.0);
+                    let radius = vec3((far - near) / 2.0, far * tan_half_fovy, far * tan_half_fovy * camera.aspect).magnitude();
+                    let eye = center - direction * light.shadow.far / 2.0;
+                    let view = cgmath::Matrix4::look_at_rh(vec_to_point(eye), vec_to_point(center), Self::up_for(direction));
+                    let proj = cgmath::ortho(-radius, radius, -radius, radius, light.shadow.near, light.shadow.far);
+                    ShadowFace { view_proj: proj * view, origin: center, range: vec3(radius, radius, light.shadow.far / 2.0).magnitude(), span: radius * 2.0 }
+                }).collect()
+            }
+        }
+    }
+
+    /// View projections looking along +X, -X, +Y, -Y, +Z and -Z from `position`, the face order `cube_face` picks from.
+    pub fn cube_faces(position: Vector3<f32>, near: f32, far: f32) -> [Matrix4<f32>; 6] {
+        [[1,0,0], [-1,0,0], [0,1,0], [0,-1,0], [0,0,1], [0,0,-1]].map(|dir| {
+            let up = match dir {
+                [0,1,0] => vec3(0.0, 0.0, 1.0),
+                [0,-1,0] => vec3(0.0, 0.0, -1.0),
+                _ => vec3(0.0, 1.0, 0.0),
+            };
+            let view = cgmath::Matrix4::look_at_rh(vec_to_point(position), vec_to_point(position+vec3(dir[0] as f32, dir[1] as f32, dir[2] as f32)), up);
+            let proj = cgmath::perspective(cgmath::Deg(FACE_FOV), 1.0, near, far);
+            proj * view
+        })
+    }
+
+    fn up_for(direction: Vector3<f32>) -> Vector3<f32> {
+        if direction.y.abs() > 0.99 { vec3(0.0, 0.0, 1.0) } else { vec3(0.0, 1.0, 0.0) }
+    }
+
+    pub fn face_frustum(&self, i: usize) -> Frustum {
+        Frustum::from_matrix(self.faces[i].view_proj)
+    }
+
+    /// Starts a pass drawing face `i` into its tile of the atlas, the first pass of a frame clears the whole atlas.
+    /// `settings` must belong to one of the lights last given to `set_lights`.
+    pub fn setup_render<'a>(&'a mut self, atlas: &DepthTexture, tile: ShadowTile, settings: &ShadowSettings, clear: bool, surface_ctx: &dyn SurfaceCtx, encoder: &'a mut CommandEncoder, i: usize, timestamp_writes: Option<RenderPassTimestampWrites>) -> RenderPass<'a> {
         self.index_uniform.set_data(surface_ctx.device(), i as u32);
-        let depth_texture = &outputs[i];
+        let depth_texture = atlas;
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Point Light Render Pass"),
             color_attachments: &[],
-            timestamp_writes: None,
+            timestamp_writes,
             occlusion_query_set: None,
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                 view: &depth_texture.view,
                 depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
+                    load: if clear { wgpu::LoadOp::Clear(1.0) } else { wgpu::LoadOp::Load },
                     store: wgpu::StoreOp::Store,
                 }),
                 stencil_ops: None,
             }),
         });
-        self.shader.bind(&mut render_pass);
+        render_pass.set_viewport(tile.x as f32, tile.y as f32, tile.size as f32, tile.size as f32, 0.0, 1.0);
+        render_pass.set_scissor_rect(tile.x, tile.y, tile.size, tile.size);
+        self.shaders[&Self::bias_key(settings)].bind(&mut render_pass);
         render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
         render_pass.set_bind_group(1, &self.index_uniform.binding, &[]);
         render_pass
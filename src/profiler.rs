@@ -0,0 +1,256 @@
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Instant};
+
+use bespoke_engine::surface_context::SurfaceCtx;
+use wgpu::{Buffer, Device, Features, QuerySet, RenderPass, RenderPassTimestampWrites};
+use wgpu_text::{glyph_brush::{ab_glyph::FontRef, Section, Text}, BrushBuilder, TextBrush};
+
+// enough for a frame without shadowed lights, the set grows when a frame needs more
+const INITIAL_QUERIES: u32 = 64;
+// wgpu's limit on the size of a query set
+const MAX_QUERIES: u32 = 4096;
+const AVERAGE_WINDOW: usize = 60;
+
+struct GpuTimer {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+    timestamp_period: f32,
+    capacity: u32,
+    next_query: u32,
+    // queries asked for this frame, past `capacity` when some passes went untimed
+    requested: u32,
+    // passes of the last frame that didn't fit, shown in the overlay
+    untimed: u32,
+    entries: Vec<(&'static str, u32)>,
+    pending_entries: Vec<(&'static str, u32)>,
+    pending: bool,
+    mapped: Arc<AtomicBool>,
+}
+
+/// Times each render pass with timestamp queries where the device supports them,
+/// falling back to CPU timings of the encoding, and draws the rolling averages as an overlay.
+pub struct Profiler {
+    gpu: Option<GpuTimer>,
+    cpu_times: HashMap<&'static str, f64>,
+    history: HashMap<&'static str, VecDeque<f64>>,
+    order: Vec<&'static str>,
+    counters: Vec<(&'static str, String)>,
+    brush: TextBrush<FontRef<'static>>,
+    pub visible: bool,
+}
+
+impl GpuTimer {
+    fn create_queries(device: &Device, count: u32) -> (QuerySet, Buffer, Buffer) {
+        let size = count as u64 * size_of::<u64>() as u64;
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("Profiler Query Set"),
+            ty: wgpu::QueryType::Timestamp,
+            count,
+        });
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Profiler Resolve Buffer"),
+            size,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Profiler Readback Buffer"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        (query_set, resolve_buffer, readback_buffer)
+    }
+
+    // Makes room for as many queries as the last frame asked for, each shadow face is a pass of its own
+    // so the count changes with how many lights cast shadows.
+    fn grow(&mut self, device: &Device) {
+        if self.capacity == MAX_QUERIES {
+            return;
+        }
+        let capacity = self.requested.next_power_of_two().min(MAX_QUERIES);
+        if capacity < self.requested {
+            log::warn!("The profiler needs {} timestamp queries but can have {MAX_QUERIES}, the last passes of each frame go untimed", self.requested);
+        } else {
+            log::info!("Growing the profiler from {} to {capacity} timestamp queries", self.capacity);
+        }
+        (self.query_set, self.resolve_buffer, self.readback_buffer) = Self::create_queries(device, capacity);
+        self.capacity = capacity;
+    }
+}
+
+impl Profiler {
+    pub fn new(surface_ctx: &dyn SurfaceCtx) -> Self {
+        let device = surface_ctx.device();
+        let gpu = if device.features().contains(Features::TIMESTAMP_QUERY) {
+            let (query_set, resolve_buffer, readback_buffer) = GpuTimer::create_queries(device, INITIAL_QUERIES);
+            Some(GpuTimer {
+                query_set,
+                resolve_buffer,
+                readback_buffer,
+                timestamp_period: surface_ctx.queue().get_timestamp_period(),
+                capacity: INITIAL_QUERIES,
+                next_query: 0,
+                requested: 0,
+                untimed: 0,
+                entries: vec![],
+                pending_entries: vec![],
+                pending: false,
+                mapped: Arc::new(AtomicBool::new(false)),
+            })
+        } else {
+            log::info!("Timestamp queries unavailable, profiling on the CPU");
+            None
+        };
+        let brush = BrushBuilder::using_font_bytes(include_bytes!("res/fonts/DejaVuSansMono.ttf")).unwrap()
+            .build(device, surface_ctx.config().width, surface_ctx.config().height, surface_ctx.config().format);
+        Self {
+            gpu,
+            cpu_times: HashMap::new(),
+            history: HashMap::new(),
+            order: vec![],
+            counters: vec![],
+            brush,
+            visible: false,
+        }
+    }
+
+    pub fn resize(&mut self, surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) {
+        self.brush.resize_view(width as f32, height as f32, surface_ctx.queue());
+    }
+
+    /// Collects last frame's timestamps if they have been read back.
+    pub fn begin_frame(&mut self, surface_ctx: &dyn SurfaceCtx) {
+        let mut samples: HashMap<&'static str, f64> = HashMap::new();
+        if let Some(gpu) = &mut self.gpu {
+            gpu.next_query = 0;
+            gpu.entries.clear();
+            if gpu.pending {
+                surface_ctx.device().poll(wgpu::Maintain::Poll);
+                if gpu.mapped.swap(false, Ordering::AcqRel) {
+                    {
+                        let data = gpu.readback_buffer.slice(..).get_mapped_range();
+                        let timestamps: &[u64] = bytemuck::cast_slice(&data);
+                        for (name, begin) in &gpu.pending_entries {
+                            let ticks = timestamps[*begin as usize + 1].saturating_sub(timestamps[*begin as usize]);
+                            *samples.entry(*name).or_insert(0.0) += ticks as f64 * gpu.timestamp_period as f64 / 1_000_000.0;
+                        }
+                    }
+                    gpu.readback_buffer.unmap();
+                    gpu.pending = false;
+                }
+            }
+            gpu.untimed = gpu.requested.saturating_sub(gpu.capacity) / 2;
+            // the buffers can only be replaced once nothing is waiting on them
+            if gpu.requested > gpu.capacity && !gpu.pending {
+                gpu.grow(surface_ctx.device());
+            }
+            gpu.requested = 0;
+        } else {
+            samples = std::mem::take(&mut self.cpu_times);
+        }
+        for (name, ms) in samples {
+            if !self.order.contains(&name) {
+                self.order.push(name);
+            }
+            let history = self.history.entry(name).or_default();
+            history.push_back(ms);
+            if history.len() > AVERAGE_WINDOW {
+                history.pop_front();
+            }
+        }
+    }
+
+    /// Timestamp writes for a render pass, summed with any other pass of the same name this frame.
+    pub fn pass_timestamps(&mut self, name: &'static str) -> Option<RenderPassTimestampWrites<'_>> {
+        let gpu = self.gpu.as_mut()?;
+        gpu.requested += 2;
+        if gpu.next_query + 2 > gpu.capacity {
+            return None;
+        }
+        let begin = gpu.next_query;
+        gpu.next_query += 2;
+        gpu.entries.push((name, begin));
+        Some(RenderPassTimestampWrites {
+            query_set: &gpu.query_set,
+            beginning_of_pass_write_index: Some(begin),
+            end_of_pass_write_index: Some(begin + 1),
+        })
+    }
+
+    /// Records CPU time spent since `start`, only used when timestamp queries are unavailable.
+    pub fn record_cpu(&mut self, name: &'static str, start: Instant) {
+        if self.gpu.is_none() {
+            *self.cpu_times.entry(name).or_insert(0.0) += start.elapsed().as_secs_f64() * 1000.0;
+        }
+    }
+
+    /// Resolves this frame's queries, must be called after every timed pass has been submitted.
+    pub fn end_frame(&mut self, surface_ctx: &dyn SurfaceCtx) {
+        let Some(gpu) = &mut self.gpu else {
+            return;
+        };
+        // skip this frame's timings while the last readback is still mapped
+        if gpu.pending || gpu.next_query == 0 {
+            return;
+        }
+        let mut encoder = surface_ctx.device().create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Profiler Resolve Encoder") });
+        encoder.resolve_query_set(&gpu.query_set, 0..gpu.next_query, &gpu.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&gpu.resolve_buffer, 0, &gpu.readback_buffer, 0, gpu.next_query as u64 * size_of::<u64>() as u64);
+        surface_ctx.queue().submit([encoder.finish()]);
+        gpu.pending_entries = gpu.entries.clone();
+        gpu.pending = true;
+        let mapped = gpu.mapped.clone();
+        gpu.readback_buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            if result.is_ok() {
+                mapped.store(true, Ordering::Release);
+            }
+        });
+    }
+
+    /// Sets a value shown under the timings, such as culling counts.
+    pub fn set_counter(&mut self, name: &'static str, value: String) {
+        if let Some(counter) = self.counters.iter_mut().find(|(counter_name, _)| *counter_name == name) {
+            counter.1 = value;
+        } else {
+            self.counters.push((name, value));
+        }
+    }
+
+    pub fn average(&self, name: &str) -> Option<f64> {
+        let history = self.history.get(name)?;
+        if history.is_empty() {
+            return None;
+        }
+        Some(history.iter().sum::<f64>() / history.len() as f64)
+    }
+
+    pub fn draw<'pass>(&'pass mut self, surface_ctx: &dyn SurfaceCtx, render_pass: &mut RenderPass<'pass>) {
+        if !self.visible {
+            return;
+        }
+        let mut text = format!("{} timings (ms)\n", if self.gpu.is_some() { "GPU" } else { "CPU" });
+        let mut total = 0.0;
+        for name in &self.order {
+            if let Some(average) = self.average(name) {
+                total += average;
+                text += &format!("{name:<22}{average:>7.3}\n");
+            }
+        }
+        text += &format!("{:<22}{total:>7.3}\n", "Total");
+        if let Some(gpu) = self.gpu.as_ref().filter(|gpu| gpu.untimed > 0) {
+            text += &format!("{:<22}{:>7}\n", "Untimed passes", gpu.untimed);
+        }
+        for (name, value) in &self.counters {
+            text += &format!("{name:<22}{value:>7}\n");
+        }
+        let section = Section::default()
+            .add_text(Text::new(&text).with_scale(20.0).with_color([1.0, 1.0, 1.0, 1.0]))
+            .with_screen_position((10.0, 10.0));
+        if let Err(err) = self.brush.queue(surface_ctx.device(), surface_ctx.queue(), vec![&section]) {
+            log::warn!("Failed to queue profiler text: {err}");
+            return;
+        }
+        self.brush.draw(render_pass);
+    }
+}
@@ -0,0 +1,320 @@
+use bespoke_engine::{binding::{create_layout, Descriptor, UniformBinding, WgslType}, mesh::ModelVertex, model::Render, shader::{Shader, ShaderConfig, ShaderType}, surface_context::SurfaceCtx, texture::{DepthTexture, Texture}};
+use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use cgmath::{Matrix4, SquareMatrix, Vector3, Zero};
+use wgpu::{util::{BufferInitDescriptor, DeviceExt}, BindGroup, BindGroupLayoutDescriptor, Buffer, CommandEncoder, Device};
+
+use crate::{frustum::{CullingStats, Frustum}, instance::Instance, light::Light, mesh_chunks::ChunkedMesh, point_shadow::PointShadowRenderer};
+
+// must match MAX_REFLECTION_PROBES in custom_shader_types.wgsl
+pub const MAX_REFLECTION_PROBES: usize = 8;
+const PROBE_SIZE: u32 = 128;
+// each mip is prefiltered for a rougher surface, the last one for fully rough
+const PROBE_MIPS: u32 = 5;
+pub const PROBE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const CAPTURE_NEAR: f32 = 0.1;
+const CAPTURE_FAR: f32 = 100.0;
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct ProbeCapture {
+    view_proj: [[f32; 4]; 4],
+    position: [f32; 3],
+    exposure: f32,
+}
+
+impl WgslType for ProbeCapture {
+    fn wgsl_name() -> String {
+        "ProbeCapture".into()
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct PrefilterParams {
+    faces: [[[f32; 4]; 4]; 6],
+    inverse_faces: [[[f32; 4]; 4]; 6],
+    face: u32,
+    roughness: f32,
+    padding: [f32; 2],
+}
+
+impl WgslType for PrefilterParams {
+    fn wgsl_name() -> String {
+        "PrefilterParams".into()
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct ReflectionProbeParams {
+    faces: [[[f32; 4]; 4]; 6],
+    // xyz only, w is padding
+    positions: [[f32; 4]; MAX_REFLECTION_PROBES],
+    count: u32,
+    mip_count: f32,
+    padding: [f32; 2],
+}
+
+/// The cube faces of a probe seen from the origin. The faces only rotate, so these give the directions of every probe.
+pub fn probe_faces() -> [Matrix4<f32>; 6] {
+    PointShadowRenderer::cube_faces(Vector3::zero(), CAPTURE_NEAR, CAPTURE_FAR)
+}
+
+/// Renders the lit cave into the six faces of a probe. The capture has no shadows,
+/// probes only stand in for light that isn't on screen. Alpha is only set where the
+/// inside of the cave is seen, probes buried in rock see its back faces or nothing.
+pub struct ProbeCapturer {
+    shader: Shader,
+    binding: UniformBinding<ProbeCapture>,
+    light_group: BindGroup,
+    depth: DepthTexture,
+    exposure: f32,
+}
+
+impl ProbeCapturer {
+    pub fn new(device: &Device, size: u32, lights: &[Light], exposure: f32) -> Self {
+        let mut raw_lights = lights.iter().map(|light| light.to_raw()).collect::<Vec<_>>();
+        if raw_lights.is_empty() {
+            // storage bindings can't be empty
+            raw_lights.push(Light::new(Vector3::zero(), Vector3::zero()).with_intensity(0.0).to_raw());
+        }
+        let light_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Probe Capture Light Buffer"),
+            contents: cast_slice(&raw_lights),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let light_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Probe Capture Light Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let light_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Probe Capture Lights"),
+            layout: &light_layout,
+            entries: &[wgpu::BindGroupEntry { binding: 0, resource: light_buffer.as_entire_binding() }],
+        });
+        let binding = UniformBinding::new(device, "Probe Capture", ProbeCapture { view_proj: probe_faces()[0].into(), position: [0.0; 3], exposure }, None);
+        let shader = Shader::new(
+            include_str!("shaders/probe_capture.wgsl"),
+            device,
+            vec![PROBE_FORMAT],
+            vec![&create_layout::<Texture>(device), &binding.layout, &light_layout],
+            vec![&Texture::shader_type(), &binding.shader_type, &ShaderType::buffer_type(false, "Light".into())],
+            &[ModelVertex::desc(), Instance::desc()],
+            ShaderConfig::default(),
+        );
+        Self {
+            shader,
+            binding,
+            light_group,
+            depth: DepthTexture::create_depth_texture(device, size, size, "Probe Capture Depth"),
+            exposure,
+        }
+    }
+
+    /// Draws the cave around `position` into the six layers of `target` from `first_layer`, in `cube_face` order.
+    pub fn capture(&mut self, device: &Device, encoder: &mut CommandEncoder, target: &wgpu::Texture, first_layer: u32, position: Vector3<f32>, cave: &ChunkedMesh) {
+        for (face, view_proj) in PointShadowRenderer::cube_faces(position, CAPTURE_NEAR, CAPTURE_FAR).into_iter().enumerate() {
+            self.binding.set_data(device, ProbeCapture { view_proj: view_proj.into(), position: position.into(), exposure: self.exposure });
+            let face_view = target.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: first_layer + face as u32,
+                array_layer_count: Some(1),
+                ..Default::default()
+            });
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Probe Capture Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &face_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                timestamp_writes: None,
+                occlusion_query_set: None,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            self.shader.bind(&mut render_pass);
+            render_pass.set_bind_group(1, &self.binding.binding, &[]);
+            render_pass.set_bind_group(2, &self.light_group, &[]);
+            let chunks = cave.visible_chunks(&Frustum::from_matrix(view_proj), |_| false, &mut CullingStats::default());
+            let chunks = cave.select_lods(chunks, position, std::f32::consts::FRAC_PI_2, 0);
+            cave.render_chunks(&mut render_pass, &chunks, true, None, None, None);
+        }
+    }
+}
+
+/// Cube maps of the cave captured once at author placed positions, each prefiltered so
+/// rougher surfaces read blurrier mips. All probes live in one array texture, six layers each.
+/// They are bound together with the irradiance volume, see `LightProbes`.
+pub struct ReflectionProbes {
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+    pub params: Buffer,
+}
+
+impl ReflectionProbes {
+    /// Captures the lit cave around every position and prefilters it.
+    pub fn bake(surface_ctx: &dyn SurfaceCtx, positions: &[Vector3<f32>], cave: &ChunkedMesh, lights: &[Light], exposure: f32) -> Self {
+        let device = surface_ctx.device();
+        let positions = &positions[..positions.len().min(MAX_REFLECTION_PROBES)];
+        let faces = probe_faces();
+        let probes = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Reflection Probes"),
+            size: wgpu::Extent3d { width: PROBE_SIZE, height: PROBE_SIZE, depth_or_array_layers: 6 * positions.len().max(1) as u32 },
+            mip_level_count: PROBE_MIPS,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: PROBE_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let capture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Reflection Probe Capture"),
+            size: wgpu::Extent3d { width: PROBE_SIZE, height: PROBE_SIZE, depth_or_array_layers: 6 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: PROBE_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Reflection Probe Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let mut capturer = ProbeCapturer::new(device, PROBE_SIZE, lights, exposure);
+
+        let capture_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Reflection Probe Capture Layout"),
+            entries: &Self::texture_entries(wgpu::ShaderStages::FRAGMENT),
+        });
+        let capture_view = capture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let capture_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Reflection Probe Capture"),
+            layout: &capture_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&capture_view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(&sampler) },
+            ],
+        });
+        let inverse_faces = faces.map(|face| face.invert().unwrap().into());
+        let raw_faces = faces.map(|face| face.into());
+        let mut prefilter_binding = UniformBinding::new(device, "Reflection Probe Prefilter", PrefilterParams { faces: raw_faces, inverse_faces, face: 0, roughness: 0.0, padding: [0.0; 2] }, None);
+        let prefilter_shader = Shader::new_post_process(
+            include_str!("shaders/probe_prefilter.wgsl"),
+            device,
+            PROBE_FORMAT,
+            vec![&capture_layout, &prefilter_binding.layout],
+            vec![&Self::texture_shader_type(), &prefilter_binding.shader_type],
+        );
+
+        for (probe, position) in positions.iter().enumerate() {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Reflection Probe Bake") });
+            capturer.capture(device, &mut encoder, &capture, 0, *position, cave);
+            for mip in 0..PROBE_MIPS {
+                for face in 0..6 {
+                    prefilter_binding.set_data(device, PrefilterParams { faces: raw_faces, inverse_faces, face, roughness: mip as f32 / (PROBE_MIPS - 1) as f32, padding: [0.0; 2] });
+                    let target = probes.create_view(&wgpu::TextureViewDescriptor {
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_mip_level: mip,
+                        mip_level_count: Some(1),
+                        base_array_layer: probe as u32 * 6 + face,
+                        array_layer_count: Some(1),
+                        ..Default::default()
+                    });
+                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        label: Some("Reflection Probe Prefilter Render Pass"),
+                        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                            view: &target,
+                            resolve_target: None,
+                            ops: wgpu::Operations {
+                                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                                store: wgpu::StoreOp::Store,
+                            },
+                        })],
+                        timestamp_writes: None,
+                        occlusion_query_set: None,
+                        depth_stencil_attachment: None,
+                    });
+                    prefilter_shader.bind(&mut render_pass);
+                    render_pass.set_bind_group(0, &capture_group, &[]);
+                    render_pass.set_bind_group(1, &prefilter_binding.binding, &[]);
+                    surface_ctx.screen_model().render(&mut render_pass);
+                }
+            }
+            // the capture texture is reused by the next probe
+            surface_ctx.queue().submit([encoder.finish()]);
+        }
+
+        let params = ReflectionProbeParams {
+            faces: raw_faces,
+            positions: std::array::from_fn(|i| positions.get(i).map_or([0.0; 4], |position| [position.x, position.y, position.z, 0.0])),
+            count: positions.len() as u32,
+            mip_count: PROBE_MIPS as f32,
+            padding: [0.0; 2],
+        };
+        let params = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Reflection Probe Params Buffer"),
+            contents: bytes_of(&params),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let view = probes.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        Self { view, sampler, params }
+    }
+
+    /// An array texture at binding 0 and its sampler at 1.
+    pub fn texture_entries(visibility: wgpu::ShaderStages) -> [wgpu::BindGroupLayoutEntry; 2] {
+        [
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ]
+    }
+
+    fn texture_shader_type() -> ShaderType {
+        ShaderType {
+            var_types: vec!["".into(), "".into()],
+            wgsl_types: vec!["texture_2d_array<f32>".into(), "sampler".into()],
+        }
+    }
+}
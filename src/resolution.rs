@@ -0,0 +1,62 @@
+const SCALE_STEP: f32 = 0.05;
+// frames to wait after a change before adjusting again, resizing every target is not free
+const ADJUST_COOLDOWN: u32 = 30;
+
+pub struct RenderScale {
+    pub scale: f32,
+    pub auto: bool,
+    pub target_frame_time: f64,
+    pub min_scale: f32,
+    pub max_scale: f32,
+    smoothed_frame_time: f64,
+    cooldown: u32,
+}
+
+impl RenderScale {
+    pub fn new(scale: f32, auto: bool) -> Self {
+        Self {
+            scale,
+            auto,
+            target_frame_time: 1000.0 / 60.0,
+            min_scale: 0.5,
+            max_scale: 1.0,
+            smoothed_frame_time: 1000.0 / 60.0,
+            cooldown: ADJUST_COOLDOWN,
+        }
+    }
+
+    pub fn render_size(&self, surface_size: (u32, u32)) -> (u32, u32) {
+        (
+            ((surface_size.0 as f32 * self.scale).round() as u32).max(1),
+            ((surface_size.1 as f32 * self.scale).round() as u32).max(1),
+        )
+    }
+
+    /// Sets the scale manually, returns true if it changed.
+    pub fn set_scale(&mut self, scale: f32) -> bool {
+        let scale = ((scale / SCALE_STEP).round() * SCALE_STEP).clamp(self.min_scale, self.max_scale);
+        let changed = (scale - self.scale).abs() > f32::EPSILON;
+        self.scale = scale;
+        self.cooldown = ADJUST_COOLDOWN;
+        changed
+    }
+
+    /// Feeds the last frame time in milliseconds, returns true if the scale changed.
+    pub fn update(&mut self, frame_time: f64) -> bool {
+        self.smoothed_frame_time = self.smoothed_frame_time * 0.9 + frame_time * 0.1;
+        if !self.auto {
+            return false;
+        }
+        if self.cooldown > 0 {
+            self.cooldown -= 1;
+            return false;
+        }
+        if self.smoothed_frame_time > self.target_frame_time * 1.05 && self.scale > self.min_scale {
+            self.set_scale(self.scale - SCALE_STEP)
+        } else if self.smoothed_frame_time < self.target_frame_time * 0.85 && self.scale < self.max_scale {
+            self.set_scale(self.scale + SCALE_STEP)
+        } else {
+            false
+        }
+    }
+}
@@ -1,4 +1,9 @@
-use bespoke_engine::{surface_context::SurfaceCtx, window::Surface};
+#[cfg(not(target_os = "android"))]
+use std::path::Path;
+
+#[cfg(not(target_os = "android"))]
+use anyhow::Context;
+use bespoke_engine::{surface_context::SurfaceCtx, window::{Surface, WindowHandler}};
 use winit::event_loop::EventLoop;
 
 use crate::game::Game;
@@ -11,4 +16,22 @@ pub async fn common_main(event_loop: EventLoop<()>) {
     };
     let mut surface = Surface::new(ready).await;
     event_loop.run_app(&mut surface).unwrap();
-}
\ No newline at end of file
+}
+
+/// Bakes the cave's irradiance volume on a device without a window and writes it to `path`.
+#[cfg(not(target_os = "android"))]
+// only the desktop binary calls it, not the library built alongside it
+#[allow(dead_code)]
+pub async fn bake_irradiance(path: &Path) -> anyhow::Result<()> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions::default()).await.context("No adapter to bake on")?;
+    let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
+        label: Some("Irradiance Bake Device"),
+        required_features: (Game::required_features() | Game::optional_features()) & adapter.features(),
+        required_limits: Game::limits(),
+        memory_hints: wgpu::MemoryHints::default(),
+    }, None).await?;
+    Game::bake_irradiance(&device, &queue)?.save(path)?;
+    log::info!("Wrote {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,361 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use anyhow::{bail, Context};
+use cgmath::{Deg, Euler, Quaternion, Vector2, Vector3};
+
+use crate::{instance::Instance, load_resource};
+
+/// How a decal's color is combined with the surface under it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DecalBlend {
+    // the decal's color over the surface's
+    Alpha,
+    // darkens the surface by the decal's color, for stains and grime
+    Multiply,
+    // keeps the surface's detail while tinting it, for moss and lichen
+    Overlay,
+}
+
+impl DecalBlend {
+    // must match the DECAL_ constants in decals.wgsl
+    pub fn raw(&self) -> u32 {
+        match self {
+            DecalBlend::Alpha => 0,
+            DecalBlend::Multiply => 1,
+            DecalBlend::Overlay => 2,
+        }
+    }
+}
+
+/// A box that projects a texture down its local y axis onto whatever surfaces are inside it.
+#[derive(Clone)]
+pub struct DecalDesc {
+    // relative to the scene file
+    pub texture: PathBuf,
+    pub normal_map: Option<PathBuf>,
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    // the box's extent, the texture covers x and z and y is how deep it reaches
+    pub size: Vector3<f32>,
+    pub blend: DecalBlend,
+    pub opacity: f32,
+    // replace the surface's own values where the decal covers it
+    pub reflectivity: Option<f32>,
+    pub roughness: Option<f32>,
+}
+
+impl DecalDesc {
+    pub fn instance(&self) -> Instance {
+        Instance { position: self.position, rotation: self.rotation, scale: self.size }
+    }
+}
+
+/// What a particle emitter spawns, each with its own look and motion, see `ParticleKind::preset`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParticleKind {
+    Dust,
+    Sparkle,
+    Drip,
+}
+
+/// What an emitter follows.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmitterAttachment {
+    // stays where the scene put it
+    Fixed,
+    // around the player's lantern, its position is an offset from it
+    Lantern,
+    // one emitter on each glowing crystal, its position is an offset from them
+    Crystals,
+}
+
+/// Spawns particles at random inside a box, `rate` of them a second.
+#[derive(Clone)]
+pub struct EmitterDesc {
+    pub kind: ParticleKind,
+    pub attachment: EmitterAttachment,
+    pub position: Vector3<f32>,
+    pub size: Vector3<f32>,
+    // the kind's own rate if not given
+    pub rate: Option<f32>,
+}
+
+/// How an animation curve moves from its min to its max over each period, see `AnimationCurve::evaluate`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CurveShape {
+    // stays at its min
+    Constant,
+    // eases up to its max and back down, for slow pulses
+    Sine,
+    // wanders at random between them, for glows that sputter
+    Flicker,
+    // climbs to its max and jumps back, for textures that keep flowing one way
+    Ramp,
+}
+
+/// A value that changes with the time since the game started.
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationCurve {
+    pub shape: CurveShape,
+    // seconds for one cycle, or between the random values of a flicker
+    pub period: f32,
+    pub min: f32,
+    pub max: f32,
+    // fraction of a period the curve is ahead by, so materials sharing a curve don't move in lockstep
+    pub phase: f32,
+}
+
+/// How a material changes over time. The cave's materials go by their names in its MTL file
+/// and the glowing crystals by `crystals`, see animation.rs.
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialAnimation {
+    // light given off by the material, the curve's value times `emission_color`
+    pub emission: Option<AnimationCurve>,
+    pub emission_color: Vector3<f32>,
+    // how far the texture has moved along `scroll_direction`, a ramp from 0 to 1 moves it `scroll_direction` each period
+    pub scroll: Option<AnimationCurve>,
+    pub scroll_direction: Vector2<f32>,
+}
+
+impl Default for MaterialAnimation {
+    fn default() -> Self {
+        Self {
+            emission: None,
+            emission_color: Vector3::new(1.0, 1.0, 1.0),
+            scroll: None,
+            // down the texture
+            scroll_direction: Vector2::new(0.0, 1.0),
+        }
+    }
+}
+
+/// What is placed in the cave on top of its mesh, read from a text file in the style of OBJ,
+/// one entry per line as a keyword followed by named values.
+///
+/// ```text
+/// decal decals/moss.png position 1 -2 0 rotation 0 45 0 size 3 2 3 blend overlay roughness 1
+/// emitter drip position 2 6 0 size 4 0.5 4 rate 3
+/// animate material_2 emission flicker period 0.3 min 0.2 max 0.8 color 0.3 1 0.6
+/// ```
+#[derive(Default)]
+pub struct Scene {
+    pub decals: Vec<DecalDesc>,
+    pub emitters: Vec<EmitterDesc>,
+    // by material name
+    pub animations: HashMap<String, MaterialAnimation>,
+}
+
+impl Scene {
+    /// Loads a scene resource, an empty scene if it is missing or malformed.
+    pub fn load(path: &Path) -> Self {
+        let scene = load_resource(path).and_then(|bytes| Self::parse(&String::from_utf8(bytes)?));
+        match scene {
+            Ok(scene) => scene,
+            Err(err) => {
+                log::warn!("Failed to load the scene {}: {err:?}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let mut scene = Self::default();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut parts = line.split_whitespace();
+            let result = match parts.next() {
+                Some("decal") => parse_decal(parts).map(|decal| scene.decals.push(decal)),
+                Some("emitter") => parse_emitter(parts).map(|emitter| scene.emitters.push(emitter)),
+                Some("animate") => parse_animation(parts, &mut scene.animations),
+                Some(keyword) => Err(anyhow::anyhow!("Unknown keyword {keyword}")),
+                None => Ok(()),
+            };
+            result.with_context(|| format!("Line {}: {}", number + 1, line.trim()))?;
+        }
+        Ok(scene)
+    }
+}
+
+fn parse_floats<'a, const N: usize>(name: &str, parts: &mut impl Iterator<Item = &'a str>) -> anyhow::Result<[f32; N]> {
+    let mut values = [0.0; N];
+    for value in &mut values {
+        let part = parts.next().with_context(|| format!("{name} needs {N} values"))?;
+        *value = part.parse().with_context(|| format!("{name} has a value of {part}"))?;
+    }
+    Ok(values)
+}
+
+fn parse_decal<'a>(mut parts: impl Iterator<Item = &'a str>) -> anyhow::Result<DecalDesc> {
+    let mut decal = DecalDesc {
+        texture: parts.next().context("A decal needs a texture")?.into(),
+        normal_map: None,
+        position: Vector3::new(0.0, 0.0, 0.0),
+        rotation: Instance::default().rotation,
+        size: Vector3::new(1.0, 1.0, 1.0),
+        blend: DecalBlend::Alpha,
+        opacity: 1.0,
+        reflectivity: None,
+        roughness: None,
+    };
+    while let Some(name) = parts.next() {
+        match name {
+            "position" => decal.position = parse_floats::<3>(name, &mut parts)?.into(),
+            // degrees around x, y and z
+            "rotation" => {
+                let [x, y, z] = parse_floats(name, &mut parts)?;
+                decal.rotation = Euler::new(Deg(x), Deg(y), Deg(z)).into();
+            }
+            "size" => decal.size = parse_floats::<3>(name, &mut parts)?.into(),
+            "normal" => decal.normal_map = Some(parts.next().context("normal needs a texture")?.into()),
+            "blend" => decal.blend = match parts.next() {
+                Some("alpha") => DecalBlend::Alpha,
+                Some("multiply") => DecalBlend::Multiply,
+                Some("overlay") => DecalBlend::Overlay,
+                other => bail!("blend is alpha, multiply or overlay, got {}", other.unwrap_or_default()),
+            },
+            "opacity" => decal.opacity = parse_floats::<1>(name, &mut parts)?[0],
+            "reflectivity" => decal.reflectivity = Some(parse_floats::<1>(name, &mut parts)?[0]),
+            "roughness" => decal.roughness = Some(parse_floats::<1>(name, &mut parts)?[0]),
+            _ => bail!("Unknown decal value {name}"),
+        }
+    }
+    Ok(decal)
+}
+
+fn parse_emitter<'a>(mut parts: impl Iterator<Item = &'a str>) -> anyhow::Result<EmitterDesc> {
+    let kind = match parts.next() {
+        Some("dust") => ParticleKind::Dust,
+        Some("sparkle") => ParticleKind::Sparkle,
+        Some("drip") => ParticleKind::Drip,
+        other => bail!("An emitter is dust, sparkle or drip, got {}", other.unwrap_or_default()),
+    };
+    let mut emitter = EmitterDesc {
+        kind,
+        attachment: EmitterAttachment::Fixed,
+        position: Vector3::new(0.0, 0.0, 0.0),
+        size: Vector3::new(1.0, 1.0, 1.0),
+        rate: None,
+    };
+    while let Some(name) = parts.next() {
+        match name {
+            "attach" => emitter.attachment = match parts.next() {
+                Some("lantern") => EmitterAttachment::Lantern,
+                Some("crystals") => EmitterAttachment::Crystals,
+                other => bail!("attach is lantern or crystals, got {}", other.unwrap_or_default()),
+            },
+            "position" => emitter.position = parse_floats::<3>(name, &mut parts)?.into(),
+            "size" => emitter.size = parse_floats::<3>(name, &mut parts)?.into(),
+            "rate" => emitter.rate = Some(parse_floats::<1>(name, &mut parts)?[0]),
+            _ => bail!("Unknown emitter value {name}"),
+        }
+    }
+    Ok(emitter)
+}
+
+// Each line animates one property of a material, a second line for the same material adds to it.
+fn parse_animation<'a>(mut parts: impl Iterator<Item = &'a str>, animations: &mut HashMap<String, MaterialAnimation>) -> anyhow::Result<()> {
+    let material = parts.next().context("An animation needs a material")?;
+    let property = parts.next().unwrap_or_default();
+    if !matches!(property, "emission" | "scroll") {
+        bail!("An animation is of emission or scroll, got {property}");
+    }
+    let shape = match parts.next() {
+        Some("constant") => CurveShape::Constant,
+        Some("sine") => CurveShape::Sine,
+        Some("flicker") => CurveShape::Flicker,
+        Some("ramp") => CurveShape::Ramp,
+        other => bail!("A curve is constant, sine, flicker or ramp, got {}", other.unwrap_or_default()),
+    };
+    let mut curve = AnimationCurve { shape, period: 1.0, min: 0.0, max: 1.0, phase: 0.0 };
+    let animation = animations.entry(material.to_string()).or_default();
+    while let Some(name) = parts.next() {
+        match (property, name) {
+            (_, "period") => curve.period = parse_floats::<1>(name, &mut parts)?[0],
+            (_, "min") => curve.min = parse_floats::<1>(name, &mut parts)?[0],
+            (_, "max") => curve.max = parse_floats::<1>(name, &mut parts)?[0],
+            (_, "phase") => curve.phase = parse_floats::<1>(name, &mut parts)?[0],
+            ("emission", "color") => animation.emission_color = parse_floats::<3>(name, &mut parts)?.into(),
+            ("scroll", "direction") => animation.scroll_direction = parse_floats::<2>(name, &mut parts)?.into(),
+            _ => bail!("Unknown {property} animation value {name}"),
+        }
+    }
+    if curve.period <= 0.0 {
+        bail!("period must be above zero, got {}", curve.period);
+    }
+    match property {
+        "emission" => animation.emission = Some(curve),
+        _ => animation.scroll = Some(curve),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_add_what_they_describe() {
+        let scene = Scene::parse("
+            # a comment on its own
+            decal decals/moss.png position 1 -2 0 size 3 2 3 blend overlay roughness 1 # and after a line
+            decal decals/crack.png normal decals/crack_normal.png blend multiply opacity 0.5
+            emitter drip attach crystals position 0 1 0 rate 3
+            animate crystals emission sine period 4 min 0.5 max 2 color 0.3 1 0.6
+            animate crystals scroll ramp direction 1 0
+        ").unwrap();
+        assert_eq!(scene.decals.len(), 2);
+        let moss = &scene.decals[0];
+        assert_eq!(moss.texture, PathBuf::from("decals/moss.png"));
+        assert_eq!(moss.position, Vector3::new(1.0, -2.0, 0.0));
+        assert_eq!(moss.size, Vector3::new(3.0, 2.0, 3.0));
+        assert!(moss.blend == DecalBlend::Overlay);
+        assert_eq!((moss.opacity, moss.reflectivity, moss.roughness), (1.0, None, Some(1.0)));
+        let crack = &scene.decals[1];
+        assert_eq!(crack.normal_map, Some(PathBuf::from("decals/crack_normal.png")));
+        assert!(crack.blend == DecalBlend::Multiply);
+        assert_eq!(crack.opacity, 0.5);
+
+        assert_eq!(scene.emitters.len(), 1);
+        let drip = &scene.emitters[0];
+        assert_eq!((drip.kind, drip.attachment, drip.rate), (ParticleKind::Drip, EmitterAttachment::Crystals, Some(3.0)));
+        assert_eq!(drip.position, Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(drip.size, Vector3::new(1.0, 1.0, 1.0));
+
+        let crystals = scene.animations["crystals"];
+        let emission = crystals.emission.unwrap();
+        assert_eq!((emission.shape, emission.period, emission.min, emission.max), (CurveShape::Sine, 4.0, 0.5, 2.0));
+        assert_eq!(crystals.emission_color, Vector3::new(0.3, 1.0, 0.6));
+        assert_eq!(crystals.scroll.unwrap().shape, CurveShape::Ramp);
+        assert_eq!(crystals.scroll_direction, Vector2::new(1.0, 0.0));
+    }
+
+    #[test]
+    fn unknown_keywords_and_values_are_errors() {
+        assert!(Scene::parse("light 0 1 0").is_err());
+        assert!(Scene::parse("decal moss.png colour 1 0 0").is_err());
+        assert!(Scene::parse("decal moss.png blend screen").is_err());
+        assert!(Scene::parse("emitter smoke").is_err());
+        assert!(Scene::parse("emitter dust attach camera").is_err());
+        assert!(Scene::parse("animate rock glow sine").is_err());
+        assert!(Scene::parse("animate rock emission wobble").is_err());
+        assert!(Scene::parse("animate rock scroll ramp color 1 1 1").is_err());
+    }
+
+    #[test]
+    fn missing_values_are_errors() {
+        assert!(Scene::parse("decal").is_err());
+        assert!(Scene::parse("decal moss.png position 1 2").is_err());
+        assert!(Scene::parse("decal moss.png normal").is_err());
+        assert!(Scene::parse("decal moss.png opacity half").is_err());
+        assert!(Scene::parse("emitter").is_err());
+        assert!(Scene::parse("emitter dust rate").is_err());
+        assert!(Scene::parse("animate").is_err());
+        assert!(Scene::parse("animate rock emission sine period 0").is_err());
+    }
+
+    #[test]
+    fn errors_name_their_line() {
+        let err = Scene::parse("decal moss.png\n\nemitter smoke").err().unwrap();
+        assert!(format!("{err}").starts_with("Line 3"));
+    }
+}
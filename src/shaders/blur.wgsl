@@ -1,34 +1,47 @@
 struct Params {
-  image_size: vec2u,
-  output_scale: f32,
+  radius: i32,
+  sigma: f32,
+  output_size: vec2u,
 }
 
-t_input: $0,0;
-s_input: $0,1;
+// t_input and s_input are declared with sample_input, see BlurInput
 params: $1;
-outputTex: $2;
+output_tex: $2;
 
-struct Flip {
+struct Direction {
   value : u32,
 }
-@group(3) @binding(0) var<uniform> flip : Flip;
+@group(3) @binding(0) var<uniform> direction : Direction;
 
-@compute @workgroup_size(1, 1, 1)
+// One pass of a separable blur. The horizontal pass (direction 0) reads the
+// full size input and writes into the downscaled intermediate texture, the
+// vertical pass (direction 1) reads that intermediate and writes the output.
+// A sigma of 0 gives a box blur, anything else a gaussian.
+
+@compute @workgroup_size(8, 8, 1)
 fn main(
-  // @builtin(workgroup_id) WorkGroupID : vec3u,
   @builtin(global_invocation_id) invocation_id : vec3u
 ) {
+    if invocation_id.x >= params.output_size.x || invocation_id.y >= params.output_size.y {
+        return;
+    }
+    let texel = 1.0 / vec2f(params.output_size);
+    var step = vec2f(texel.x, 0.0);
+    if direction.value != 0u {
+        step = vec2f(0.0, texel.y);
+    }
+    let tex_coords = (vec2f(invocation_id.xy) + vec2f(0.5)) * texel;
+
     var acc = vec4f(0.0);
-    let steps = 1.0;
-    let dist = 0;
-    let dist_f = vec2f(f32(dist)/f32(params.image_size.x), f32(dist)/f32(params.image_size.y));
-    let tex_coords = vec2f(f32(invocation_id.x)/f32(params.image_size.x), f32(invocation_id.y)/f32(params.image_size.y));
-    for (var h = 0.0; h < 1.0; h += 1/steps) {
-      for (var k = 0.0; k < 1.0; k += 1/steps) {
-        acc += textureSampleLevel(t_input, s_input, tex_coords+vec2f(mix(-1*dist_f.x, dist_f.x, h), mix(-1*dist_f.y, dist_f.y, k)), 0.0)/(steps*steps);
-      }
-        // acc += textureSampleLevel(t_input, s_input, tex_coords+vec2f(mix(-1*dist_f.x, dist_f.x, h), 0.0), 0.0)/(steps*2.0);
+    var total = 0.0;
+    for (var i = -params.radius; i <= params.radius; i++) {
+        var weight = 1.0;
+        if params.sigma > 0.0 {
+            weight = exp(-f32(i * i) / (2.0 * params.sigma * params.sigma));
+        }
+        acc += sample_input(tex_coords + step * f32(i)) * weight;
+        total += weight;
     }
-    
-    textureStore(outputTex, vec2u(u32(f32(invocation_id.x)*params.output_scale), u32(f32(invocation_id.y)*params.output_scale)), acc);
-}
\ No newline at end of file
+
+    textureStore(output_tex, invocation_id.xy, acc / total);
+}
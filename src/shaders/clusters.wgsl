@@ -0,0 +1,61 @@
+screen_info: $0;
+params: $1,0;
+lights: $1,1;
+// per cluster a light count followed by MAX_CLUSTER_LIGHTS indices
+clusters: $1,2;
+
+// Splits the view frustum into a grid of froxels, tiles on screen and exponential
+// slices in depth, and lists the lights whose range touches each of them.
+
+fn slice_depth(slice: u32) -> f32 {
+    return params.near * pow(params.far / params.near, f32(slice) / f32(params.grid.z));
+}
+
+// a ray through the screen position scaled so that it advances one unit of view depth
+fn depth_ray(ndc: vec2f) -> vec3f {
+    let far_w = screen_info.camera.inverse_proj * vec4f(ndc, 1.0, 1.0);
+    let direction = far_w.xyz / far_w.w - params.camera_position;
+    return direction / dot(direction, params.camera_forward);
+}
+
+fn sphere_intersects_aabb(center: vec3f, radius: f32, aabb_min: vec3f, aabb_max: vec3f) -> bool {
+    let closest = clamp(center, aabb_min, aabb_max);
+    let offset = closest - center;
+    return dot(offset, offset) <= radius * radius;
+}
+
+@compute @workgroup_size(4, 4, 4)
+fn main(
+  @builtin(global_invocation_id) cluster : vec3u
+) {
+    if cluster.x >= params.grid.x || cluster.y >= params.grid.y || cluster.z >= params.grid.z {
+        return;
+    }
+    let tile_min = vec2f(cluster.xy) / vec2f(params.grid.xy);
+    let tile_max = vec2f(cluster.xy + 1u) / vec2f(params.grid.xy);
+    let near = slice_depth(cluster.z);
+    let far = slice_depth(cluster.z + 1u);
+
+    var aabb_min = vec3f(1e30);
+    var aabb_max = vec3f(-1e30);
+    for (var corner = 0u; corner < 4u; corner++) {
+        let uv = vec2f(select(tile_min.x, tile_max.x, (corner & 1u) != 0u), select(tile_min.y, tile_max.y, (corner & 2u) != 0u));
+        let ray = depth_ray(vec2f(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0));
+        let near_point = params.camera_position + ray * near;
+        let far_point = params.camera_position + ray * far;
+        aabb_min = min(aabb_min, min(near_point, far_point));
+        aabb_max = max(aabb_max, max(near_point, far_point));
+    }
+
+    let base = ((cluster.z * params.grid.y + cluster.y) * params.grid.x + cluster.x) * (MAX_CLUSTER_LIGHTS + 1u);
+    var count = 0u;
+    for (var i = 0u; i < params.light_count && count < MAX_CLUSTER_LIGHTS; i++) {
+        let light = lights[i];
+        // spot lights are tested by their whole sphere, the cone is left to the lighting pass
+        if light.kind == LIGHT_DIRECTIONAL || sphere_intersects_aabb(light.position, light.radius, aabb_min, aabb_max) {
+            clusters[base + 1u + count] = i;
+            count++;
+        }
+    }
+    clusters[base] = count;
+}
@@ -4,6 +4,10 @@ t_normal: $0,2;
 s_normal: $0,3;
 t_diffuse: $0,4;
 s_diffuse: $0,5;
+t_surface: $0,8;
+s_surface: $0,9;
+t_baked: $0,10;
+s_baked: $0,11;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -29,6 +33,8 @@ struct FragmentOutput {
   @location(0) material: vec4f,
   @location(1) normal: vec4f,
   @location(2) diffuse: vec4f,
+  @location(3) surface: vec4f,
+  @location(4) baked: vec4f,
 }
 
 @fragment
@@ -37,5 +43,7 @@ fn fs_main(in: VertexOutput) -> FragmentOutput {
     out.material = textureSample(t_material, s_material, in.tex_coords);
     out.normal = textureSample(t_normal, s_normal, in.tex_coords);
     out.diffuse = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    out.surface = textureSample(t_surface, s_surface, in.tex_coords);
+    out.baked = textureSample(t_baked, s_baked, in.tex_coords);
     return out;
 }
\ No newline at end of file
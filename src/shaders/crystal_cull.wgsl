@@ -0,0 +1,127 @@
+struct CrystalInstance {
+    position: vec3f,
+    crystal_type: u32,
+    rotation: vec4f,
+    scale: vec3f,
+}
+
+struct InstanceRaw {
+    model_matrix: mat4x4f,
+    normal_matrix: mat3x3f,
+}
+
+struct CullParams {
+    planes: array<vec4f, 6>,
+    // the camera the Hi-Z pyramid was rendered from
+    occlusion_view_proj: mat4x4f,
+    instance_count: u32,
+    type_count: u32,
+    // zero for targets that weren't given a frustum this frame
+    enabled: u32,
+    // zero for targets that aren't tested against the pyramid
+    occlusion: u32,
+}
+
+struct CrystalType {
+    radius: f32,
+}
+
+struct CullRegion {
+    // first slot of this type's survivors of this target in the output buffer
+    offset: u32,
+    capacity: u32,
+}
+
+instances: $0;
+// one per target
+params: $1,0;
+types: $1,1;
+// one per type per target
+regions: $1,2;
+output: $2,0;
+// five u32 per type per target laid out like DrawIndexedIndirectArgs, instance_count is the second
+draw_args: $2,1;
+// furthest depth of last frame per texel, coarser with every mip
+t_hi_z: $3;
+
+fn rotation_matrix(q: vec4f) -> mat3x3f {
+    let x = q.x;
+    let y = q.y;
+    let z = q.z;
+    let w = q.w;
+    return mat3x3f(
+        vec3f(1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + w * z), 2.0 * (x * z - w * y)),
+        vec3f(2.0 * (x * y - w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + w * x)),
+        vec3f(2.0 * (x * z + w * y), 2.0 * (y * z - w * x), 1.0 - 2.0 * (x * x + y * y)),
+    );
+}
+
+fn in_frustum(target_params: CullParams, center: vec3f, radius: f32) -> bool {
+    for (var i = 0; i < 6; i++) {
+        let plane = target_params.planes[i];
+        if dot(plane.xyz, center) + plane.w < -radius {
+            return false;
+        }
+    }
+    return true;
+}
+
+// Frustum culls every crystal and appends the visible ones to their type's region
+// of the target's output, counting them into that type's indirect draw. Crystals
+// that don't fit in a full region are dropped.
+// Each row of workgroups culls for one target, the camera's also drops crystals
+// that were hidden last frame.
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+  @builtin(global_invocation_id) invocation_id : vec3u
+) {
+    let index = invocation_id.x;
+    let target_index = invocation_id.y;
+    let target_params = params[target_index];
+    if target_params.enabled == 0u || index >= target_params.instance_count {
+        return;
+    }
+    let instance = instances[index];
+    if instance.crystal_type >= target_params.type_count {
+        return;
+    }
+    let crystal_type = types[instance.crystal_type];
+    let scale = abs(instance.scale);
+    let radius = crystal_type.radius * max(scale.x, max(scale.y, scale.z));
+    if !in_frustum(target_params, instance.position, radius) {
+        return;
+    }
+    if target_params.occlusion != 0u && hi_z_occluded(t_hi_z, target_params.occlusion_view_proj, instance.position - vec3f(radius), instance.position + vec3f(radius)) {
+        return;
+    }
+
+    let draw = target_index * target_params.type_count + instance.crystal_type;
+    let region = regions[draw];
+    let slot = atomicAdd(&draw_args[draw * 5u + 1u], 1u);
+    if slot >= region.capacity {
+        // every slot taken past the capacity is given back, so the count ends at the capacity
+        atomicSub(&draw_args[draw * 5u + 1u], 1u);
+        return;
+    }
+    let rotation = rotation_matrix(instance.rotation);
+    let linear = rotation * mat3x3f(
+        vec3f(instance.scale.x, 0.0, 0.0),
+        vec3f(0.0, instance.scale.y, 0.0),
+        vec3f(0.0, 0.0, instance.scale.z),
+    );
+    var out: InstanceRaw;
+    out.model_matrix = mat4x4f(
+        vec4f(linear[0], 0.0),
+        vec4f(linear[1], 0.0),
+        vec4f(linear[2], 0.0),
+        vec4f(instance.position, 1.0),
+    );
+    // the inverse transpose of rotation * scale is rotation * inverse scale
+    out.normal_matrix = rotation * mat3x3f(
+        vec3f(1.0 / instance.scale.x, 0.0, 0.0),
+        vec3f(0.0, 1.0 / instance.scale.y, 0.0),
+        vec3f(0.0, 0.0, 1.0 / instance.scale.z),
+    );
+    output[region.offset + slot] = out;
+}
@@ -1,6 +1,7 @@
 camera: $0;
 screen_info: $1;
 light: $2;
+crystal_animation: $3;
 
 //CUBE
 // struct VertexInput {
@@ -20,6 +21,9 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
 }
 
 struct VertexOutput {
@@ -41,8 +45,11 @@ fn vs_main(
     );
     var out: VertexOutput;
     out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
-    let rotation_matrix = mat3x3(model_matrix[0].xyz, model_matrix[1].xyz, model_matrix[2].xyz);
-    out.normal = rotation_matrix*model.normal;
+    // sub-pixel offset for TAA, zero when it is disabled
+    out.clip_position = vec4f(out.clip_position.xy + screen_info.jitter * out.clip_position.w, out.clip_position.zw);
+    // inverse transpose of the model matrix, the upper 3x3 alone skews normals under non-uniform scale
+    let normal_matrix = mat3x3(instance.normal_matrix_0, instance.normal_matrix_1, instance.normal_matrix_2);
+    out.normal = normalize(normal_matrix * model.normal);
     out.world_position = (model_matrix * vec4<f32>(model.position, 1.0)).xyz;
     return out;
 }
@@ -50,8 +57,12 @@ fn vs_main(
 struct FragmentOutput {
   @location(0) material: vec4f,
   @location(1) normal: vec4f,
+  @location(2) surface: vec4f,
 }
 
+const CRYSTAL_REFLECTIVITY = 0.3;
+const CRYSTAL_ROUGHNESS = 0.02;
+
 @fragment
 fn fs_main(in: VertexOutput) -> FragmentOutput {
     var out: FragmentOutput;
@@ -62,7 +73,10 @@ fn fs_main(in: VertexOutput) -> FragmentOutput {
     //     textureStore(normal_buffer, tex_coords_u, vec4f(texture_normal, 1.0));
     // }
     out.normal = vec4f((in.normal+vec3f(1.0))*0.5, 1.0);
-    out.material = vec4f(1.0);
+    // the lighting pass tells crystals apart by the alpha alone, which leaves the red for how brightly they glow
+    let glow = animate(crystal_animation.emission_shape, crystal_animation.emission_curve, screen_info.time);
+    out.material = vec4f(saturate(glow / MAX_CRYSTAL_GLOW), 0.0, 0.0, 1.0);
+    out.surface = vec4f(CRYSTAL_REFLECTIVITY, CRYSTAL_ROUGHNESS, 1.0, 1.0);
     return out;
 
     //DEBUG
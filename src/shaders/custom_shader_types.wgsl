@@ -2,13 +2,29 @@ struct ScreenInfo {
     screen_size: vec2f,
     time: f32,
     camera: Camera,
+    prev_camera: Camera,
+    jitter: vec2f,
 }
 
 struct Light {
     position: vec3f,
+    radius: f32,
     color: vec3f,
+    // channel of the shadow texture holding this light's shadow, -1 when unshadowed
+    shadow_slot: i32,
+    // unused by point lights
+    direction: vec3f,
+    kind: u32,
+    cos_inner: f32,
+    cos_outer: f32,
+    // candela, or lux for directional lights
+    intensity: f32,
 }
 
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_SPOT: u32 = 1u;
+const LIGHT_DIRECTIONAL: u32 = 2u;
+
 fn mix_colors(back: vec4f, front: vec4f) -> vec4f{
     let pre_back = vec4f(back.rgb * back.a, back.a);
     let pre_front = vec4f(front.rgb * front.a, front.a);
@@ -16,4 +32,358 @@ fn mix_colors(back: vec4f, front: vec4f) -> vec4f{
     let final_a = back.a + (front.a * (1.0 - back.a));
     return vec4f(final_rgb, final_a);
 
-}
\ No newline at end of file
+}
+
+const MAX_CLUSTER_LIGHTS: u32 = 32u;
+
+struct ClusterParams {
+    camera_position: vec3f,
+    near: f32,
+    camera_forward: vec3f,
+    far: f32,
+    grid: vec3u,
+    light_count: u32,
+    debug: u32,
+    // scales illuminance in lux to the brightness the lighting works in
+    exposure: f32,
+}
+
+const MAX_SHADOWED_LIGHTS: u32 = 4u;
+
+struct ShadowLight {
+    position: vec3f,
+    far: f32,
+    near: f32,
+    // in shadow map texels
+    normal_offset: f32,
+    kind: u32,
+    face_count: u32,
+    // cube faces in the order +x, -x, +y, -y, +z, -z for point lights, a single face for
+    // spot lights and cascades from nearest to furthest for directional ones
+    faces: array<mat4x4f, 6>,
+    // atlas uv offset in xy and size in zw for each face
+    tiles: array<vec4f, 6>,
+    // world size across each face, at unit distance for perspective faces
+    face_spans: array<f32, 6>,
+    // the screen space contact shadow ray, in world units
+    contact_length: f32,
+    contact_thickness: f32,
+    // zero for point lights
+    direction: vec3f,
+}
+
+struct ShadowAtlasParams {
+    count: u32,
+    atlas_size: f32,
+}
+
+// index of the light count of the cluster this pixel falls in, the light indices follow it
+fn cluster_base(params: ClusterParams, tex_coords: vec2f, world_position: vec3f) -> u32 {
+    let depth = max(dot(world_position - params.camera_position, params.camera_forward), params.near);
+    let slice = u32(log(depth / params.near) / log(params.far / params.near) * f32(params.grid.z));
+    let cluster = min(vec3u(vec2u(tex_coords * vec2f(params.grid.xy)), slice), params.grid - 1u);
+    return ((cluster.z * params.grid.y + cluster.y) * params.grid.x + cluster.x) * (MAX_CLUSTER_LIGHTS + 1u);
+}
+
+// the direction towards the light in xyz and how much of it reaches the position in w
+fn incident_light(light: Light, world_position: vec3f, exposure: f32) -> vec4f {
+    if light.kind == LIGHT_DIRECTIONAL {
+        return vec4f(-light.direction, light.intensity * exposure);
+    }
+    let light_dir = normalize(light.position - world_position);
+    var attenuation = distance_attenuation(light, distance(light.position, world_position)) * exposure;
+    if light.kind == LIGHT_SPOT {
+        attenuation *= smoothstep(light.cos_outer, light.cos_inner, dot(-light_dir, light.direction));
+    }
+    return vec4f(light_dir, attenuation);
+}
+
+// inverse square falloff, windowed to reach zero smoothly at the light's radius
+fn distance_attenuation(light: Light, light_distance: f32) -> f32 {
+    let window = saturate(1.0 - pow(light_distance / light.radius, 4.0));
+    // closer than 10 cm would blow up
+    return light.intensity / max(light_distance * light_distance, 0.01) * window * window;
+}
+
+// the cube face looking along the major axis of the direction
+fn cube_face(direction: vec3f) -> u32 {
+    let a = abs(direction);
+    if a.x >= a.y && a.x >= a.z {
+        return select(1u, 0u, direction.x > 0.0);
+    }
+    if a.y >= a.z {
+        return select(3u, 2u, direction.y > 0.0);
+    }
+    return select(5u, 4u, direction.z > 0.0);
+}
+
+// must match MAX_REFLECTION_PROBES in reflection_probe.rs
+const MAX_REFLECTION_PROBES = 8u;
+
+struct ReflectionProbeParams {
+    // the cube faces seen from the origin, a probe's faces only differ by its position
+    faces: array<mat4x4f, 6>,
+    positions: array<vec4f, MAX_REFLECTION_PROBES>,
+    count: u32,
+    mip_count: f32,
+}
+
+// Texture coordinates of `direction` on the cube face looking through `face_view_proj`.
+fn cube_face_uv(face_view_proj: mat4x4f, direction: vec3f) -> vec2f {
+    let clip = face_view_proj * vec4f(direction, 1.0);
+    let ndc = clip.xy / clip.w;
+    // just inside the face, filtering can't reach across the seam to the next one
+    return clamp(vec2f((ndc.x + 1.0) / 2.0, (ndc.y - 1.0) / -2.0), vec2f(0.004), vec2f(0.996));
+}
+
+// What the probe nearest `position` sees along `direction`, blurrier the rougher the surface.
+fn sample_reflection_probes(probes: texture_2d_array<f32>, probe_sampler: sampler, params: ReflectionProbeParams, position: vec3f, direction: vec3f, roughness: f32) -> vec3f {
+    if params.count == 0u {
+        return vec3f(0.0);
+    }
+    // copied into function memory, arrays passed by value can't be indexed dynamically everywhere
+    var positions = params.positions;
+    var faces = params.faces;
+    var nearest = 0u;
+    var nearest_distance = 1e30;
+    for (var i = 0u; i < min(params.count, MAX_REFLECTION_PROBES); i++) {
+        let offset = positions[i].xyz - position;
+        let distance = dot(offset, offset);
+        if distance < nearest_distance {
+            nearest = i;
+            nearest_distance = distance;
+        }
+    }
+    let face = cube_face(direction);
+    let tex_coords = cube_face_uv(faces[face], direction);
+    return textureSampleLevel(probes, probe_sampler, tex_coords, nearest * 6u + face, roughness * (params.mip_count - 1.0)).rgb;
+}
+
+// second order spherical harmonics of the light a diffuse surface receives, see irradiance.rs
+// the alpha of the first coefficient is how much the probe can be trusted
+struct IrradianceProbe {
+    sh: array<vec4f, 9>,
+}
+
+struct IrradianceGrid {
+    origin: vec3f,
+    cell_size: vec3f,
+    // probes along each axis, x varies fastest in the probe array
+    dims: vec3u,
+}
+
+fn evaluate_sh(sh: array<vec4f, 9>, normal: vec3f) -> vec3f {
+    let n = normal;
+    return sh[0].rgb * 0.282095
+        + sh[1].rgb * 0.488603 * n.y
+        + sh[2].rgb * 0.488603 * n.z
+        + sh[3].rgb * 0.488603 * n.x
+        + sh[4].rgb * 1.092548 * n.x * n.y
+        + sh[5].rgb * 1.092548 * n.y * n.z
+        + sh[6].rgb * 0.315392 * (3.0 * n.z * n.z - 1.0)
+        + sh[7].rgb * 1.092548 * n.x * n.z
+        + sh[8].rgb * 0.546274 * (n.x * n.x - n.y * n.y);
+}
+
+// 1.0 where the light reaches the position, 0.0 where its shadow map has something in the way
+fn shadow_visibility(atlas: texture_depth_2d, atlas_size: f32, light: ShadowLight, world_position: vec3f, normal: vec3f) -> f32 {
+    if light.kind == LIGHT_DIRECTIONAL {
+        // the nearest cascade that holds the position, orthographic faces have no w to divide by
+        for (var face = 0u; face < light.face_count; face++) {
+            let projected = light.faces[face] * vec4f(world_position, 1.0);
+            if all(abs(projected.xy) < vec2f(1.0)) && projected.z > 0.0 && projected.z < 1.0 {
+                return sample_shadow_face(atlas, atlas_size, light, face, world_position, normal, light.face_spans[face]);
+            }
+        }
+        return 1.0;
+    }
+    var face = 0u;
+    if light.kind == LIGHT_POINT {
+        face = cube_face(world_position - light.position);
+    }
+    return sample_shadow_face(atlas, atlas_size, light, face, world_position, normal, light.face_spans[face] * distance(world_position, light.position));
+}
+
+// `span` is the world size across the face at the position
+fn sample_shadow_face(atlas: texture_depth_2d, atlas_size: f32, light: ShadowLight, face: u32, world_position: vec3f, normal: vec3f, span: f32) -> f32 {
+    let tile = light.tiles[face] * atlas_size;
+    // pushing the lookup out by normal_offset texels along the normal keeps surfaces from shadowing themselves
+    let offset_position = world_position + normal * light.normal_offset * span / tile.z;
+    let camera_space_pos_w = light.faces[face] * vec4f(offset_position, 1.0);
+    let camera_space_pos = camera_space_pos_w.xyz / camera_space_pos_w.w;
+    let tex_coords = vec2f((camera_space_pos.x+1.0)/2.0, (camera_space_pos.y-1.0)/-2.0);
+    // outside a spot light's face is outside its cone, which the lighting already leaves dark
+    if any(tex_coords < vec2f(0.0)) || any(tex_coords > vec2f(1.0)) {
+        return 1.0;
+    }
+    // stay inside the face's tile so neighbouring tiles never bleed in
+    let texel = clamp(tile.xy + tex_coords * tile.zw, tile.xy, tile.xy + tile.zw - 1.0);
+    // the shadow pipeline's depth bias already pushed the stored depths back
+    let light_depth = textureLoad(atlas, vec2i(texel), 0);
+    if light.kind == LIGHT_DIRECTIONAL {
+        return select(1.0, 0.0, camera_space_pos.z > light_depth);
+    }
+    let depth = linearize_shadow_depth(camera_space_pos.z, light.near, light.far);
+    if depth <= 0.0 || depth >= 1.0 {
+        return 1.0;
+    }
+    return select(1.0, 0.0, depth > linearize_shadow_depth(light_depth, light.near, light.far));
+}
+
+fn linearize_shadow_depth(depth: f32, near: f32, far: f32) -> f32 {
+    let r = (2.0 * near) / (far + near - depth * (far - near));
+    return r;
+}
+
+// The world position the depth buffer holds at `tex_coords`, for the camera whose view projection
+// `inverse_view_proj` undoes. The camera's `inverse_proj` is that inverse.
+fn world_position_at(inverse_view_proj: mat4x4f, tex_coords: vec2f, depth: f32) -> vec3f {
+    let clip_pos = vec4(tex_coords.x * 2.0 - 1.0, tex_coords.y * -2.0 + 1.0, depth, 1.0);
+    let view_pos = inverse_view_proj * clip_pos;
+    return view_pos.xyz / view_pos.w;
+}
+
+// Screen uv of `position` in xy and its clip w in z, which is zero or negative behind the camera.
+fn project(view_proj: mat4x4f, position: vec3f) -> vec3f {
+    let clip = view_proj * vec4f(position, 1.0);
+    let ndc = clip.xyz / clip.w;
+    return vec3f(ndc.x * 0.5 + 0.5, ndc.y * -0.5 + 0.5, clip.w);
+}
+
+// How far `position` is behind `surface` along the view ray from `eye`, negative in front of it.
+fn depth_difference(eye: vec3f, position: vec3f, surface: vec3f) -> f32 {
+    return distance(eye, position) - distance(eye, surface);
+}
+
+// True if the box was behind everything in the Hi-Z pyramid over the area it covered, `view_proj`
+// being the camera the pyramid was rendered from. Anything that can't be tested conservatively
+// counts as visible.
+fn hi_z_occluded(hi_z: texture_2d<f32>, view_proj: mat4x4f, box_min: vec3f, box_max: vec3f) -> bool {
+    var ndc_min = vec3f(1e30);
+    var ndc_max = vec3f(-1e30);
+    for (var i = 0u; i < 8u; i++) {
+        let corner = select(box_min, box_max, vec3<bool>((i & 1u) != 0u, (i & 2u) != 0u, (i & 4u) != 0u));
+        let clip = view_proj * vec4f(corner, 1.0);
+        // boxes crossing the near plane can't be projected, and are right in front of the camera anyway
+        if clip.w <= 0.0 {
+            return false;
+        }
+        let ndc = clip.xyz / clip.w;
+        ndc_min = min(ndc_min, ndc);
+        ndc_max = max(ndc_max, ndc);
+    }
+    if any(ndc_max.xy < vec2f(-1.0)) || any(ndc_min.xy > vec2f(1.0)) {
+        // off the screen the pyramid covers, frustum culling decides these
+        return false;
+    }
+    // texture rows go down the screen
+    let uv_min = clamp(vec2f(ndc_min.x, -ndc_max.y) * 0.5 + 0.5, vec2f(0.0), vec2f(1.0));
+    let uv_max = clamp(vec2f(ndc_max.x, -ndc_min.y) * 0.5 + 0.5, vec2f(0.0), vec2f(1.0));
+    // the mip where the box spans about a texel, so a few loads cover it
+    let extent = (uv_max - uv_min) * vec2f(textureDimensions(hi_z, 0));
+    let level = min(u32(ceil(log2(max(max(extent.x, extent.y), 1.0)))), textureNumLevels(hi_z) - 1u);
+    let size = textureDimensions(hi_z, level);
+    // odd sized levels don't halve exactly, a texel of margin keeps the test conservative
+    let start = vec2u(max(vec2i(uv_min * vec2f(size)) - 1, vec2i(0)));
+    let end = min(vec2u(uv_max * vec2f(size)) + 1u, size - 1u);
+    for (var y = start.y; y <= end.y; y++) {
+        for (var x = start.x; x <= end.x; x++) {
+            if ndc_min.z <= textureLoad(hi_z, vec2u(x, y), i32(level)).r {
+                return false;
+            }
+        }
+    }
+    return true;
+}
+
+// per pixel noise that is cheap and spreads evenly, for jittering marches
+fn interleaved_gradient_noise(pixel: vec2f) -> f32 {
+    return fract(52.9829189 * fract(dot(pixel, vec2f(0.06711056, 0.00583715))));
+}
+
+struct ParticleEmitter {
+    position: vec3f,
+    lifetime: f32,
+    // the box particles spawn in
+    size: vec3f,
+    spread: f32,
+    velocity: vec3f,
+    // fraction of gravity
+    gravity: f32,
+    albedo: vec3f,
+    drag: f32,
+    emission: vec3f,
+    wander: f32,
+    // the emitter's slots of the particle buffer
+    first: u32,
+    count: u32,
+    particle_size: f32,
+    stretch: f32,
+    opacity: f32,
+    // negative dies on hitting the cave
+    bounce: f32,
+}
+
+struct Particle {
+    position: vec3f,
+    // negative until it is first spawned
+    age: f32,
+    velocity: vec3f,
+    lifetime: f32,
+    emitter: u32,
+}
+
+// must match CurveShape::raw in animation.rs
+const CURVE_CONSTANT: u32 = 0u;
+const CURVE_SINE: u32 = 1u;
+const CURVE_FLICKER: u32 = 2u;
+const CURVE_RAMP: u32 = 3u;
+
+struct MaterialAnimation {
+    emission_color: vec3f,
+    emission_shape: u32,
+    // period, min, max and phase
+    emission_curve: vec4f,
+    // zero for materials that don't scroll
+    scroll_direction: vec2f,
+    scroll_shape: u32,
+    scroll_curve: vec4f,
+}
+
+// pcg, a well mixed hash for random numbers. animation.rs has the same one so flickers match on both sides
+fn pcg_hash(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// smoothly interpolated random values, one per whole number
+fn animation_noise(x: f32) -> f32 {
+    let cell = i32(floor(x));
+    let a = f32(pcg_hash(bitcast<u32>(cell))) / 4294967295.0;
+    let b = f32(pcg_hash(bitcast<u32>(cell + 1))) / 4294967295.0;
+    return mix(a, b, smoothstep(0.0, 1.0, x - floor(x)));
+}
+
+// The value of an animation curve `time` seconds in, see AnimationCurve::evaluate.
+fn animate(shape: u32, curve: vec4f, time: f32) -> f32 {
+    let cycles = time / curve.x + curve.w;
+    var amount = 0.0;
+    switch shape {
+        case CURVE_SINE: {
+            amount = 0.5 - 0.5 * cos(cycles * 6.28318530718);
+        }
+        case CURVE_FLICKER: {
+            // a slow wander with quicker sputters on top
+            amount = animation_noise(cycles) * 0.7 + animation_noise(cycles * 2.7 + 17.0) * 0.3;
+        }
+        case CURVE_RAMP: {
+            amount = fract(cycles);
+        }
+        default: {}
+    }
+    return mix(curve.y, curve.z, amount);
+}
+
+// the crystals' glow is kept in the red of their material, as a fraction of this
+const MAX_CRYSTAL_GLOW = 4.0;
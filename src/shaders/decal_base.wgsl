@@ -0,0 +1,23 @@
+t_normal: $0,2;
+s_normal: $0,3;
+t_diffuse: $0,4;
+s_diffuse: $0,5;
+t_surface: $0,8;
+s_surface: $0,9;
+
+struct FragmentOutput {
+  @location(0) normal: vec4f,
+  @location(1) diffuse: vec4f,
+  @location(2) surface: vec4f,
+}
+
+// Copies the G-buffer under the box, so the decals blended into it can still read the surface they cover.
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    let pixel = vec2u(in.clip_position.xy);
+    var out: FragmentOutput;
+    out.normal = vec4f(textureLoad(t_normal, pixel, 0).rgb, 1.0);
+    out.diffuse = vec4f(textureLoad(t_diffuse, pixel, 0).rgb, 1.0);
+    out.surface = vec4f(textureLoad(t_surface, pixel, 0).rgb, 1.0);
+    return out;
+}
@@ -0,0 +1,46 @@
+screen_info: $2;
+
+decals: $3,3;
+
+struct Decal {
+    // the box into world space and back, it spans -0.5 to 0.5 and projects down its y axis
+    transform: mat4x4f,
+    inverse_transform: mat4x4f,
+    // the box's x and y axes in world space, the texture's u runs along x
+    tangent: vec3f,
+    blend: u32,
+    up: vec3f,
+    opacity: f32,
+    // world size the texture covers
+    size: vec2f,
+    texture: u32,
+    // layer of t_decal_normals, negative without one
+    normal_map: i32,
+    // negative keeps the surface's own
+    reflectivity: f32,
+    roughness: f32,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) @interpolate(flat) decal: u32,
+}
+
+// Draws the box of each decal instance. Corner i sits at the ends of the x, y and z axes its bits pick,
+// and the faces wind counter-clockwise seen from outside, so culling clockwise keeps the far side and
+// the box still draws with the camera inside it.
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex: u32,
+    @builtin(instance_index) instance: u32,
+) -> VertexOutput {
+    var corners = array(4u, 6u, 2u, 4u, 2u, 0u, 1u, 3u, 7u, 1u, 7u, 5u, 1u, 5u, 4u, 1u, 4u, 0u, 2u, 6u, 7u, 2u, 7u, 3u, 2u, 3u, 1u, 2u, 1u, 0u, 4u, 5u, 7u, 4u, 7u, 6u);
+    let corner = corners[vertex];
+    let local = vec3f((vec3u(corner) >> vec3u(0u, 1u, 2u)) & vec3u(1u)) - vec3f(0.5);
+    var out: VertexOutput;
+    out.decal = instance;
+    out.clip_position = screen_info.camera.view_proj * decals[instance].transform * vec4f(local, 1.0);
+    // sub-pixel offset for TAA, zero when it is disabled
+    out.clip_position = vec4f(out.clip_position.xy + screen_info.jitter * out.clip_position.w, out.clip_position.zw);
+    return out;
+}
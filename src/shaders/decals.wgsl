@@ -0,0 +1,99 @@
+t_normal: $0,2;
+s_normal: $0,3;
+t_diffuse: $0,4;
+s_diffuse: $0,5;
+t_surface: $0,8;
+s_surface: $0,9;
+
+t_depth: $1,0;
+s_depth: $1,1;
+
+t_decals: $3,0;
+s_decals: $3,1;
+t_decal_normals: $3,2;
+
+// must match DecalBlend::raw
+const DECAL_ALPHA = 0u;
+const DECAL_MULTIPLY = 1u;
+const DECAL_OVERLAY = 2u;
+// surfaces turned further from the box's up axis than this fade out, the texture would streak across them
+const FACING_START = 0.2;
+const FACING_END = 0.5;
+// how far from the box's top and bottom its decal starts fading, so the cut isn't visible where it meets a wall
+const DEPTH_FADE_START = 0.35;
+
+struct FragmentOutput {
+  @location(0) normal: vec4f,
+  @location(1) diffuse: vec4f,
+  @location(2) surface: vec4f,
+}
+
+fn overlay(base: vec3f, blend: vec3f) -> vec3f {
+    return select(1.0 - 2.0 * (1.0 - base) * (1.0 - blend), 2.0 * base * blend, base < vec3f(0.5));
+}
+
+fn blend_decal(base: vec3f, color: vec3f, blend: u32) -> vec3f {
+    switch blend {
+        case DECAL_MULTIPLY: {
+            return base * color;
+        }
+        case DECAL_OVERLAY: {
+            return overlay(base, color);
+        }
+        default: {
+            return color;
+        }
+    }
+}
+
+// Blends the decal into the G-buffer where the surface behind the pixel lies inside its box. Every target
+// takes the decal's coverage as alpha, zero leaves it as it was. What the decal is blended with is read from
+// the copy the base pass made, the G-buffer itself can't be read while it is drawn to.
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    let pixel = vec2u(in.clip_position.xy);
+    let depth = textureLoad(t_depth, pixel, 0);
+    if depth >= 1.0 {
+        discard;
+    }
+    let decal = decals[in.decal];
+    let tex_coords = in.clip_position.xy / screen_info.screen_size;
+    let world_position = world_position_at(screen_info.camera.inverse_proj, tex_coords, depth);
+    let local = (decal.inverse_transform * vec4f(world_position, 1.0)).xyz;
+    if any(abs(local) > vec3f(0.5)) {
+        discard;
+    }
+
+    // the world size of a pixel here, which picks the decal textures' mip level
+    let pixel_size = distance(world_position_at(screen_info.camera.inverse_proj, tex_coords + vec2f(1.0 / screen_info.screen_size.x, 0.0), depth), world_position);
+    let uv = vec2f(local.x + 0.5, 0.5 - local.z);
+    let lod = log2(pixel_size * f32(textureDimensions(t_decals).x) / min(decal.size.x, decal.size.y));
+    let color = textureSampleLevel(t_decals, s_decals, uv, decal.texture, lod);
+    let base_surface = textureLoad(t_surface, pixel, 0);
+    let surface_normal = normalize(textureLoad(t_normal, pixel, 0).xyz * 2.0 - vec3f(1.0));
+    let facing = smoothstep(FACING_START, FACING_END, dot(surface_normal, decal.up));
+    let coverage = color.a * decal.opacity * facing * (1.0 - smoothstep(DEPTH_FADE_START, 0.5, abs(local.y)));
+    if coverage <= 0.0 {
+        discard;
+    }
+
+    var out: FragmentOutput;
+    out.diffuse = vec4f(blend_decal(textureLoad(t_diffuse, pixel, 0).rgb, color.rgb, decal.blend), coverage);
+    if decal.normal_map >= 0 {
+        let tangent_normal = textureSampleLevel(t_decal_normals, s_decals, uv, decal.normal_map, lod).xyz * 2.0 - vec3f(1.0);
+        // the box's axes laid flat on the surface, v runs against the box's z
+        let tangent = normalize(decal.tangent - surface_normal * dot(decal.tangent, surface_normal));
+        let bitangent = cross(surface_normal, tangent);
+        let decal_normal = normalize(tangent * tangent_normal.x + bitangent * tangent_normal.y + surface_normal * tangent_normal.z);
+        out.normal = vec4f((decal_normal + vec3f(1.0)) * 0.5, coverage);
+    }
+    if decal.reflectivity >= 0.0 || decal.roughness >= 0.0 {
+        out.surface = vec4f(
+            select(base_surface.r, decal.reflectivity, decal.reflectivity >= 0.0),
+            select(base_surface.g, decal.roughness, decal.roughness >= 0.0),
+            base_surface.b,
+            coverage,
+        );
+    }
+    return out;
+}
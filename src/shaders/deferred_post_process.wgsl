@@ -10,6 +10,10 @@ t_diffuse: $2,4;
 s_diffuse: $2,5;
 t_shadows: $2,6;
 s_shadows: $2,7;
+t_surface: $2,8;
+s_surface: $2,9;
+t_baked: $2,10;
+s_baked: $2,11;
 
 screen_info: $3;
 
@@ -18,7 +22,21 @@ s_backface_depth: $4,1;
 t_frontface_depth: $4,2;
 s_frontface_depth: $4,3;
 
-light: $5;
+cluster_params: $5,0;
+lights: $5,1;
+clusters: $5,2;
+
+t_crystal_blur: $6,0;
+s_crystal_blur: $6,1;
+
+t_probes: $7,0;
+s_probes: $7,1;
+probe_params: $7,2;
+irradiance_probes: $7,3;
+irradiance_grid: $7,4;
+
+// sampled a little off the surface so the probes behind it don't darken it
+const IRRADIANCE_NORMAL_OFFSET = 0.3;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -51,7 +69,8 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         let e_material = textureLoad(t_material, tex_coords_u + vec2u(border_width, 0), 0);
         let s_material = textureLoad(t_material, tex_coords_u - vec2u(0, border_width), 0);
         let n_material = textureLoad(t_material, tex_coords_u + vec2u(0, border_width), 0);
-        let frontface_depth = translate_depth(textureSample(t_frontface_depth, s_frontface_depth, in.tex_coords.xy));
+        // the blurred frontface depth softens the thickness gradient along crystal edges
+        let frontface_depth = translate_depth(textureSample(t_crystal_blur, s_crystal_blur, in.tex_coords.xy).x);
         let backface_depth = translate_depth(textureSample(t_backface_depth, s_backface_depth, in.tex_coords.xy));
         var diff = backface_depth-frontface_depth;
         diff *= (100-0.1);
@@ -59,19 +78,29 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         // color = vec4f(vec3f(diff), 1.0);
         // color = vec4f(mix(vec3f(173.0/255.0, 3.0/255.0, 252.0/255.0), vec3f(186.0/255.0, 0.0/255.0, 207.0/255.0), diff), 1.0);
         color = vec4f(mix(vec3f(1.0, 0.0, 0.0), vec3f(0.0, 0.0, 1.0), diff), 1.0);
+        // pulsing with the crystals' animation, see cube.wgsl
+        color = vec4f(color.rgb * material.r * MAX_CRYSTAL_GLOW, color.a);
 
-        var result = lighting_result(in, true, 0.1, 0.01, 0.25, 8.0);
+        var result = lighting_result(in, true, 0.1, 0.25, 8.0);
         if material.w != w_material.w || material.w != e_material.w || material.w != s_material.w || material.w != n_material.w {
             result.w = max(result.w * 2.0, 0.5);
         }
         color.w -= result.w;
         color = mix_colors(color, result);
+        color = vec4f(color.rgb + crystal_reflection(in), color.a);
     } else if material.w == 0.0 {
         color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
         // color = vec4f(1.0);
-        var result = lighting_result(in, true, 0.1, 0.01, 0.0, 1.0);
+        var result = lighting_result(in, true, 0.1, 0.0, 1.0);
+        // static light from the cave's lightmaps
+        let baked = textureSample(t_baked, s_baked, in.tex_coords).rgb * cluster_params.exposure;
         // color.w -= result.w;
-        color = vec4f(color.xyz*(result.xyz*result.a), color.a);
+        color = vec4f(color.xyz*(result.xyz*result.a + baked), color.a);
+    }
+
+    if cluster_params.debug != 0u && textureSample(t_depth, s_depth, in.tex_coords.xy) < 1.0 {
+        let count = clusters[cluster_base(cluster_params, in.tex_coords, reconstruct_world_position(in))];
+        color = vec4f(mix(color.rgb, heatmap(f32(count) / f32(MAX_CLUSTER_LIGHTS)), 0.6), 1.0);
     }
  
     //DEBUG
@@ -108,40 +137,87 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     return color;
 }
 
-fn lighting_result(in: VertexOutput, point: bool, diffuse_strength: f32, ambient_strength: f32, specular_strength: f32, specular_pow: f32) -> vec4f {
-    // let ambient_strength = 0.0;
-    let ambient = ambient_strength;
-
-    let tex_coords_u = vec2u(u32(in.tex_coords.x*screen_info.screen_size.x), u32(in.tex_coords.y*screen_info.screen_size.y));
+fn reconstruct_world_position(in: VertexOutput) -> vec3f {
     let screen_depth = textureSample(t_depth, s_depth, in.tex_coords.xy);
-    let clip_pos = vec4(in.tex_coords.x * 2.0 - 1.0, in.tex_coords.y * -2.0 + 1.0, screen_depth, 1.0);
-    let view_pos = screen_info.camera.inverse_proj * clip_pos;
-    let world_position = view_pos.xyz / view_pos.w;
+    return world_position_at(screen_info.camera.inverse_proj, in.tex_coords, screen_depth);
+}
 
-    let normal = normalize(textureSample(t_normal, s_normal, in.tex_coords.xy).xyz * 2 - vec3f(1.0));
+// The nearest reflection probe seen in the crystal's surface, weighted by Schlick's fresnel.
+fn crystal_reflection(in: VertexOutput) -> vec3f {
+    let surface = textureSampleLevel(t_surface, s_surface, in.tex_coords, 0.0);
+    let world_position = reconstruct_world_position(in);
+    let normal = normalize(textureSampleLevel(t_normal, s_normal, in.tex_coords, 0.0).xyz * 2 - vec3f(1.0));
+    let view_dir = normalize(screen_info.camera.position - world_position);
+    let reflection = sample_reflection_probes(t_probes, s_probes, probe_params, world_position, reflect(-view_dir, normal), surface.g);
+    let fresnel = surface.r + (1.0 - surface.r) * pow(1.0 - clamp(dot(view_dir, normal), 0.0, 1.0), 5.0);
+    return reflection * fresnel;
+}
 
-    let light_dir = normalize(light.position - world_position);
+fn heatmap(value: f32) -> vec3f {
+    let t = clamp(value, 0.0, 1.0);
+    return clamp(vec3f(t * 3.0 - 1.0, 1.0 - abs(t * 3.0 - 1.5), 1.5 - t * 3.0), vec3f(0.0), vec3f(1.0));
+}
 
-    let light_dist = 50.0;
-    let diff = max(dot(normal, light_dir), 0.0) * diffuse_strength * (1.0-distance(light.position, world_position)/light_dist);
-    // let diffuse = diff * light.color;
+// Bounce light from the irradiance volume, trilinearly blended between the eight probes around
+// the position. Probes buried in rock are barely trusted so the ones inside the cave win.
+fn ambient_light(world_position: vec3f, normal: vec3f) -> vec3f {
+    let last = vec3f(irradiance_grid.dims - vec3u(1u));
+    let cell = clamp((world_position + normal * IRRADIANCE_NORMAL_OFFSET - irradiance_grid.origin) / irradiance_grid.cell_size, vec3f(0.0), last);
+    let base = min(vec3u(floor(cell)), irradiance_grid.dims - vec3u(1u));
+    let fraction = cell - vec3f(base);
+    var light = vec3f(0.0);
+    var total_weight = 0.0;
+    for (var i = 0u; i < 8u; i++) {
+        let offset = vec3u(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        let corner = min(base + offset, irradiance_grid.dims - vec3u(1u));
+        let index = corner.x + irradiance_grid.dims.x * (corner.y + irradiance_grid.dims.y * corner.z);
+        let trilinear = mix(vec3f(1.0) - fraction, fraction, vec3f(offset));
+        let sh = irradiance_probes[index].sh;
+        let weight = trilinear.x * trilinear.y * trilinear.z * max(sh[0].a, 0.001);
+        light += max(evaluate_sh(sh, normal), vec3f(0.0)) * weight;
+        total_weight += weight;
+    }
+    return light / max(total_weight, 0.0001);
+}
 
-    // let specular_strength = 0.25;
+fn lighting_result(in: VertexOutput, point: bool, diffuse_strength: f32, specular_strength: f32, specular_pow: f32) -> vec4f {
+    let world_position = reconstruct_world_position(in);
+    let normal = normalize(textureSample(t_normal, s_normal, in.tex_coords.xy).xyz * 2 - vec3f(1.0));
     let view_dir = normalize(screen_info.camera.position - world_position);
-    let reflect_dir = reflect(-light_dir, normal);
+    // crevices the lightmap bake found occluded get less of the bounce light
+    let occlusion = textureSampleLevel(t_surface, s_surface, in.tex_coords, 0.0).b;
+    let ambient = ambient_light(world_position, normal) * occlusion;
 
-    let spec = pow(max(dot(view_dir, reflect_dir), 0.0), specular_pow) * specular_strength;
-    // let specular = specular_strength * spec * light.color;
+    // the strength of every light is summed, its colour averaged by strength
+    var total = dot(ambient, vec3f(1.0 / 3.0));
+    var weighted_color = ambient;
+    // sampled once up front, the light loop isn't uniform control flow
+    let shadows = textureSample(t_shadows, s_shadows, in.tex_coords);
+    let base = cluster_base(cluster_params, in.tex_coords, world_position);
+    let count = clusters[base];
+    for (var i = 0u; i < count; i++) {
+        let light_index = clusters[base + 1u + i];
+        let light = lights[light_index];
+        let incident = incident_light(light, world_position, cluster_params.exposure);
+        let light_dir = incident.xyz;
 
-    let shadow = calculate_shadow(in);
+        let diff = max(dot(normal, light_dir), 0.0) * diffuse_strength * incident.w;
+        let reflect_dir = reflect(-light_dir, normal);
+        let spec = pow(max(dot(view_dir, reflect_dir), 0.0), specular_pow) * specular_strength * incident.w;
 
-    var result = (diff + spec)*shadow + ambient;
-    // result = shadow + ambient;
-    return vec4f(light.color, result);
+        let strength = (diff + spec) * calculate_shadow(shadows, light.shadow_slot);
+        total += strength;
+        weighted_color += light.color * strength;
+    }
+    return vec4f(weighted_color / max(total, 0.0001), total);
 }
 
-fn calculate_shadow(in: VertexOutput) -> f32 {
-    return textureSample(t_shadows, s_shadows, in.tex_coords).x;
+// lights without a slot in the shadow atlas are never shadowed
+fn calculate_shadow(shadows: vec4f, shadow_slot: i32) -> f32 {
+    if shadow_slot < 0 {
+        return 1.0;
+    }
+    return shadows[shadow_slot];
     // for(var i: i32 = 0; i < 6; i++) {
     //     let camera = light_cameras[i];
     //     let camera_space_pos_w = camera * vec4f(world_position, 1.0);
@@ -0,0 +1,96 @@
+t_depth: $0,0;
+s_depth: $0,1;
+
+screen_info: $1;
+
+cluster_params: $2,0;
+lights: $2,1;
+clusters: $2,2;
+
+t_atlas: $3,0;
+shadow_lights: $3,1;
+atlas_params: $3,2;
+
+fog: $4;
+
+struct FogParams {
+    albedo: vec3f,
+    density: f32,
+    base_height: f32,
+    height_falloff: f32,
+    anisotropy: f32,
+    steps: u32,
+    max_distance: f32,
+}
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+// Marches from the camera to the surface at half resolution, gathering the light each
+// cluster's lights scatter towards the camera. Light in rgb, transmittance in a.
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    if fog.density <= 0.0 || fog.steps == 0u {
+        return vec4f(0.0, 0.0, 0.0, 1.0);
+    }
+    let screen_depth = textureSample(t_depth, s_depth, in.tex_coords.xy);
+    let surface = world_position_at(screen_info.camera.inverse_proj, in.tex_coords, screen_depth);
+    let camera_position = screen_info.camera.position;
+    let ray = surface - camera_position;
+    let ray_length = min(length(ray), fog.max_distance);
+    let direction = ray / length(ray);
+    let step_length = ray_length / f32(fog.steps);
+    // a different offset per pixel and frame turns banding into noise that TAA smooths out
+    let jitter = interleaved_gradient_noise(in.clip_position.xy + screen_info.time * 60.0);
+
+    var scattered = vec3f(0.0);
+    var transmittance = 1.0;
+    for (var i = 0u; i < fog.steps; i++) {
+        let position = camera_position + direction * (f32(i) + jitter) * step_length;
+        let density = fog.density * exp(-fog.height_falloff * max(position.y - fog.base_height, 0.0));
+        var in_light = vec3f(0.0);
+        let base = cluster_base(cluster_params, in.tex_coords, position);
+        let count = clusters[base];
+        for (var l = 0u; l < count; l++) {
+            let light = lights[clusters[base + 1u + l]];
+            let incident = incident_light(light, position, cluster_params.exposure);
+            if incident.w <= 0.0 {
+                continue;
+            }
+            var visibility = 1.0;
+            if light.shadow_slot >= 0 {
+                visibility = shadow_visibility(t_atlas, atlas_params.atlas_size, shadow_lights[light.shadow_slot], position, vec3f(0.0));
+            }
+            in_light += light.color * incident.w * visibility * henyey_greenstein(dot(incident.xyz, direction), fog.anisotropy);
+        }
+        // integrated over the step so thick fog doesn't gain energy as the steps get longer
+        let step_transmittance = exp(-density * step_length);
+        scattered += transmittance * in_light * fog.albedo * (1.0 - step_transmittance);
+        transmittance *= step_transmittance;
+    }
+    return vec4f(scattered, transmittance);
+}
+
+// how much light arriving along one direction scatters into another `cos_theta` away,
+// positive anisotropy favours light travelling towards the camera
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * 3.14159265 * pow(denominator, 1.5));
+}
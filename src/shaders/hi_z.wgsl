@@ -0,0 +1,33 @@
+struct Params {
+  input_size: vec2u,
+  output_size: vec2u,
+}
+
+params: $1;
+output_tex: $2;
+
+// One level of the hierarchical depth pyramid. Every output texel keeps the
+// furthest depth of the input texels it covers, so a box whose nearest depth
+// is behind every covered texel is hidden. The first level reads the depth
+// texture, every level after it reads the level before.
+
+@compute @workgroup_size(8, 8, 1)
+fn main(
+  @builtin(global_invocation_id) invocation_id : vec3u
+) {
+    if invocation_id.x >= params.output_size.x || invocation_id.y >= params.output_size.y {
+        return;
+    }
+    // odd sized inputs make some output texels cover three input texels
+    let start = invocation_id.xy * params.input_size / params.output_size;
+    let end = max((invocation_id.xy + 1u) * params.input_size / params.output_size, start + 1u);
+
+    var furthest = 0.0;
+    for (var x = start.x; x < end.x; x++) {
+        for (var y = start.y; y < end.y; y++) {
+            furthest = max(furthest, load_input(vec2u(x, y)));
+        }
+    }
+
+    textureStore(output_tex, invocation_id.xy, vec4f(furthest, 0.0, 0.0, 1.0));
+}
@@ -0,0 +1,35 @@
+t_level: $0,0;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let size = textureDimensions(t_level);
+    let texel = min(vec2u(in.tex_coords * vec2f(size)), size - 1u);
+    let depth = textureLoad(t_level, texel, 0).r;
+    // depth is packed close to 1, stretch it so walls a few metres apart are distinguishable
+    let shade = 1.0 - pow(depth, 64.0);
+    if depth >= 1.0 {
+        // nothing was drawn here, so nothing can be occluded by it
+        return vec4f(0.4, 0.0, 0.0, 1.0);
+    }
+    return vec4f(vec3f(shade), 1.0);
+}
@@ -0,0 +1,31 @@
+struct OcclusionBox {
+    min: vec3f,
+    max: vec3f,
+}
+
+struct OcclusionParams {
+    // the camera the pyramid was rendered from
+    view_proj: mat4x4f,
+    box_count: u32,
+}
+
+boxes: $0,0;
+params: $0,1;
+// one per box, non-zero where it was hidden
+occluded: $0,2;
+t_hi_z: $1;
+
+// Tests world space boxes against the pyramid it was just built from, for the CPU to skip
+// the hidden ones when it culls the next frame.
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+  @builtin(global_invocation_id) invocation_id : vec3u
+) {
+    let index = invocation_id.x;
+    if index >= params.box_count {
+        return;
+    }
+    let occlusion_box = boxes[index];
+    occluded[index] = u32(hi_z_occluded(t_hi_z, params.view_proj, occlusion_box.min, occlusion_box.max));
+}
@@ -3,6 +3,25 @@ s_diffuse: $0,1;
 camera: $1;
 screen_info: $2;
 light: $3;
+t_lightmap: $4,0;
+s_lightmap: $4,1;
+t_height: $5,0;
+s_height: $5,1;
+height_params: $5,2;
+parallax: $6;
+animation: $7;
+
+struct HeightParams {
+    // world units from the height map's white to its black, zero draws the material flat
+    depth: f32,
+}
+
+struct ParallaxSettings {
+    min_steps: u32,
+    max_steps: u32,
+    // zero without self-shadowing
+    shadow_steps: u32,
+}
 
 //CUBE
 // struct VertexInput {
@@ -22,13 +41,16 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
 }
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) normal: vec3<f32>,
-    // @location(1) world_position: vec3f,
     @location(1) tex_coords: vec2f,
+    @location(2) world_position: vec3f,
 }
 
 @vertex
@@ -44,10 +66,13 @@ fn vs_main(
     );
     var out: VertexOutput;
     out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
-    let rotation_matrix = mat3x3(model_matrix[0].xyz, model_matrix[1].xyz, model_matrix[2].xyz);
-    out.normal = rotation_matrix*model.normal;
+    // sub-pixel offset for TAA, zero when it is disabled
+    out.clip_position = vec4f(out.clip_position.xy + screen_info.jitter * out.clip_position.w, out.clip_position.zw);
+    // inverse transpose of the model matrix, the upper 3x3 alone skews normals under non-uniform scale
+    let normal_matrix = mat3x3(instance.normal_matrix_0, instance.normal_matrix_1, instance.normal_matrix_2);
+    out.normal = normalize(normal_matrix * model.normal);
     out.tex_coords = model.tex_coords;
-    // out.world_position = (model_matrix * vec4<f32>(model.position, 1.0)).xyz;
+    out.world_position = (model_matrix * vec4<f32>(model.position, 1.0)).xyz;
     return out;
 }
 
@@ -55,6 +80,129 @@ struct FragmentOutput {
   @location(0) material: vec4f,
   @location(1) normal: vec4f,
   @location(2) color: vec4f,
+  @location(3) surface: vec4f,
+  @location(4) baked: vec4f,
+}
+
+// water pools on floors flatter than this, the rest of the rock stays rough
+const WET_SLOPE_START = 0.8;
+const WET_SLOPE_END = 0.95;
+const WET_REFLECTIVITY = 0.6;
+const WET_ROUGHNESS = 0.05;
+const ROCK_ROUGHNESS = 0.9;
+// how dark the deepest self-shadowed texel gets, the rest of the lighting still reaches it
+const PARALLAX_SHADOW_STRENGTH = 0.6;
+// a blocker this fraction of the height map's depth above the ray shadows fully
+const PARALLAX_SHADOW_SOFTNESS = 0.1;
+// parallax fades out past this, where the steps would alias more than they add
+const PARALLAX_FADE_START = 8.0;
+const PARALLAX_FADE_END = 16.0;
+
+struct ParallaxResult {
+    tex_coords: vec2f,
+    // 1.0 where the main light reaches the displaced texel
+    light: f32,
+}
+
+// How far the texture coordinates move per world unit along each axis, the rows of the inverse of the
+// surface's tangent frame. Built from derivatives since the cave's vertices carry no tangents.
+fn tex_coord_gradients(world_position: vec3f, tex_coords: vec2f, normal: vec3f) -> mat2x3f {
+    let dp1 = dpdx(world_position);
+    let dp2 = dpdy(world_position);
+    let duv1 = dpdx(tex_coords);
+    let duv2 = dpdy(tex_coords);
+    let dp2_perp = cross(dp2, normal);
+    let dp1_perp = cross(normal, dp1);
+    let determinant = dot(dp1, dp2_perp);
+    if abs(determinant) < 1e-12 {
+        return mat2x3f(vec3f(0.0), vec3f(0.0));
+    }
+    return mat2x3f(dp2_perp * duv1.x + dp1_perp * duv2.x, dp2_perp * duv1.y + dp1_perp * duv2.y) * (1.0 / determinant);
+}
+
+// Offset of the texture coordinates for a ray that sinks one full depth below the surface along `direction`.
+fn tex_coord_offset(gradients: mat2x3f, normal: vec3f, direction: vec3f, depth: f32) -> vec2f {
+    let along_normal = max(dot(direction, normal), 0.05);
+    let sideways = direction - normal * dot(direction, normal);
+    return vec2f(dot(gradients[0], sideways), dot(gradients[1], sideways)) * depth / along_normal;
+}
+
+// How far the material's texture has scrolled, wrapped so it stays precise however long the game runs.
+fn scroll_offset() -> vec2f {
+    return fract(animation.scroll_direction * animate(animation.scroll_shape, animation.scroll_curve, screen_info.time));
+}
+
+// Scrolling textures wrap around on their own rather than leaving it to the sampler. The gradients
+// are always passed in, so the jump where they wrap doesn't pick the wrong mip.
+fn material_coords(tex_coords: vec2f) -> vec2f {
+    if any(animation.scroll_direction != vec2f(0.0)) {
+        return fract(tex_coords);
+    }
+    return tex_coords;
+}
+
+fn sample_depth(tex_coords: vec2f, ddx: vec2f, ddy: vec2f) -> f32 {
+    return 1.0 - textureSampleGrad(t_height, s_height, material_coords(tex_coords), ddx, ddy).r;
+}
+
+// Steps the view ray down through the height map until it passes under it, then shadows the found texel by
+// stepping back up toward the main light.
+fn parallax_occlusion(in: VertexOutput, normal: vec3f) -> ParallaxResult {
+    var result = ParallaxResult(in.tex_coords, 1.0);
+    let ddx = dpdx(in.tex_coords);
+    let ddy = dpdy(in.tex_coords);
+    let gradients = tex_coord_gradients(in.world_position, in.tex_coords, normal);
+    let distance_to_camera = distance(camera.position, in.world_position);
+    let fade = 1.0 - smoothstep(PARALLAX_FADE_START, PARALLAX_FADE_END, distance_to_camera);
+    let depth = height_params.depth * fade;
+    if depth <= 0.0 || parallax.max_steps == 0u {
+        return result;
+    }
+
+    let view = (camera.position - in.world_position) / distance_to_camera;
+    let steps = u32(mix(f32(parallax.max_steps), f32(parallax.min_steps), saturate(dot(view, normal))));
+    let step_depth = 1.0 / f32(steps);
+    let step_offset = -tex_coord_offset(gradients, normal, view, depth) * step_depth;
+    var tex_coords = in.tex_coords;
+    var ray_depth = 0.0;
+    var surface_depth = sample_depth(tex_coords, ddx, ddy);
+    var previous_difference = -surface_depth;
+    for (var i = 0u; i < steps && ray_depth < surface_depth; i++) {
+        previous_difference = ray_depth - surface_depth;
+        tex_coords += step_offset;
+        ray_depth += step_depth;
+        surface_depth = sample_depth(tex_coords, ddx, ddy);
+    }
+    // between the last step above the height map and the first below it
+    let difference = ray_depth - surface_depth;
+    let weight = difference / max(difference - previous_difference, 1e-5);
+    result.tex_coords = tex_coords - step_offset * weight;
+    let hit_depth = ray_depth - step_depth * weight;
+
+    if parallax.shadow_steps == 0u {
+        return result;
+    }
+    var to_light = -light.direction;
+    if light.kind != LIGHT_DIRECTIONAL {
+        to_light = normalize(light.position - in.world_position);
+    }
+    if dot(to_light, normal) <= 0.0 {
+        return result;
+    }
+    let shadow_step_depth = hit_depth / f32(parallax.shadow_steps);
+    let shadow_step_offset = tex_coord_offset(gradients, normal, to_light, depth) * shadow_step_depth;
+    var occlusion = 0.0;
+    var shadow_coords = result.tex_coords;
+    var shadow_depth = hit_depth;
+    for (var i = 0u; i < parallax.shadow_steps; i++) {
+        shadow_coords += shadow_step_offset;
+        shadow_depth -= shadow_step_depth;
+        // how far the height map rises above the ray, nearer blockers cast harder shadows
+        let blocked = shadow_depth - sample_depth(shadow_coords, ddx, ddy);
+        occlusion = max(occlusion, blocked * (1.0 - f32(i) / f32(parallax.shadow_steps)));
+    }
+    result.light = 1.0 - saturate(occlusion / PARALLAX_SHADOW_SOFTNESS) * PARALLAX_SHADOW_STRENGTH * fade;
+    return result;
 }
 
 @fragment
@@ -68,7 +216,21 @@ fn fs_main(in: VertexOutput) -> FragmentOutput {
     // }
     out.normal = vec4f((in.normal+vec3f(1.0))*0.5, 1.0);
     out.material = vec4f(0.0);
-    out.color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let scroll = scroll_offset();
+    var scrolled = in;
+    scrolled.tex_coords += scroll;
+    let displaced = parallax_occlusion(scrolled, in.normal);
+    // sampled with the undisplaced gradients, the displaced coordinates jump at height map edges and would pick the lowest mip
+    out.color = textureSampleGrad(t_diffuse, s_diffuse, material_coords(displaced.tex_coords), dpdx(in.tex_coords), dpdy(in.tex_coords));
+    // the G-buffer has no per light channel to keep it in, so the self-shadow darkens the albedo
+    out.color = vec4f(out.color.rgb * displaced.light, out.color.a);
+    let wetness = smoothstep(WET_SLOPE_START, WET_SLOPE_END, in.normal.y);
+    // the lightmap shares the diffuse texture's coordinates, see bake.rs, but the light baked onto the surface doesn't flow with it
+    let lightmap = textureSampleGrad(t_lightmap, s_lightmap, displaced.tex_coords - scroll, dpdx(in.tex_coords), dpdy(in.tex_coords));
+    out.surface = vec4f(WET_REFLECTIVITY * wetness, mix(ROCK_ROUGHNESS, WET_ROUGHNESS, wetness), lightmap.a, 1.0);
+    // the lighting pass multiplies the baked light by the albedo, so a glowing material shines in its texture's colors
+    let emission = animation.emission_color * max(animate(animation.emission_shape, animation.emission_curve, screen_info.time), 0.0);
+    out.baked = vec4f(lightmap.rgb + emission, 1.0);
     return out;
 
     //DEBUG
@@ -0,0 +1,32 @@
+t_lit: $0,0;
+s_lit: $0,1;
+t_particles: $1,0;
+s_particles: $1,1;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+// the particle layer was blended over black, so its color is already weighted by its coverage
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_lit, s_lit, in.tex_coords);
+    let particles = textureSample(t_particles, s_particles, in.tex_coords);
+    return vec4f(color.rgb * (1.0 - particles.a) + particles.rgb, color.a);
+}
@@ -0,0 +1,89 @@
+t_depth: $0,0;
+s_depth: $0,1;
+
+params: $1,0;
+emitters: $1,1;
+particles: $1,2;
+
+struct ParticleParams {
+    // the camera the depth buffer was rendered from
+    view_proj: mat4x4f,
+    inverse_view_proj: mat4x4f,
+    camera_position: vec3f,
+    delta: f32,
+    time: f32,
+    particle_count: u32,
+}
+
+const GRAVITY = vec3f(0.0, -9.81, 0.0);
+// how far behind the depth buffer a particle may be and still count as touching it, in metres
+const COLLISION_THICKNESS = 0.3;
+
+fn random(seed: ptr<function, u32>) -> f32 {
+    *seed = pcg_hash(*seed);
+    return f32(*seed) / 4294967295.0;
+}
+
+fn random3(seed: ptr<function, u32>) -> vec3f {
+    return vec3f(random(seed), random(seed), random(seed));
+}
+
+// Whether the segment's end has gone into the cave, judged by the depth buffer where it is on screen.
+// Particles off screen or hidden more than the thickness behind something fly on.
+fn hits_depth(position: vec3f) -> bool {
+    let projected = project(params.view_proj, position);
+    if projected.z <= 0.0 || any(projected.xy <= vec2f(0.0)) || any(projected.xy >= vec2f(1.0)) {
+        return false;
+    }
+    let size = textureDimensions(t_depth);
+    let pixel = min(vec2u(projected.xy * vec2f(size)), size - 1u);
+    let depth = textureLoad(t_depth, pixel, 0);
+    if depth >= 1.0 {
+        return false;
+    }
+    let behind = depth_difference(params.camera_position, position, world_position_at(params.inverse_view_proj, projected.xy, depth));
+    return behind > 0.0 && behind < COLLISION_THICKNESS;
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+  @builtin(global_invocation_id) invocation_id : vec3u
+) {
+    let index = invocation_id.x;
+    if index >= params.particle_count {
+        return;
+    }
+    var particle = particles[index];
+    let emitter = emitters[particle.emitter];
+    var seed = pcg_hash(index ^ pcg_hash(bitcast<u32>(params.time)));
+    particle.age += params.delta;
+    // still waiting for its first spawn
+    if particle.age < 0.0 {
+        particles[index] = particle;
+        return;
+    }
+    if particle.age >= particle.lifetime {
+        particle.age = 0.0;
+        particle.lifetime = emitter.lifetime * mix(0.75, 1.25, random(&seed));
+        particle.position = emitter.position + (random3(&seed) - 0.5) * emitter.size;
+        particle.velocity = emitter.velocity + (random3(&seed) * 2.0 - 1.0) * emitter.spread;
+    }
+
+    // a slow swirl that differs from place to place, so floating particles don't drift in lockstep
+    let phase = particle.position * 1.7 + f32(index % 97u);
+    let wander = sin(vec3f(params.time * 0.7) + phase.yzx) * emitter.wander;
+    particle.velocity += (GRAVITY * emitter.gravity + wander) * params.delta;
+    particle.velocity /= 1.0 + emitter.drag * params.delta;
+    let next = particle.position + particle.velocity * params.delta;
+    if hits_depth(next) {
+        if emitter.bounce < 0.0 {
+            particle.age = particle.lifetime;
+        } else {
+            // the depth buffer has no normals to reflect off, so it turns back the way it came
+            particle.velocity *= -emitter.bounce;
+        }
+    } else {
+        particle.position = next;
+    }
+    particles[index] = particle;
+}
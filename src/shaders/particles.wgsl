@@ -0,0 +1,109 @@
+t_depth: $0,0;
+s_depth: $0,1;
+
+screen_info: $1;
+
+cluster_params: $2,0;
+lights: $2,1;
+clusters: $2,2;
+
+emitters: $3,0;
+particles: $3,1;
+
+// how close behind a particle the cave can be before the particle starts fading into it, in metres
+const SOFT_DISTANCE = 0.1;
+// fractions of a particle's life it takes to fade in and out
+const FADE_IN = 0.1;
+const FADE_OUT = 0.3;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // -1 to 1 across the quad
+    @location(0) corner: vec2f,
+    @location(1) world_position: vec3f,
+    @location(2) @interpolate(flat) particle: u32,
+}
+
+// Builds a camera facing quad for each particle, stretched along its velocity for streaks.
+// Particles that aren't alive collapse to a point and draw nothing.
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex: u32,
+    @builtin(instance_index) instance: u32,
+) -> VertexOutput {
+    var corners = array(vec2f(-1.0, -1.0), vec2f(1.0, -1.0), vec2f(1.0, 1.0), vec2f(-1.0, -1.0), vec2f(1.0, 1.0), vec2f(-1.0, 1.0));
+    let particle = particles[instance];
+    let emitter = emitters[particle.emitter];
+    var out: VertexOutput;
+    out.particle = instance;
+    if particle.age < 0.0 || particle.age >= particle.lifetime {
+        out.clip_position = vec4f(0.0, 0.0, 2.0, 1.0);
+        return out;
+    }
+    let corner = corners[vertex];
+    let view_proj = screen_info.camera.view_proj;
+    // the first two rows of the view projection point along the screen's axes in world space
+    var right = normalize(vec3f(view_proj[0].x, view_proj[1].x, view_proj[2].x));
+    var up = normalize(vec3f(view_proj[0].y, view_proj[1].y, view_proj[2].y));
+    var half_length = emitter.particle_size;
+    let speed = length(particle.velocity);
+    if emitter.stretch > 0.0 && speed > 0.0 {
+        let to_camera = normalize(screen_info.camera.position - particle.position);
+        let along = particle.velocity / speed;
+        let side = cross(along, to_camera);
+        if dot(side, side) > 1e-6 {
+            right = normalize(side);
+            up = cross(to_camera, right);
+            half_length += speed * emitter.stretch;
+        }
+    }
+    out.corner = corner;
+    out.world_position = particle.position + right * corner.x * emitter.particle_size + up * corner.y * half_length;
+    out.clip_position = view_proj * vec4f(out.world_position, 1.0);
+    // sub-pixel offset for TAA, zero when it is disabled
+    out.clip_position = vec4f(out.clip_position.xy + screen_info.jitter * out.clip_position.w, out.clip_position.zw);
+    return out;
+}
+
+// Light the cave's lights shine on the particle, as if it were a tiny sphere seen from any side.
+fn particle_lighting(tex_coords: vec2f, position: vec3f) -> vec3f {
+    var lit = vec3f(0.0);
+    let base = cluster_base(cluster_params, tex_coords, position);
+    let count = clusters[base];
+    for (var l = 0u; l < count; l++) {
+        let light = lights[clusters[base + 1u + l]];
+        let incident = incident_light(light, position, cluster_params.exposure);
+        lit += light.color * max(incident.w, 0.0);
+    }
+    return lit * 0.25;
+}
+
+// Color in rgb and coverage in a, fading where the particle meets the cave behind it instead of cutting into it.
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let particle = particles[in.particle];
+    let emitter = emitters[particle.emitter];
+    let radius = length(in.corner);
+    var shape = saturate(1.0 - radius * radius);
+    shape *= shape;
+    if dot(emitter.emission, emitter.emission) > 0.0 {
+        // a bright core with thin rays across it, twinkling at a rate of its own
+        let rays = max(1.0 - abs(in.corner.x) * 8.0, 0.0) * (1.0 - abs(in.corner.y)) + max(1.0 - abs(in.corner.y) * 8.0, 0.0) * (1.0 - abs(in.corner.x));
+        let twinkle = 0.6 + 0.4 * sin(screen_info.time * 9.0 + f32(in.particle) * 2.4);
+        shape = saturate(exp(-radius * radius * 16.0) + rays * 0.6 * twinkle);
+    }
+
+    let tex_coords = in.clip_position.xy / screen_info.screen_size;
+    let depth = textureLoad(t_depth, vec2u(in.clip_position.xy), 0);
+    // how far the cave is behind the particle
+    let gap = depth_difference(screen_info.camera.position, world_position_at(screen_info.camera.inverse_proj, tex_coords, depth), in.world_position);
+    let soft = saturate(gap / SOFT_DISTANCE);
+    let life = particle.age / particle.lifetime;
+    let fade = smoothstep(0.0, FADE_IN, life) * (1.0 - smoothstep(1.0 - FADE_OUT, 1.0, life));
+    let alpha = shape * soft * fade * emitter.opacity;
+    if alpha <= 0.0 {
+        discard;
+    }
+    let color = emitter.albedo * particle_lighting(tex_coords, in.world_position) + emitter.emission;
+    return vec4f(color, alpha);
+}
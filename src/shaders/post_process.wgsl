@@ -1,5 +1,6 @@
 t_screen: $0,0;
 s_screen: $0,1;
+aa_mode: $1;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -24,6 +25,56 @@ fn vs_main(
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     var color = textureSample(t_screen, s_screen, in.tex_coords.xy);
+    if aa_mode == 1u {
+        color = fxaa(in.tex_coords, color);
+    }
 
     return color;
-}
\ No newline at end of file
+}
+
+fn luma(color: vec4f) -> f32 {
+    return dot(color.rgb, vec3f(0.299, 0.587, 0.114));
+}
+
+const FXAA_EDGE_THRESHOLD: f32 = 0.125;
+const FXAA_EDGE_THRESHOLD_MIN: f32 = 0.0312;
+const FXAA_SPAN_MAX: f32 = 8.0;
+const FXAA_REDUCE_MUL: f32 = 0.125;
+const FXAA_REDUCE_MIN: f32 = 0.0078125;
+
+fn fxaa(tex_coords: vec2f, color: vec4f) -> vec4f {
+    let texel = 1.0 / vec2f(textureDimensions(t_screen));
+    let luma_nw = luma(textureSample(t_screen, s_screen, tex_coords + vec2f(-1.0, -1.0) * texel));
+    let luma_ne = luma(textureSample(t_screen, s_screen, tex_coords + vec2f(1.0, -1.0) * texel));
+    let luma_sw = luma(textureSample(t_screen, s_screen, tex_coords + vec2f(-1.0, 1.0) * texel));
+    let luma_se = luma(textureSample(t_screen, s_screen, tex_coords + vec2f(1.0, 1.0) * texel));
+    let luma_m = luma(color);
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+    let luma_range = luma_max - luma_min;
+    if luma_range < max(FXAA_EDGE_THRESHOLD_MIN, luma_max * FXAA_EDGE_THRESHOLD) {
+        return color;
+    }
+
+    var dir = vec2f(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        ((luma_nw + luma_sw) - (luma_ne + luma_se)),
+    );
+    let dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    let rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2f(-FXAA_SPAN_MAX), vec2f(FXAA_SPAN_MAX)) * texel;
+
+    let rgb_a = 0.5 * (
+        textureSample(t_screen, s_screen, tex_coords + dir * (1.0 / 3.0 - 0.5)) +
+        textureSample(t_screen, s_screen, tex_coords + dir * (2.0 / 3.0 - 0.5)));
+    let rgb_b = rgb_a * 0.5 + 0.25 * (
+        textureSample(t_screen, s_screen, tex_coords + dir * -0.5) +
+        textureSample(t_screen, s_screen, tex_coords + dir * 0.5));
+
+    let luma_b = luma(rgb_b);
+    if luma_b < luma_min || luma_b > luma_max {
+        return vec4f(rgb_a.rgb, color.a);
+    }
+    return vec4f(rgb_b.rgb, color.a);
+}
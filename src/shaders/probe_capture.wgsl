@@ -0,0 +1,73 @@
+t_diffuse: $0,0;
+s_diffuse: $0,1;
+capture: $1;
+capture_lights: $2;
+
+struct ProbeCapture {
+    view_proj: mat4x4f,
+    position: vec3f,
+    exposure: f32,
+}
+
+// the strengths the lighting pass gives the cave, ambient being the irradiance floor, so probes match what is on screen
+const CAVE_DIFFUSE = 0.1;
+const CAVE_AMBIENT = 0.01;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) normal: vec3<f32>,
+    @location(1) tex_coords: vec2f,
+    @location(2) world_position: vec3f,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: VertexOutput;
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.clip_position = capture.view_proj * world_position;
+    let normal_matrix = mat3x3(instance.normal_matrix_0, instance.normal_matrix_1, instance.normal_matrix_2);
+    out.normal = normalize(normal_matrix * model.normal);
+    out.tex_coords = model.tex_coords;
+    out.world_position = world_position.xyz;
+    return out;
+}
+
+// Forward lit cave without shadows, every light is looped over since the clusters belong to the player camera.
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let diffuse = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let normal = normalize(in.normal);
+    var light = vec3f(CAVE_AMBIENT);
+    for (var i = 0u; i < arrayLength(&capture_lights); i++) {
+        let incident = incident_light(capture_lights[i], in.world_position, capture.exposure);
+        light += capture_lights[i].color * max(dot(normal, incident.xyz), 0.0) * CAVE_DIFFUSE * incident.w;
+    }
+    // the irradiance bake counts how much of a capture is the inside of the cave
+    let inside = select(0.0, 1.0, dot(normal, in.world_position - capture.position) < 0.0);
+    return vec4f(diffuse.rgb * light, inside);
+}
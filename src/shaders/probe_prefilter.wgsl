@@ -0,0 +1,79 @@
+t_capture: $0,0;
+s_capture: $0,1;
+prefilter: $1;
+
+struct PrefilterParams {
+    faces: array<mat4x4f, 6>,
+    inverse_faces: array<mat4x4f, 6>,
+    face: u32,
+    roughness: f32,
+}
+
+const SAMPLES = 64u;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+fn sample_capture(direction: vec3f) -> vec3f {
+    let face = cube_face(direction);
+    let tex_coords = cube_face_uv(prefilter.faces[face], direction);
+    return textureSampleLevel(t_capture, s_capture, tex_coords, face, 0.0).rgb;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2f {
+    return vec2f(f32(i) / f32(count), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// a half vector around `normal` distributed like the GGX lobe of `roughness`
+fn importance_sample_ggx(xi: vec2f, normal: vec3f, roughness: f32) -> vec3f {
+    let a = roughness * roughness;
+    let phi = 2.0 * 3.14159265 * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let h = vec3f(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+    let up = select(vec3f(1.0, 0.0, 0.0), vec3f(0.0, 0.0, 1.0), abs(normal.z) < 0.999);
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return normalize(tangent * h.x + bitangent * h.y + normal * h.z);
+}
+
+// Convolves the captured faces with the GGX lobe of this mip's roughness, assuming the
+// view direction is the reflection direction as split sum prefiltering does.
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let ndc = vec2f(in.tex_coords.x * 2.0 - 1.0, in.tex_coords.y * -2.0 + 1.0);
+    let far = prefilter.inverse_faces[prefilter.face] * vec4f(ndc, 1.0, 1.0);
+    let normal = normalize(far.xyz / far.w);
+    if prefilter.roughness <= 0.0 {
+        return vec4f(sample_capture(normal), 1.0);
+    }
+    var total = vec3f(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < SAMPLES; i++) {
+        let h = importance_sample_ggx(hammersley(i, SAMPLES), normal, prefilter.roughness);
+        let l = normalize(2.0 * dot(normal, h) * h - normal);
+        let n_dot_l = dot(normal, l);
+        if n_dot_l > 0.0 {
+            total += sample_capture(l) * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    return vec4f(total / max(weight, 0.0001), 1.0);
+}
@@ -1,23 +1,16 @@
 t_depth: $0,0;
 s_depth: $0,1;
 
-light: $1;
+t_atlas: $1,0;
+shadow_lights: $1,1;
+atlas_params: $1,2;
 
-t_depth_cube_xp: $2,0;
-s_depth_cube_xp: $2,1;
-t_depth_cube_xn: $2,2;
-s_depth_cube_xn: $2,3;
-t_depth_cube_yp: $2,4;
-s_depth_cube_yp: $2,5;
-t_depth_cube_yn: $2,6;
-s_depth_cube_yn: $2,7;
-t_depth_cube_zp: $2,8;
-s_depth_cube_zp: $2,9;
-t_depth_cube_zn: $2,10;
-s_depth_cube_zn: $2,11;
+player_camera: $2;
 
-player_camera: $3;
-light_cameras: $4;
+// samples along each contact shadow ray
+const CONTACT_STEPS = 12u;
+// where the ray starts off the surface, so it doesn't hit the pixel it left from
+const CONTACT_START_OFFSET = 0.02;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -39,70 +32,61 @@ fn vs_main(
     return out;
 }
 
+// Marches a short ray toward the light through the depth buffer, 0 if it passes less than
+// contact_thickness behind something on screen. Catches what sits on a surface, like crystals
+// on rock, which the shadow map is too coarse to resolve.
+fn contact_shadow(light: ShadowLight, world_position: vec3f, normal: vec3f, jitter: f32) -> f32 {
+    var direction = -light.direction;
+    var reach = light.contact_length;
+    if light.kind != LIGHT_DIRECTIONAL {
+        direction = normalize(light.position - world_position);
+        reach = min(reach, distance(light.position, world_position));
+    }
+    if reach <= 0.0 || dot(direction, normal) <= 0.0 {
+        return 1.0;
+    }
+    let origin = world_position + normal * CONTACT_START_OFFSET;
+    let step_length = reach / f32(CONTACT_STEPS);
+    for (var i = 0u; i < CONTACT_STEPS; i++) {
+        let position = origin + direction * (f32(i) + jitter) * step_length;
+        let projected = project(player_camera.view_proj, position);
+        if projected.z <= 0.0 || any(projected.xy < vec2f(0.0)) || any(projected.xy > vec2f(1.0)) {
+            break;
+        }
+        let scene_depth = textureSampleLevel(t_depth, s_depth, projected.xy, 0.0);
+        if scene_depth >= 1.0 {
+            continue;
+        }
+        // how far the ray is behind the depth buffer along the view ray
+        let difference = depth_difference(player_camera.position, position, world_position_at(player_camera.inverse_proj, projected.xy, scene_depth));
+        if difference > 0.0 && difference < light.contact_thickness {
+            // fade out toward the end of the ray so its reach doesn't show as a hard edge
+            return smoothstep(0.5, 1.0, f32(i) / f32(CONTACT_STEPS));
+        }
+    }
+    return 1.0;
+}
+
+// Each channel holds the shadow of one atlas slot, lights find theirs through shadow_slot.
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let screen_depth = textureSample(t_depth, s_depth, in.tex_coords.xy);
-    let clip_pos = vec4(in.tex_coords.x * 2.0 - 1.0, in.tex_coords.y * -2.0 + 1.0, screen_depth, 1.0);
-    let view_pos = player_camera.inverse_proj * clip_pos;
-    let world_position = view_pos.xyz / view_pos.w;
-    var color = vec4f(vec3f(calculate_shadow(in, world_position)), 1.0);
-
-    return color;
-}
-
-fn calculate_shadow(in: VertexOutput, world_position: vec3f) -> f32 {
-    for(var i: i32 = 0; i < 6; i++) {
-        let camera = light_cameras[i];
-        let camera_space_pos_w = camera * vec4f(world_position, 1.0);
-        let camera_space_pos = camera_space_pos_w.xyz / camera_space_pos_w.w;
-        let tex_coords = vec2f((camera_space_pos.x+1.0)/2.0, (camera_space_pos.y-1.0)/-2.0);
-        if tex_coords.x >= 0.0 && tex_coords.x <= 1.0 && tex_coords.y >= 0.0 && tex_coords.y <= 1.0 && translate_depth(camera_space_pos.z) > 0.0 && translate_depth(camera_space_pos.z) < 1.0 {
-            let screen_depth = textureSample(t_depth, s_depth, in.tex_coords);
-            var light_depth = 0.0;
-            if i == 0 {
-                light_depth = textureSample(t_depth_cube_xp, s_depth_cube_xp, tex_coords);
-            }
-            if i == 1 {
-                light_depth = textureSample(t_depth_cube_xn, s_depth_cube_xn, tex_coords);
-            }
-            if i == 2 {
-                light_depth = textureSample(t_depth_cube_yp, s_depth_cube_yp, tex_coords);
-            }
-            if i == 3 {
-                light_depth = textureSample(t_depth_cube_yn, s_depth_cube_yn, tex_coords);
-            }
-            if i == 4 {
-                light_depth = textureSample(t_depth_cube_zp, s_depth_cube_zp, tex_coords);
-            }
-            if i == 5 {
-                light_depth = textureSample(t_depth_cube_zn, s_depth_cube_zn, tex_coords);
-            }
-            var shadow_value = max(0.0, translate_depth(camera_space_pos.z)-translate_depth(light_depth))*100.0;
-            if (shadow_value > 0.1) {
-                shadow_value = 1.0;
-            }
-            // return f32(i)/6.0;
-
-            // return vec4f(vec3f(translate_depth(screen_pos.z)), 1.0);
-            if (translate_depth(camera_space_pos.z) >= 0.0 && translate_depth(camera_space_pos.z) < 1.0 && screen_depth != 1.0) {
-                return (1.0-shadow_value);
-            }
-            return 1.0;
+    let world_position = world_position_at(player_camera.inverse_proj, in.tex_coords, screen_depth);
+    // the surface normal from the reconstructed positions, facing the camera
+    var normal = normalize(cross(dpdx(world_position), dpdy(world_position)));
+    if dot(normal, player_camera.position - world_position) < 0.0 {
+        normal = -normal;
+    }
+    if screen_depth == 1.0 {
+        return vec4f(1.0);
+    }
 
-            // return translate_depth(light_depth);
-            // if light_depth <= camera_space_pos.z {
-            //     return light_depth;
-            // } else {
-            //     return 1.0;
-            // }
-        }
+    let jitter = interleaved_gradient_noise(in.clip_position.xy);
+    var shadows = vec4f(1.0);
+    for (var slot = 0u; slot < min(atlas_params.count, MAX_SHADOWED_LIGHTS); slot++) {
+        let light = shadow_lights[slot];
+        let shadow_map = shadow_visibility(t_atlas, atlas_params.atlas_size, light, world_position, normal);
+        shadows[slot] = min(shadow_map, contact_shadow(light, world_position, normal, jitter));
     }
-    return 0.0;
+    return shadows;
 }
-
-fn translate_depth(depth: f32) -> f32 {
-    let near = 0.1;
-    let far = 100.0;
-    let r = (2.0 * near) / (far + near - depth * (far - near));
-    return r;
-}
\ No newline at end of file
@@ -0,0 +1,135 @@
+t_scene: $0,0;
+s_scene: $0,1;
+
+t_depth: $1,0;
+s_depth: $1,1;
+
+t_normal: $2,2;
+s_normal: $2,3;
+t_surface: $2,8;
+s_surface: $2,9;
+
+screen_info: $3;
+
+t_probes: $4,0;
+s_probes: $4,1;
+probe_params: $4,2;
+
+ssr: $5;
+
+struct SsrParams {
+    max_distance: f32,
+    steps: u32,
+    thickness: f32,
+    max_roughness: f32,
+}
+
+// halvings of the last step once a ray has passed behind the depth buffer
+const REFINE_STEPS = 4u;
+// rays this close to the screen edge in uv fade into the probe
+const EDGE_FADE = 0.1;
+// keeps the ray from hitting the surface it starts on
+const START_OFFSET = 0.05;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+fn project_ray(position: vec3f) -> vec3f {
+    return project(screen_info.camera.view_proj, position);
+}
+
+// How far the ray at `position` is behind the depth buffer, along the view ray, negative in front of it.
+fn ray_depth_difference(position: vec3f, tex_coords: vec2f) -> f32 {
+    let scene_depth = textureSampleLevel(t_depth, s_depth, tex_coords, 0.0);
+    if scene_depth >= 1.0 {
+        return -1.0;
+    }
+    return depth_difference(screen_info.camera.position, position, world_position_at(screen_info.camera.inverse_proj, tex_coords, scene_depth));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let scene = textureSample(t_scene, s_scene, in.tex_coords);
+    let surface = textureSample(t_surface, s_surface, in.tex_coords);
+    let screen_depth = textureSample(t_depth, s_depth, in.tex_coords);
+    let normal = normalize(textureSample(t_normal, s_normal, in.tex_coords).xyz * 2.0 - vec3f(1.0));
+    let reflectivity = surface.r;
+    let roughness = surface.g;
+    if screen_depth >= 1.0 || reflectivity <= 0.0 || roughness >= ssr.max_roughness || ssr.steps == 0u {
+        return scene;
+    }
+
+    let world_position = world_position_at(screen_info.camera.inverse_proj, in.tex_coords, screen_depth);
+    let view_dir = normalize(world_position - screen_info.camera.position);
+    let direction = reflect(view_dir, normal);
+    let origin = world_position + normal * START_OFFSET;
+    let step_length = ssr.max_distance / f32(ssr.steps);
+    let jitter = interleaved_gradient_noise(in.clip_position.xy + screen_info.time * 60.0);
+
+    var hit = false;
+    var hit_coords = vec2f(0.0);
+    var travelled = 0.0;
+    var previous = 0.0;
+    for (var i = 0u; i < ssr.steps; i++) {
+        let distance = (f32(i) + jitter) * step_length;
+        let projected = project_ray(origin + direction * distance);
+        if projected.z <= 0.0 || any(projected.xy < vec2f(0.0)) || any(projected.xy > vec2f(1.0)) {
+            break;
+        }
+        let difference = ray_depth_difference(origin + direction * distance, projected.xy);
+        if difference > 0.0 && difference < ssr.thickness {
+            // bisect between the last point in front of the depth buffer and this one
+            var near = previous;
+            var far = distance;
+            for (var r = 0u; r < REFINE_STEPS; r++) {
+                let middle = (near + far) * 0.5;
+                let middle_coords = project_ray(origin + direction * middle).xy;
+                if ray_depth_difference(origin + direction * middle, middle_coords) > 0.0 {
+                    far = middle;
+                } else {
+                    near = middle;
+                }
+            }
+            hit = true;
+            travelled = far;
+            hit_coords = project_ray(origin + direction * far).xy;
+            break;
+        }
+        previous = distance;
+    }
+
+    let probe = sample_reflection_probes(t_probes, s_probes, probe_params, world_position, direction, roughness);
+    var reflection = probe;
+    if hit {
+        let edge = min(hit_coords, vec2f(1.0) - hit_coords);
+        let edge_fade = smoothstep(0.0, EDGE_FADE, min(edge.x, edge.y));
+        let distance_fade = 1.0 - travelled / ssr.max_distance;
+        let hit_color = textureSampleLevel(t_scene, s_scene, hit_coords, 0.0).rgb;
+        reflection = mix(probe, hit_color, edge_fade * distance_fade);
+    }
+
+    // Schlick's fresnel with the reflectivity as the reflectance head on
+    let cos_theta = clamp(dot(-view_dir, normal), 0.0, 1.0);
+    let fresnel = reflectivity + (1.0 - reflectivity) * pow(1.0 - cos_theta, 5.0);
+    // fade out towards max_roughness instead of cutting off
+    let glossiness = 1.0 - smoothstep(ssr.max_roughness * 0.5, ssr.max_roughness, roughness);
+    let amount = clamp(fresnel * glossiness * surface.a, 0.0, 1.0);
+    return vec4f(mix(scene.rgb, reflection, amount), scene.a);
+}
@@ -0,0 +1,66 @@
+t_current: $0,0;
+s_current: $0,1;
+t_history: $1,0;
+s_history: $1,1;
+t_depth: $2,0;
+s_depth: $2,1;
+
+screen_info: $3;
+params: $4;
+
+struct TaaParams {
+    blend: f32,
+}
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let current = textureSample(t_current, s_current, in.tex_coords);
+
+    // reproject this pixel into last frame using the unjittered camera matrices
+    let screen_depth = textureSample(t_depth, s_depth, in.tex_coords);
+    let clip_pos = vec4(in.tex_coords.x * 2.0 - 1.0 - screen_info.jitter.x, in.tex_coords.y * -2.0 + 1.0 - screen_info.jitter.y, screen_depth, 1.0);
+    let world_pos_w = screen_info.camera.inverse_proj * clip_pos;
+    let world_position = world_pos_w.xyz / world_pos_w.w;
+    let prev_clip_w = screen_info.prev_camera.view_proj * vec4f(world_position, 1.0);
+    let prev_clip = prev_clip_w.xy / prev_clip_w.w;
+    let prev_tex_coords = vec2f((prev_clip.x + 1.0) / 2.0, (prev_clip.y - 1.0) / -2.0);
+
+    if prev_tex_coords.x < 0.0 || prev_tex_coords.x > 1.0 || prev_tex_coords.y < 0.0 || prev_tex_coords.y > 1.0 {
+        return current;
+    }
+
+    // clamp the history to the current neighbourhood to reject disoccluded samples
+    let texel = 1.0 / screen_info.screen_size;
+    var min_color = current;
+    var max_color = current;
+    for (var x = -1; x <= 1; x++) {
+        for (var y = -1; y <= 1; y++) {
+            let neighbour = textureSample(t_current, s_current, in.tex_coords + vec2f(f32(x), f32(y)) * texel);
+            min_color = min(min_color, neighbour);
+            max_color = max(max_color, neighbour);
+        }
+    }
+    let history = clamp(textureSample(t_history, s_history, prev_tex_coords), min_color, max_color);
+
+    return mix(history, current, params.blend);
+}
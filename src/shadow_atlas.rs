@@ -0,0 +1,274 @@
+use bespoke_engine::{shader::ShaderType, texture::DepthTexture};
+use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use cgmath::{InnerSpace, Vector3};
+use wgpu::{BindGroup, BindGroupLayout, BindGroupLayoutDescriptor, Buffer, Device, Queue};
+
+use crate::{frustum::Frustum, light::{Light, LightKind}, point_shadow::ShadowFace};
+
+pub const MAX_SHADOWED_LIGHTS: usize = 4;
+// a fixed memory budget, 2048x2048 fits six 512 faces for two lights and smaller ones for the rest
+const ATLAS_SIZE: u32 = 2048;
+// caps each light's own shadow resolution
+const MAX_TILE: u32 = 512;
+const MIN_TILE: u32 = 64;
+
+/// Square region of the atlas in texels.
+#[derive(Clone, Copy)]
+pub struct ShadowTile {
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+}
+
+/// The shadow map faces given to one light this frame.
+pub struct ShadowAllocation {
+    pub light: usize,
+    // index of the first face among the faces of every allocation, the rest follow it
+    pub first_face: usize,
+    pub tiles: Vec<ShadowTile>,
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct ShadowLightRaw {
+    position: [f32; 3],
+    far: f32,
+    near: f32,
+    normal_offset: f32,
+    kind: u32,
+    face_count: u32,
+    faces: [[[f32; 4]; 4]; 6],
+    // uv offset in xy and uv size in zw
+    tiles: [[f32; 4]; 6],
+    face_spans: [f32; 6],
+    contact_length: f32,
+    contact_thickness: f32,
+    direction: [f32; 3],
+    padding: f32,
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+struct ShadowAtlasParams {
+    count: u32,
+    atlas_size: f32,
+    padding: [u32; 2],
+}
+
+/// Rows of tiles filled left to right, each as tall as the first tile placed in it.
+#[derive(Clone, Default)]
+struct ShelfPacker {
+    shelves: Vec<(u32, u32, u32)>,
+    top: u32,
+}
+
+impl ShelfPacker {
+    fn pack(&mut self, size: u32) -> Option<ShadowTile> {
+        if let Some(shelf) = self.shelves.iter_mut().find(|(_, height, x)| *height >= size && x + size <= ATLAS_SIZE) {
+            let tile = ShadowTile { x: shelf.2, y: shelf.0, size };
+            shelf.2 += size;
+            return Some(tile);
+        }
+        if self.top + size > ATLAS_SIZE {
+            return None;
+        }
+        self.shelves.push((self.top, size, size));
+        self.top += size;
+        Some(ShadowTile { x: 0, y: self.top - size, size })
+    }
+}
+
+fn luminance(color: Vector3<f32>) -> f32 {
+    color.dot(Vector3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// One depth texture shared by the cube maps of the most important lights each frame,
+/// so several lights can cast shadows at resolutions that follow how much of the view they light.
+pub struct ShadowAtlas {
+    pub depth: DepthTexture,
+    lights: Buffer,
+    params: Buffer,
+    pub layout: BindGroupLayout,
+    pub bind_group: BindGroup,
+    pub allocations: Vec<ShadowAllocation>,
+}
+
+impl ShadowAtlas {
+    pub fn new(device: &Device) -> Self {
+        let depth = DepthTexture::create_depth_texture(device, ATLAS_SIZE, ATLAS_SIZE, "Shadow Atlas");
+        let lights = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Atlas Light Buffer"),
+            size: (MAX_SHADOWED_LIGHTS * size_of::<ShadowLightRaw>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Atlas Params Buffer"),
+            size: size_of::<ShadowAtlasParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let buffer_entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Shadow Atlas Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                buffer_entry(1, wgpu::BufferBindingType::Storage { read_only: true }),
+                buffer_entry(2, wgpu::BufferBindingType::Uniform),
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Shadow Atlas"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&depth.view) },
+                wgpu::BindGroupEntry { binding: 1, resource: lights.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: params.as_entire_binding() },
+            ],
+        });
+        Self {
+            depth,
+            lights,
+            params,
+            layout,
+            bind_group,
+            allocations: vec![],
+        }
+    }
+
+    pub fn shader_type() -> ShaderType {
+        ShaderType {
+            var_types: vec!["".into(), "<storage, read>".into(), "<uniform>".into()],
+            wgsl_types: vec!["texture_depth_2d".into(), "array<ShadowLight>".into(), "ShadowAtlasParams".into()],
+        }
+    }
+
+    /// Ranks the lights by brightness and how much of the view they can reach, then gives
+    /// the best ones a tile per face, shrinking a light's tiles until they fit.
+    pub fn allocate(&mut self, lights: &[Light], frustum: &Frustum, eye: Vector3<f32>) -> &[ShadowAllocation] {
+        let mut candidates = lights.iter().enumerate().filter(|(_, light)| {
+            // a light whose range is off screen can't shadow anything visible
+            matches!(light.kind, LightKind::Directional { .. }) || frustum.intersects_sphere(light.position, light.radius)
+        }).map(|(i, light)| {
+            let coverage = match light.kind {
+                LightKind::Directional { .. } => 1.0,
+                _ => (light.radius / (light.position - eye).magnitude().max(1.0)).min(1.0),
+            };
+            (i, luminance(light.color) * light.intensity * coverage, coverage)
+        }).collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let mut packer = ShelfPacker::default();
+        let mut first_face = 0;
+        self.allocations.clear();
+        for (light, _, coverage) in candidates.into_iter().take(MAX_SHADOWED_LIGHTS) {
+            let face_count = lights[light].kind.shadow_faces();
+            let resolution = lights[light].shadow.resolution.clamp(MIN_TILE, MAX_TILE);
+            let mut size = ((resolution as f32 * coverage) as u32).next_power_of_two().clamp(MIN_TILE, resolution);
+            while size >= MIN_TILE {
+                let mut attempt = packer.clone();
+                let tiles = (0..face_count).map(|_| attempt.pack(size)).collect::<Option<Vec<_>>>();
+                if let Some(tiles) = tiles {
+                    packer = attempt;
+                    self.allocations.push(ShadowAllocation { light, first_face, tiles });
+                    first_face += face_count;
+                    break;
+                }
+                size /= 2;
+            }
+        }
+        &self.allocations
+    }
+
+    /// Uploads the faces of the allocated lights, laid out as `first_face` of each allocation says.
+    pub fn upload(&self, lights: &[Light], faces: &[ShadowFace], queue: &Queue) {
+        let raw = self.allocations.iter().map(|allocation| {
+            let light = &lights[allocation.light];
+            let face = |i: usize| faces.get(allocation.first_face + i).filter(|_| i < allocation.tiles.len());
+            ShadowLightRaw {
+                position: light.position.into(),
+                far: light.shadow.far,
+                near: light.shadow.near,
+                normal_offset: light.shadow.normal_offset,
+                kind: light.kind.raw_kind(),
+                face_count: allocation.tiles.len() as u32,
+                faces: std::array::from_fn(|i| face(i).map_or([[0.0; 4]; 4], |face| face.view_proj.into())),
+                tiles: std::array::from_fn(|i| allocation.tiles.get(i).map_or([0.0; 4], |tile| {
+                    [tile.x as f32 / ATLAS_SIZE as f32, tile.y as f32 / ATLAS_SIZE as f32, tile.size as f32 / ATLAS_SIZE as f32, tile.size as f32 / ATLAS_SIZE as f32]
+                })),
+                face_spans: std::array::from_fn(|i| face(i).map_or(0.0, |face| face.span)),
+                contact_length: light.shadow.contact_length,
+                contact_thickness: light.shadow.contact_thickness,
+                direction: light.direction().into(),
+                padding: 0.0,
+            }
+        }).collect::<Vec<_>>();
+        if !raw.is_empty() {
+            queue.write_buffer(&self.lights, 0, cast_slice(&raw));
+        }
+        queue.write_buffer(&self.params, 0, bytes_of(&ShadowAtlasParams { count: raw.len() as u32, atlas_size: ATLAS_SIZE as f32, padding: [0; 2] }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlap(a: &ShadowTile, b: &ShadowTile) -> bool {
+        a.x < b.x + b.size && b.x < a.x + a.size && a.y < b.y + b.size && b.y < a.y + a.size
+    }
+
+    #[test]
+    fn tiles_fill_a_shelf_before_starting_the_next() {
+        let mut packer = ShelfPacker::default();
+        let tiles = (0..5).map(|_| packer.pack(512).unwrap()).collect::<Vec<_>>();
+        assert_eq!(tiles.iter().map(|tile| (tile.x, tile.y)).collect::<Vec<_>>(), [(0, 0), (512, 0), (1024, 0), (1536, 0), (0, 512)]);
+        // shorter tiles go on the taller shelves that still have room
+        let small = packer.pack(256).unwrap();
+        assert_eq!((small.x, small.y), (512, 512));
+    }
+
+    #[test]
+    fn tiles_stay_inside_the_atlas_without_overlapping() {
+        let mut packer = ShelfPacker::default();
+        let mut tiles: Vec<ShadowTile> = vec![];
+        for size in [512, 64, 256, 512, 128, 64, 512, 256, 256, 128, 512, 512, 64].into_iter().cycle().take(200) {
+            let Some(tile) = packer.pack(size) else {
+                continue;
+            };
+            assert_eq!(tile.size, size);
+            assert!(tile.x + tile.size <= ATLAS_SIZE && tile.y + tile.size <= ATLAS_SIZE);
+            assert!(tiles.iter().all(|other| !overlap(&tile, other)));
+            tiles.push(tile);
+        }
+    }
+
+    #[test]
+    fn a_full_atlas_packs_nothing() {
+        let mut packer = ShelfPacker::default();
+        let count = (ATLAS_SIZE / MAX_TILE).pow(2);
+        for _ in 0..count {
+            assert!(packer.pack(MAX_TILE).is_some());
+        }
+        assert!(packer.pack(MAX_TILE).is_none());
+        assert!(packer.pack(MIN_TILE).is_none());
+    }
+}
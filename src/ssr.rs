@@ -0,0 +1,119 @@
+use bespoke_engine::{binding::{create_layout, UniformBinding, WgslType}, model::Render, shader::Shader, surface_context::SurfaceCtx, texture::{DepthTexture, Texture}};
+use bytemuck::{Pod, Zeroable};
+use wgpu::{CommandEncoder, RenderPassTimestampWrites};
+
+use crate::{game::ScreenInfo, light_probes::LightProbes, texture_types::TextureLayer};
+
+/// How far and how carefully reflection rays are marched.
+#[derive(Clone, Copy)]
+pub struct SsrSettings {
+    pub max_distance: f32,
+    pub steps: u32,
+    // how far behind the depth buffer a ray may be and still count as hitting it, in metres
+    pub thickness: f32,
+    // rougher surfaces would need blurred reflections, they only get the lighting pass' specular
+    pub max_roughness: f32,
+}
+
+impl Default for SsrSettings {
+    fn default() -> Self {
+        Self {
+            max_distance: 20.0,
+            steps: 32,
+            thickness: 0.3,
+            max_roughness: 0.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Clone, Copy)]
+pub struct SsrParams {
+    max_distance: f32,
+    steps: u32,
+    thickness: f32,
+    max_roughness: f32,
+}
+
+impl WgslType for SsrParams {
+    fn wgsl_name() -> String {
+        "SsrParams".into()
+    }
+}
+
+impl SsrParams {
+    fn new(settings: &SsrSettings) -> Self {
+        Self {
+            max_distance: settings.max_distance,
+            steps: settings.steps,
+            thickness: settings.thickness,
+            max_roughness: settings.max_roughness,
+        }
+    }
+}
+
+/// Screen space reflections for wet rock and polished crystal. Rays are marched through the depth
+/// buffer from the surfaces the G-buffer marks as reflective and pick up the lit scene where they hit,
+/// fading to the nearest reflection probe as they run off the screen.
+pub struct ScreenSpaceReflections {
+    shader: Shader,
+    pub output: UniformBinding<Texture>,
+    params_binding: UniformBinding<SsrParams>,
+    pub settings: SsrSettings,
+    pub enabled: bool,
+}
+
+impl ScreenSpaceReflections {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32, settings: SsrSettings, depth_texture: &UniformBinding<DepthTexture>, layer: &UniformBinding<TextureLayer>, screen_info: &UniformBinding<ScreenInfo>, probes: &LightProbes) -> Self {
+        let params_binding = UniformBinding::new(surface_ctx.device(), "SSR Params", SsrParams::new(&settings), None);
+        let shader = Shader::new_post_process(
+            include_str!("shaders/ssr.wgsl"),
+            surface_ctx.device(),
+            surface_ctx.config().format,
+            vec![&create_layout::<Texture>(surface_ctx.device()), &depth_texture.layout, &layer.layout, &screen_info.layout, &probes.layout, &params_binding.layout],
+            vec![&Texture::shader_type(), &depth_texture.shader_type, &layer.shader_type, &screen_info.shader_type, &LightProbes::shader_type(), &params_binding.shader_type]
+        );
+        Self {
+            shader,
+            output: Self::create_output(surface_ctx, width, height),
+            params_binding,
+            settings,
+            enabled: true,
+        }
+    }
+
+    fn create_output(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) -> UniformBinding<Texture> {
+        UniformBinding::new(surface_ctx.device(), "Reflected Texture", Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format), None)
+    }
+
+    pub fn resize(&mut self, surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) {
+        self.output = Self::create_output(surface_ctx, width, height);
+    }
+
+    /// Reflects `scene` in the surfaces of `layer` and writes the result to `output`.
+    pub fn render(&mut self, surface_ctx: &dyn SurfaceCtx, encoder: &mut CommandEncoder, scene: &UniformBinding<Texture>, depth_texture: &UniformBinding<DepthTexture>, layer: &UniformBinding<TextureLayer>, screen_info: &UniformBinding<ScreenInfo>, probes: &LightProbes, timestamp_writes: Option<RenderPassTimestampWrites>) {
+        self.params_binding.set_data(surface_ctx.device(), SsrParams::new(&self.settings));
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SSR Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.output.value.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            timestamp_writes,
+            occlusion_query_set: None,
+            depth_stencil_attachment: None,
+        });
+        self.shader.bind(&mut render_pass);
+        render_pass.set_bind_group(0, &scene.binding, &[]);
+        render_pass.set_bind_group(1, &depth_texture.binding, &[]);
+        render_pass.set_bind_group(2, &layer.binding, &[]);
+        render_pass.set_bind_group(3, &screen_info.binding, &[]);
+        render_pass.set_bind_group(4, &probes.bind_group, &[]);
+        render_pass.set_bind_group(5, &self.params_binding.binding, &[]);
+        surface_ctx.screen_model().render(&mut render_pass);
+    }
+}
@@ -1,29 +1,32 @@
-use std::ops::Index;
-
 use bespoke_engine::{binding::{Binding, Resource}, shader::ShaderType, surface_context::SurfaceCtx, texture::{DepthTexture, Texture}};
-use wgpu::Device;
 
 pub struct TextureLayer {
     pub diffuse: Texture,
     pub material: Texture,
     pub normal: Texture,
     pub shadows: Texture,
+    // reflectivity in r, roughness in g and ambient occlusion in b, alpha is coverage so layers blend over each other
+    pub surface: Texture,
+    // static light from the cave's lightmaps, alpha is coverage
+    pub baked: Texture,
 }
 
 impl TextureLayer {
-    pub fn new(surface_ctx: &dyn SurfaceCtx) -> Self {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) -> Self {
         Self {
-            diffuse: Texture::blank_texture(surface_ctx.device(), surface_ctx.config().width, surface_ctx.config().height, surface_ctx.config().format),
-            material: Texture::blank_texture(surface_ctx.device(), surface_ctx.config().width, surface_ctx.config().height, surface_ctx.config().format),
-            normal: Texture::blank_texture(surface_ctx.device(), surface_ctx.config().width, surface_ctx.config().height, surface_ctx.config().format),
-            shadows: Texture::blank_texture(surface_ctx.device(), surface_ctx.config().width, surface_ctx.config().height, surface_ctx.config().format),
+            diffuse: Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format),
+            material: Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format),
+            normal: Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format),
+            shadows: Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format),
+            surface: Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format),
+            baked: Texture::blank_texture(surface_ctx.device(), width, height, surface_ctx.config().format),
         }
     }
 }
 
 impl Binding for TextureLayer {
     fn layout(ty: Option<wgpu::BindingType>) -> Vec<wgpu::BindGroupLayoutEntry> {
-        (0..4).map(|i| {
+        (0..6).map(|i| {
             vec![
                 wgpu::BindGroupLayoutEntry {
                     binding: i*2,
@@ -58,13 +61,17 @@ impl Binding for TextureLayer {
             Resource::Bespoke(wgpu::BindingResource::Sampler(&self.diffuse.sampler)),
             Resource::Bespoke(wgpu::BindingResource::TextureView(&self.shadows.view)),
             Resource::Bespoke(wgpu::BindingResource::Sampler(&self.shadows.sampler)),
+            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.surface.view)),
+            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.surface.sampler)),
+            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.baked.view)),
+            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.baked.sampler)),
         ]
     }
 
     fn shader_type() -> ShaderType {
         ShaderType {
-            var_types: vec!["".into(); 8],
-            wgsl_types: vec![vec!["texture_2d<f32>".into(), "sampler".into()]; 4].concat(),
+            var_types: vec!["".into(); 12],
+            wgsl_types: vec![vec!["texture_2d<f32>".into(), "sampler".into()]; 6].concat(),
         }
     }
 }
@@ -75,10 +82,10 @@ pub struct CrystalDepth {
 }
 
 impl CrystalDepth {
-    pub fn new(surface_ctx: &dyn SurfaceCtx) -> Self {
+    pub fn new(surface_ctx: &dyn SurfaceCtx, width: u32, height: u32) -> Self {
         Self {
-            front: DepthTexture::create_depth_texture(surface_ctx.device(), surface_ctx.config().width, surface_ctx.config().height, "Frontface Depth Texture"),
-            back: DepthTexture::create_depth_texture(surface_ctx.device(), surface_ctx.config().width, surface_ctx.config().height, "Backface Depth Texture"),
+            front: DepthTexture::create_depth_texture(surface_ctx.device(), width, height, "Frontface Depth Texture"),
+            back: DepthTexture::create_depth_texture(surface_ctx.device(), width, height, "Backface Depth Texture"),
         }
     }
 }
@@ -141,88 +148,3 @@ impl Binding for CrystalDepth {
         }
     }
 }
-
-pub struct DepthCube {
-    pub xp: DepthTexture,
-    pub xn: DepthTexture,
-    pub yp: DepthTexture,
-    pub yn: DepthTexture,
-    pub zp: DepthTexture,
-    pub zn: DepthTexture,
-}
-
-impl DepthCube {
-    pub fn new(device: &Device, width: u32, height: u32) -> Self {
-        Self {
-            xp: DepthTexture::create_depth_texture(device, width, height, "X Positive Depth Texture"),
-            xn: DepthTexture::create_depth_texture(device, width, height, "X Negative Depth Texture"),
-            yp: DepthTexture::create_depth_texture(device, width, height, "Y Positive Depth Texture"),
-            yn: DepthTexture::create_depth_texture(device, width, height, "Y Negative Depth Texture"),
-            zp: DepthTexture::create_depth_texture(device, width, height, "Z Positive Depth Texture"),
-            zn: DepthTexture::create_depth_texture(device, width, height, "Z Negative Depth Texture"),
-        }
-    }
-}
-
-impl Index<usize> for DepthCube {
-    type Output = DepthTexture;
-
-    fn index(&self, index: usize) -> &Self::Output {
-        match index {
-            0 => &self.xp,
-            1 => &self.xn,
-            2 => &self.yp,
-            3 => &self.yn,
-            4 => &self.zp,
-            5 => &self.zn,
-            _ => panic!("Index out of bounds"),
-        }
-    }
-}
-
-impl Binding for DepthCube {
-    fn layout(ty: Option<wgpu::BindingType>) -> Vec<wgpu::BindGroupLayoutEntry> {
-        (0..6).map(|i| {
-            vec![wgpu::BindGroupLayoutEntry {
-                binding: i*2,
-                visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Texture {
-                    multisampled: false,
-                    view_dimension: wgpu::TextureViewDimension::D2,
-                    sample_type: wgpu::TextureSampleType::Depth,
-                },
-                count: None,
-            },
-            wgpu::BindGroupLayoutEntry {
-                binding: i*2+1,
-                visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                count: None,
-            }]
-        }).collect::<Vec<Vec<_>>>().concat()
-    }
-
-    fn create_resources<'a>(&'a self) -> Vec<bespoke_engine::binding::Resource> {
-        vec![
-            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.xp.view)),
-            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.xp.sampler)),
-            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.xn.view)),
-            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.xn.sampler)),
-            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.yp.view)),
-            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.yp.sampler)),
-            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.yn.view)),
-            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.yn.sampler)),
-            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.zp.view)),
-            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.zp.sampler)),
-            Resource::Bespoke(wgpu::BindingResource::TextureView(&self.zn.view)),
-            Resource::Bespoke(wgpu::BindingResource::Sampler(&self.zn.sampler)),
-        ]
-    }
-
-    fn shader_type() -> ShaderType {
-        ShaderType {
-            var_types: vec!["".into(); 12],
-            wgsl_types: vec![vec!["texture_depth_2d".into(), "sampler".into()]; 6].concat(),
-        }
-    }
-}
\ No newline at end of file